@@ -0,0 +1,1147 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// ROMs are loaded at 0x200, and XO-CHIP has 64 KiB of RAM
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x10000;
+
+// Includes nested deeper than this are assumed to be recursive
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// Constants defined in terms of each other deeper than this are assumed to
+// be circular
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+/// An assembly error, located by file, line and column. Both are counted
+/// from 1, and are 0 for errors that don't belong to a line
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file, self.line, self.column, self.message
+            )
+        }
+    }
+}
+
+impl error::Error for AsmError {}
+
+/// An assembled ROM and the listing that maps it back to the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    pub listing: Vec<ListingLine>,
+}
+
+/// One line of source and the bytes it assembled to, if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingLine {
+    pub addr: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub file: String,
+    pub line: usize,
+    pub source: String,
+}
+
+impl Assembly {
+    /// The listing as text: the address and bytes of each source line,
+    /// followed by its location and the line itself
+    pub fn listing_text(&self) -> String {
+        let mut text = String::new();
+
+        for line in &self.listing {
+            let location = format!("{}:{}", line.file, line.line);
+            let mut chunks = line.bytes.chunks(4);
+            let (addr, first) = match (line.addr, chunks.next()) {
+                (Some(addr), Some(bytes)) => {
+                    (format!("{:03X}", addr), hex(bytes))
+                }
+                _ => (String::new(), String::new()),
+            };
+            text.push_str(&format!(
+                "{:4} {:8}  {:16} {}\n",
+                addr, first, location, line.source
+            ));
+
+            // Data longer than 4 bytes continues on the following lines
+            for (n, bytes) in chunks.enumerate() {
+                let addr = line.addr.unwrap_or(0) as usize + (n + 1) * 4;
+                text.push_str(&format!("{:03X}  {}\n", addr, hex(bytes)));
+            }
+        }
+        text
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Assemble the source file at `path`. Included files are found relative
+/// to the file that includes them
+pub fn assemble_file(path: &str) -> Result<Assembly, AsmError> {
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.to_string(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    assemble(&source, path, |include| fs::read_to_string(include))
+}
+
+/// Assemble `source`, which came from the file `name`. `read` loads
+/// included files, given their path relative to the current directory
+///
+/// Each line holds an optional label followed by a Cowgod-style
+/// instruction or a directive, and `;` starts a comment:
+///
+/// ```text
+/// SPEED   EQU 2               ; Constants
+///         INCLUDE "sprites.asm"
+/// start:  LD I, ball          ; Labels
+///         LD V0, SPEED * 4 + 1
+///         DRW V0, V1, ball_end - ball
+///         JP start
+/// ball:   DB #C0, #C0
+/// ball_end:
+/// ```
+pub fn assemble<F>(
+    source: &str,
+    name: &str,
+    read: F,
+) -> Result<Assembly, AsmError>
+where
+    F: FnMut(&str) -> Result<String, io::Error>,
+{
+    let mut assembler = Assembler {
+        files: Vec::new(),
+        lines: Vec::new(),
+        statements: Vec::new(),
+        symbols: HashMap::new(),
+        addr: START,
+        read,
+    };
+    assembler.add_file(source, name, 0)?;
+    assembler.finish()
+}
+
+// A line of source, after includes have been expanded
+struct Line {
+    file: usize,
+    number: usize,
+    text: String,
+}
+
+// An instruction or data directive, and where it is placed
+struct Statement {
+    line: usize,
+    addr: usize,
+    kind: StatementKind,
+}
+
+enum StatementKind {
+    Instruction {
+        mnemonic: String,
+        column: usize,
+        operands: Vec<Operand>,
+    },
+    Data {
+        width: usize, // 1 for DB, 2 for DW
+        items: Vec<DataItem>,
+    },
+}
+
+enum DataItem {
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+enum Symbol {
+    Label(usize),
+    Constant(Expr, usize), // Expression and the line defining it
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    V(usize),
+    I,
+    IndirectI, // [I]
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Long(Expr),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+    column: usize,
+    kind: ExprKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ExprKind {
+    Number(i64),
+    Symbol(String),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    tok: Tok,
+    column: usize,
+}
+
+// Punctuation, longest first so that "<<" isn't read as two "<"
+const PUNCTUATION: &[&str] = &[
+    "<<", ">>", ",", ":", "(", ")", "[", "]", "+", "-", "*", "/", "%", "&",
+    "|", "^", "~", "=",
+];
+
+// Binary operators from lowest to highest precedence
+const PRECEDENCE: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Assembler<F> {
+    files: Vec<String>,
+    lines: Vec<Line>,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    addr: usize,
+    read: F,
+}
+
+impl<F> Assembler<F>
+where
+    F: FnMut(&str) -> Result<String, io::Error>,
+{
+    // First pass: split the source into statements and give every label
+    // its address
+    fn add_file(
+        &mut self,
+        source: &str,
+        name: &str,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let file = self.files.len();
+        self.files.push(name.to_string());
+
+        for (n, text) in source.lines().enumerate() {
+            let line = self.lines.len();
+            self.lines.push(Line {
+                file,
+                number: n + 1,
+                text: text.to_string(),
+            });
+            self.add_line(line, depth)?;
+        }
+        Ok(())
+    }
+
+    fn add_line(&mut self, line: usize, depth: usize) -> Result<(), AsmError> {
+        let tokens = tokenize(&self.lines[line].text)
+            .map_err(|(column, message)| self.error(line, column, message))?;
+        let mut tokens = &tokens[..];
+
+        // A label is an identifier followed by a colon
+        if tokens.len() >= 2 && tokens[1].tok == Tok::Punct(":") {
+            if let Tok::Ident(ref name) = tokens[0].tok {
+                let addr = self.addr;
+                self.define(line, tokens[0].column, name, Symbol::Label(addr))?;
+                tokens = &tokens[2..];
+            }
+        }
+
+        let (name, column) = match tokens.first() {
+            Some(Token {
+                tok: Tok::Ident(name),
+                column,
+            }) => (name.clone(), *column),
+            Some(token) => {
+                return Err(self.error(
+                    line,
+                    token.column,
+                    "Expected an instruction or directive".to_string(),
+                ))
+            }
+            None => return Ok(()),
+        };
+        let rest = &tokens[1..];
+
+        // NAME EQU value, or NAME = value
+        match rest.first() {
+            Some(Token {
+                tok: Tok::Ident(equ),
+                ..
+            }) if equ.eq_ignore_ascii_case("equ") => {
+                let expr = self.expression(line, &rest[1..])?;
+                return self.define(
+                    line,
+                    column,
+                    &name,
+                    Symbol::Constant(expr, line),
+                );
+            }
+            Some(Token {
+                tok: Tok::Punct("="),
+                ..
+            }) => {
+                let expr = self.expression(line, &rest[1..])?;
+                return self.define(
+                    line,
+                    column,
+                    &name,
+                    Symbol::Constant(expr, line),
+                );
+            }
+            _ => (),
+        }
+
+        let mnemonic = name.to_uppercase();
+        match mnemonic.as_str() {
+            "INCLUDE" => {
+                let include = match rest {
+                    [Token {
+                        tok: Tok::Str(include),
+                        ..
+                    }] => String::from_utf8_lossy(include).into_owned(),
+                    _ => {
+                        return Err(self.error(
+                            line,
+                            column,
+                            "INCLUDE expects a file name in quotes".to_string(),
+                        ))
+                    }
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(self.error(
+                        line,
+                        column,
+                        "Includes are nested too deeply".to_string(),
+                    ));
+                }
+
+                let including = &self.files[self.lines[line].file];
+                let path = match Path::new(including).parent() {
+                    Some(dir) => dir.join(&include),
+                    None => Path::new(&include).to_path_buf(),
+                };
+                let path = path.to_string_lossy().into_owned();
+                let source = (self.read)(&path).map_err(|e| {
+                    self.error(
+                        line,
+                        column,
+                        format!("Can't include '{}': {}", path, e),
+                    )
+                })?;
+                self.add_file(&source, &path, depth + 1)
+            }
+            "DB" | "DW" => {
+                let width = if mnemonic == "DB" { 1 } else { 2 };
+                let mut items = Vec::new();
+                let mut size = 0;
+
+                for operand in split_operands(rest) {
+                    match operand {
+                        [Token {
+                            tok: Tok::Str(s), ..
+                        }] if width == 1 => {
+                            size += s.len();
+                            items.push(DataItem::Str(s.clone()));
+                        }
+                        _ => {
+                            let expr = self.expression(line, operand)?;
+                            size += width;
+                            items.push(DataItem::Expr(expr));
+                        }
+                    }
+                }
+                if items.is_empty() {
+                    return Err(self.error(
+                        line,
+                        column,
+                        format!("{} expects at least one value", mnemonic),
+                    ));
+                }
+                self.add_statement(
+                    line,
+                    column,
+                    size,
+                    StatementKind::Data { width, items },
+                )
+            }
+            _ => {
+                let operands = split_operands(rest)
+                    .into_iter()
+                    .map(|tokens| self.operand(line, tokens))
+                    .collect::<Result<Vec<Operand>, AsmError>>()?;
+
+                // Check the operands now, so that errors are reported in
+                // order, but leave the symbols to the second pass
+                let size = self
+                    .encode(line, &mnemonic, column, &operands, false)?
+                    .len();
+                self.add_statement(
+                    line,
+                    column,
+                    size,
+                    StatementKind::Instruction {
+                        mnemonic,
+                        column,
+                        operands,
+                    },
+                )
+            }
+        }
+    }
+
+    fn add_statement(
+        &mut self,
+        line: usize,
+        column: usize,
+        size: usize,
+        kind: StatementKind,
+    ) -> Result<(), AsmError> {
+        if self.addr + size > MEMORY_SIZE {
+            return Err(self.error(
+                line,
+                column,
+                "Program is too big for memory".to_string(),
+            ));
+        }
+        self.statements.push(Statement {
+            line,
+            addr: self.addr,
+            kind,
+        });
+        self.addr += size;
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        line: usize,
+        column: usize,
+        name: &str,
+        symbol: Symbol,
+    ) -> Result<(), AsmError> {
+        if operand_register(name).is_some() {
+            return Err(self.error(
+                line,
+                column,
+                format!("'{}' is the name of a register", name),
+            ));
+        }
+        if self.symbols.contains_key(name) {
+            return Err(self.error(
+                line,
+                column,
+                format!("'{}' is already defined", name),
+            ));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    // Second pass: encode every statement now that all symbols are known
+    fn finish(mut self) -> Result<Assembly, AsmError> {
+        let mut rom = Vec::new();
+        let mut output: HashMap<usize, (usize, Vec<u8>)> = HashMap::new();
+
+        for statement in &self.statements {
+            let bytes = match statement.kind {
+                StatementKind::Instruction {
+                    ref mnemonic,
+                    column,
+                    ref operands,
+                } => self.encode(
+                    statement.line,
+                    mnemonic,
+                    column,
+                    operands,
+                    true,
+                )?,
+                StatementKind::Data { width, ref items } => {
+                    let mut bytes = Vec::new();
+                    for item in items {
+                        match *item {
+                            DataItem::Str(ref s) => bytes.extend_from_slice(s),
+                            DataItem::Expr(ref expr) if width == 1 => {
+                                let value = self.value(
+                                    statement.line,
+                                    expr,
+                                    -0x80,
+                                    0xFF,
+                                    true,
+                                )?;
+                                bytes.push(value as u8);
+                            }
+                            DataItem::Expr(ref expr) => {
+                                let value = self.value(
+                                    statement.line,
+                                    expr,
+                                    -0x8000,
+                                    0xFFFF,
+                                    true,
+                                )?;
+                                bytes.push((value >> 8) as u8);
+                                bytes.push(value as u8);
+                            }
+                        }
+                    }
+                    bytes
+                }
+            };
+
+            rom.extend_from_slice(&bytes);
+            output.insert(statement.line, (statement.addr, bytes));
+        }
+
+        let files = &self.files;
+        let listing = self
+            .lines
+            .drain(..)
+            .enumerate()
+            .map(|(n, line)| {
+                let (addr, bytes) = match output.remove(&n) {
+                    Some((addr, bytes)) => (Some(addr as u16), bytes),
+                    None => (None, Vec::new()),
+                };
+                ListingLine {
+                    addr,
+                    bytes,
+                    file: files[line.file].clone(),
+                    line: line.number,
+                    source: line.text,
+                }
+            })
+            .collect();
+
+        Ok(Assembly { rom, listing })
+    }
+
+    // Encode an instruction. Until `resolve` is set, symbols are taken to
+    // be zero, which is enough to check the operands and find the length
+    fn encode(
+        &self,
+        line: usize,
+        mnemonic: &str,
+        column: usize,
+        operands: &[Operand],
+        resolve: bool,
+    ) -> Result<Vec<u8>, AsmError> {
+        use self::Operand::*;
+        use crate::instruction::Instruction::*;
+
+        let byte = |e: &self::Expr| {
+            self.value(line, e, -0x80, 0xFF, resolve)
+                .map(|kk| (kk & 0xFF) as u8)
+        };
+        let addr = |e: &self::Expr| self.value(line, e, 0, 0xFFF, resolve);
+        let nibble = |e: &self::Expr| {
+            self.value(line, e, 0, 0xF, resolve).map(|n| n as u8)
+        };
+
+        // Vx is the first register operand and Vy the second. SHR and SHL
+        // with one register shift it in place
+        let registers: Vec<usize> = operands
+            .iter()
+            .filter_map(|operand| match *operand {
+                V(r) => Some(r),
+                _ => None,
+            })
+            .collect();
+        let x = registers.first().cloned().unwrap_or(0);
+        let y = registers.get(1).cloned().unwrap_or(x);
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowRes,
+            ("HIGH", []) => HighRes,
+            ("AUDIO", []) => Audio,
+            ("SCD", [Expr(n)]) => ScrollDown { n: nibble(n)? },
+            ("SCU", [Expr(n)]) => ScrollUp { n: nibble(n)? },
+            ("PLANE", [Expr(n)]) => Plane {
+                n: self.value(line, n, 0, 3, resolve)? as u8,
+            },
+            ("JP", [Expr(nnn)]) => Jump { nnn: addr(nnn)? },
+            ("JP", [V(0), Expr(nnn)]) => JumpV0 { nnn: addr(nnn)? },
+            ("CALL", [Expr(nnn)]) => Call { nnn: addr(nnn)? },
+            ("SE", [V(_), Expr(kk)]) => SkipEqImm { x, kk: byte(kk)? },
+            ("SE", [V(_), V(_)]) => SkipEqReg { x, y },
+            ("SNE", [V(_), Expr(kk)]) => SkipNeImm { x, kk: byte(kk)? },
+            ("SNE", [V(_), V(_)]) => SkipNeReg { x, y },
+            ("SAVE", [V(_), V(_)]) => StoreRange { x, y },
+            ("LOAD", [V(_), V(_)]) => LoadRange { x, y },
+            ("LD", [V(_), Expr(kk)]) => LoadImm { x, kk: byte(kk)? },
+            ("LD", [V(_), V(_)]) => LoadReg { x, y },
+            ("LD", [I, Expr(nnn)]) => LoadI { nnn: addr(nnn)? },
+            ("LD", [I, Long(nnnn)]) => {
+                let nnnn = self.value(line, nnnn, 0, 0xFFFF, resolve)?;
+                return Ok(vec![0xF0, 0x00, (nnnn >> 8) as u8, nnnn as u8]);
+            }
+            ("LD", [V(_), DT]) => GetDelay { x },
+            ("LD", [V(_), K]) => WaitKey { x },
+            ("LD", [DT, V(_)]) => SetDelay { x },
+            ("LD", [ST, V(_)]) => SetSound { x },
+            ("LD", [F, V(_)]) => Font { x },
+            ("LD", [HF, V(_)]) => BigFont { x },
+            ("LD", [B, V(_)]) => Bcd { x },
+            ("LD", [IndirectI, V(_)]) => Store { x },
+            ("LD", [V(_), IndirectI]) => Load { x },
+            ("LD", [R, V(_)]) => StoreFlags { x },
+            ("LD", [V(_), R]) => LoadFlags { x },
+            ("ADD", [V(_), Expr(kk)]) => AddImm { x, kk: byte(kk)? },
+            ("ADD", [V(_), V(_)]) => AddReg { x, y },
+            ("ADD", [I, V(_)]) => AddI { x },
+            ("OR", [V(_), V(_)]) => Or { x, y },
+            ("AND", [V(_), V(_)]) => And { x, y },
+            ("XOR", [V(_), V(_)]) => Xor { x, y },
+            ("SUB", [V(_), V(_)]) => Sub { x, y },
+            ("SHR", [V(_)]) | ("SHR", [V(_), V(_)]) => ShiftRight { x, y },
+            ("SUBN", [V(_), V(_)]) => SubN { x, y },
+            ("SHL", [V(_)]) | ("SHL", [V(_), V(_)]) => ShiftLeft { x, y },
+            ("RND", [V(_), Expr(kk)]) => Random { x, kk: byte(kk)? },
+            ("DRW", [V(_), V(_), Expr(n)]) => Draw {
+                x,
+                y,
+                n: nibble(n)?,
+            },
+            ("SKP", [V(_)]) => SkipKey { x },
+            ("SKNP", [V(_)]) => SkipNotKey { x },
+            ("PITCH", [V(_)]) => Pitch { x },
+            _ if MNEMONICS.contains(&mnemonic) => {
+                return Err(self.error(
+                    line,
+                    column,
+                    format!("Invalid operands for {}", mnemonic),
+                ))
+            }
+            _ => {
+                return Err(self.error(
+                    line,
+                    column,
+                    format!("Unknown instruction '{}'", mnemonic),
+                ))
+            }
+        };
+        let opcode = instruction.encode();
+        Ok(vec![(opcode >> 8) as u8, opcode as u8])
+    }
+
+    // Evaluate an expression that must lie between `min` and `max`.
+    // Negative values are stored in two's complement
+    fn value(
+        &self,
+        line: usize,
+        expr: &Expr,
+        min: i64,
+        max: i64,
+        resolve: bool,
+    ) -> Result<u16, AsmError> {
+        if !resolve {
+            return Ok(0);
+        }
+
+        let value = self.eval(line, expr, 0)?;
+        if value < min || value > max {
+            return Err(self.error(
+                line,
+                expr.column,
+                format!("Value {} is out of range ({} to {})", value, min, max),
+            ));
+        }
+        Ok(value as u16)
+    }
+
+    fn eval(
+        &self,
+        line: usize,
+        expr: &Expr,
+        depth: usize,
+    ) -> Result<i64, AsmError> {
+        let error = |message| Err(self.error(line, expr.column, message));
+
+        match expr.kind {
+            ExprKind::Number(n) => Ok(n),
+            ExprKind::Symbol(ref name) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => Ok(*addr as i64),
+                Some(Symbol::Constant(_, _)) if depth >= MAX_CONSTANT_DEPTH => {
+                    error(format!("'{}' is defined in terms of itself", name))
+                }
+                Some(Symbol::Constant(value, defined)) => {
+                    self.eval(*defined, value, depth + 1)
+                }
+                None => error(format!("Unknown symbol '{}'", name)),
+            },
+            ExprKind::Unary(op, ref operand) => {
+                let value = self.eval(line, operand, depth)?;
+                Ok(if op == '-' { -value } else { !value })
+            }
+            ExprKind::Binary(op, ref left, ref right) => {
+                let a = self.eval(line, left, depth)?;
+                let b = self.eval(line, right, depth)?;
+                match op {
+                    "|" => Ok(a | b),
+                    "^" => Ok(a ^ b),
+                    "&" => Ok(a & b),
+                    "<<" => Ok(a.checked_shl(b as u32).unwrap_or(0)),
+                    ">>" => Ok(a.checked_shr(b as u32).unwrap_or(0)),
+                    "+" => Ok(a.wrapping_add(b)),
+                    "-" => Ok(a.wrapping_sub(b)),
+                    "*" => Ok(a.wrapping_mul(b)),
+                    _ if b == 0 => error("Division by zero".to_string()),
+                    "/" => Ok(a / b),
+                    _ => Ok(a % b),
+                }
+            }
+        }
+    }
+
+    fn operand(
+        &self,
+        line: usize,
+        tokens: &[Token],
+    ) -> Result<Operand, AsmError> {
+        match tokens {
+            [Token {
+                tok: Tok::Ident(name),
+                ..
+            }] => {
+                if let Some(register) = operand_register(name) {
+                    return Ok(register);
+                }
+            }
+            [Token {
+                tok: Tok::Punct("["),
+                ..
+            }, Token {
+                tok: Tok::Ident(name),
+                ..
+            }, Token {
+                tok: Tok::Punct("]"),
+                ..
+            }] if name.eq_ignore_ascii_case("i") => {
+                return Ok(Operand::IndirectI)
+            }
+            _ => (),
+        }
+
+        match tokens.first() {
+            Some(Token {
+                tok: Tok::Ident(name),
+                ..
+            }) if name.eq_ignore_ascii_case("long") && tokens.len() > 1 => {
+                Ok(Operand::Long(self.expression(line, &tokens[1..])?))
+            }
+            _ => Ok(Operand::Expr(self.expression(line, tokens)?)),
+        }
+    }
+
+    fn expression(
+        &self,
+        line: usize,
+        tokens: &[Token],
+    ) -> Result<Expr, AsmError> {
+        let mut pos = 0;
+        let expr = parse_expr(tokens, &mut pos, 0)
+            .map_err(|(column, message)| self.error(line, column, message))?;
+
+        match tokens.get(pos) {
+            None => Ok(expr),
+            Some(token) => Err(self.error(
+                line,
+                token.column,
+                "Unexpected text after expression".to_string(),
+            )),
+        }
+    }
+
+    fn error(&self, line: usize, column: usize, message: String) -> AsmError {
+        let line = &self.lines[line];
+        AsmError {
+            file: self.files[line.file].clone(),
+            line: line.number,
+            column,
+            message,
+        }
+    }
+}
+
+const MNEMONICS: &[&str] = &[
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU",
+    "PLANE", "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR",
+    "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP",
+    "PITCH",
+];
+
+// Registers and other operands written as a single name
+fn operand_register(name: &str) -> Option<Operand> {
+    let upper = name.to_uppercase();
+    match upper.as_str() {
+        "I" => Some(Operand::I),
+        "DT" => Some(Operand::DT),
+        "ST" => Some(Operand::ST),
+        "K" => Some(Operand::K),
+        "F" => Some(Operand::F),
+        "HF" => Some(Operand::HF),
+        "B" => Some(Operand::B),
+        "R" => Some(Operand::R),
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            usize::from_str_radix(&upper[1..], 16).ok().map(Operand::V)
+        }
+        _ => None,
+    }
+}
+
+// Split operands at commas
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|token| token.tok == Tok::Punct(",")).collect()
+}
+
+// Parse binary operators of at least the given precedence level
+fn parse_expr(
+    tokens: &[Token],
+    pos: &mut usize,
+    level: usize,
+) -> Result<Expr, (usize, String)> {
+    if level == PRECEDENCE.len() {
+        return parse_unary(tokens, pos);
+    }
+
+    let mut left = parse_expr(tokens, pos, level + 1)?;
+    while let Some(Token {
+        tok: Tok::Punct(op),
+        column,
+    }) = tokens.get(*pos)
+    {
+        if !PRECEDENCE[level].contains(op) {
+            break;
+        }
+        *pos += 1;
+        let right = parse_expr(tokens, pos, level + 1)?;
+        left = Expr {
+            column: *column,
+            kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+        };
+    }
+    Ok(left)
+}
+
+fn parse_unary(
+    tokens: &[Token],
+    pos: &mut usize,
+) -> Result<Expr, (usize, String)> {
+    let token = match tokens.get(*pos) {
+        Some(token) => token,
+        None => {
+            let column = tokens.last().map_or(1, |t| t.column + 1);
+            return Err((column, "Expected an expression".to_string()));
+        }
+    };
+    *pos += 1;
+
+    let kind = match token.tok {
+        Tok::Number(n) => ExprKind::Number(n),
+        Tok::Ident(ref name) => ExprKind::Symbol(name.clone()),
+        Tok::Punct("-") => {
+            ExprKind::Unary('-', Box::new(parse_unary(tokens, pos)?))
+        }
+        Tok::Punct("~") => {
+            ExprKind::Unary('~', Box::new(parse_unary(tokens, pos)?))
+        }
+        Tok::Punct("(") => {
+            let expr = parse_expr(tokens, pos, 0)?;
+            match tokens.get(*pos) {
+                Some(Token {
+                    tok: Tok::Punct(")"),
+                    ..
+                }) => *pos += 1,
+                _ => return Err((token.column, "Unclosed '('".to_string())),
+            }
+            return Ok(expr);
+        }
+        _ => return Err((token.column, "Expected an expression".to_string())),
+    };
+    Ok(Expr {
+        column: token.column,
+        kind,
+    })
+}
+
+// Split a line into tokens, stopping at a comment. Errors give the column
+fn tokenize(line: &str) -> Result<Vec<Token>, (usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        let column = pos + 1;
+        let rest: String = chars[pos..].iter().collect();
+
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        let ident = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        let tok = if c == '"' {
+            let mut s = Vec::new();
+            pos += 1;
+            loop {
+                match chars.get(pos) {
+                    Some('"') => break,
+                    Some('\\') if pos + 1 < chars.len() => {
+                        pos += 1;
+                        s.push(chars[pos] as u8);
+                    }
+                    Some(&c) if c.is_ascii() => s.push(c as u8),
+                    Some(_) => {
+                        return Err((
+                            pos + 1,
+                            "Strings must be ASCII".to_string(),
+                        ))
+                    }
+                    None => {
+                        return Err((column, "Unclosed string".to_string()))
+                    }
+                }
+                pos += 1;
+            }
+            pos += 1;
+            Tok::Str(s)
+        } else if c.is_ascii_digit() || c == '#' || c == '$' {
+            let (radix, skip) = if c == '#' || c == '$' {
+                (16, 1)
+            } else if rest.starts_with("0x") || rest.starts_with("0X") {
+                (16, 2)
+            } else if rest.starts_with("0b") || rest.starts_with("0B") {
+                (2, 2)
+            } else {
+                (10, 0)
+            };
+            let end = (pos + skip..chars.len())
+                .find(|&n| !ident(chars[n]))
+                .unwrap_or(chars.len());
+            let digits: String = chars[(pos + skip)..end].iter().collect();
+            pos = end;
+            let n = i64::from_str_radix(&digits, radix).map_err(|_| {
+                (
+                    column,
+                    format!(
+                        "Invalid number '{}'",
+                        rest.split_whitespace().next().unwrap_or("")
+                    ),
+                )
+            })?;
+            Tok::Number(n)
+        } else if ident(c) {
+            let end = (pos..chars.len())
+                .find(|&n| !ident(chars[n]))
+                .unwrap_or(chars.len());
+            let name: String = chars[pos..end].iter().collect();
+            pos = end;
+            Tok::Ident(name)
+        } else {
+            match PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+                Some(p) => {
+                    pos += p.len();
+                    Tok::Punct(p)
+                }
+                None => {
+                    return Err((
+                        column,
+                        format!("Unexpected character '{}'", c),
+                    ))
+                }
+            }
+        };
+        tokens.push(Token { tok, column });
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disasm::{self, Syntax};
+
+    fn assemble_str(source: &str) -> Result<Assembly, AsmError> {
+        assemble(source, "test.asm", |name| {
+            Err(io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+        })
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = assemble_str(source).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn test_instructions() {
+        let source = "
+            start:  CLS
+                    LD V0, 5        ; Comment
+                    LD I, sprite
+                    DRW V0, V1, 5
+                    SE V0, V1
+                    ADD I, VA
+                    LD [I], V3
+                    LD Vf, [i]
+                    shr v2
+                    JP V0, start
+                    LD I, LONG sprite
+                    JP start
+            sprite: DB #F0, $90, 0xF0, 0b10010000, -1
+                    DW 1234, sprite";
+        let assembly = assemble_str(source).unwrap();
+
+        assert_eq!(
+            assembly.rom,
+            vec![
+                0x00, 0xE0, 0x60, 0x05, 0xA2, 0x1A, 0xD0, 0x15, 0x50, 0x10,
+                0xFA, 0x1E, 0xF3, 0x55, 0xFF, 0x65, 0x82, 0x26, 0xB2, 0x00,
+                0xF0, 0x00, 0x02, 0x1A, 0x12, 0x00, 0xF0, 0x90, 0xF0, 0x90,
+                0xFF, 0x04, 0xD2, 0x02, 0x1A
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constants_and_expressions() {
+        let source = "
+            SPEED   EQU 2
+            DOUBLE  = SPEED * 2
+                    LD V0, (DOUBLE + 1) << 1 | 1
+                    LD V1, end - start
+                    LD V2, -SPEED
+                    LD V3, ~0 & #0F
+            start:  DB \"Hi\", 10 % 4
+            end:";
+        let assembly = assemble_str(source).unwrap();
+
+        assert_eq!(
+            assembly.rom,
+            [0x60, 0x0B, 0x61, 0x03, 0x62, 0xFE, 0x63, 0x0F, b'H', b'i', 2]
+        );
+    }
+
+    #[test]
+    fn test_include_and_listing() {
+        let files = |name: &str| match name {
+            "src/sprites.asm" => Ok("ball: DB #C0, #C0\n".to_string()),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "not found")),
+        };
+        let source = "LD I, ball\nINCLUDE \"sprites.asm\"\nDB 1, 2, 3, 4, 5";
+        let assembly = assemble(source, "src/main.asm", files).unwrap();
+
+        assert_eq!(assembly.rom, [0xA2, 0x02, 0xC0, 0xC0, 1, 2, 3, 4, 5]);
+        assert_eq!(assembly.listing.len(), 4);
+        assert_eq!(assembly.listing[2].file, "src/sprites.asm");
+        assert_eq!(assembly.listing[2].addr, Some(0x202));
+        assert_eq!(assembly.listing[1].addr, None);
+
+        let listing = assembly.listing_text();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "200  A202      src/main.asm:1   LD I, ball");
+        assert_eq!(lines[4], "208  05");
+
+        let e = assemble("INCLUDE \"x.asm\"", "main.asm", files).unwrap_err();
+        assert_eq!((e.line, e.column), (1, 1));
+        assert!(e.message.starts_with("Can't include 'x.asm'"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error("  CLS\n  LD V0, missing"),
+            (2, 10, "Unknown symbol 'missing'".to_string())
+        );
+        assert_eq!(
+            error("LD V0, 256"),
+            (1, 8, "Value 256 is out of range (-128 to 255)".to_string())
+        );
+        assert_eq!(
+            error("  FOO V0"),
+            (1, 3, "Unknown instruction 'FOO'".to_string())
+        );
+        assert_eq!(
+            error("  DRW V0, 5"),
+            (1, 3, "Invalid operands for DRW".to_string())
+        );
+        assert_eq!(
+            error("a: CLS\na: CLS"),
+            (2, 1, "'a' is already defined".to_string())
+        );
+        assert_eq!(
+            error("X EQU Y\nY EQU X\nLD V0, X"),
+            (2, 7, "'X' is defined in terms of itself".to_string())
+        );
+        assert_eq!(error("LD V0, (1").1, 8);
+        assert_eq!(error("DB 1 @").1, 6);
+        assert_eq!(error("JP 1 / 0").2, "Division by zero");
+        assert_eq!(
+            error("  LD V0, 1 2"),
+            (1, 12, "Unexpected text after expression".to_string())
+        );
+
+        assert_eq!(
+            assemble_str("JP 4096").unwrap_err().to_string(),
+            "test.asm:1:4: Value 4096 is out of range (0 to 4095)"
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let rom = [
+            0x00, 0xE0, 0xA2, 0x0C, 0x22, 0x0A, 0x30, 0x01, 0xF0, 0x00, 0x02,
+            0x0C, 0x12, 0x04, 0x00, 0xEE, 0xF0, 0x90,
+        ];
+        let source = disasm::disassemble(&rom, Syntax::Cowgod);
+        assert_eq!(assemble_str(&source).unwrap().rom, rom);
+    }
+}
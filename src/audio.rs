@@ -0,0 +1,218 @@
+use rodio::{Sink, Source};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::time::Duration;
+
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+const SAMPLE_RATE: u32 = 44100;
+
+// Tone played while the sound timer is non-zero
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beep {
+    pub frequency: f32, // Hz
+    pub volume: f32,    // 0.0 (silent) to 1.0 (full scale)
+}
+
+impl Default for Beep {
+    fn default() -> Beep {
+        Beep {
+            frequency: DEFAULT_FREQUENCY,
+            volume: DEFAULT_VOLUME,
+        }
+    }
+}
+
+// Destination for the buzzer. `frame` is called once per 60Hz frame with
+// whether the buzzer should be sounding during that frame, and `finish`
+// once when emulation stops
+pub trait AudioSink {
+    fn frame(&mut self, beeping: bool) -> Result<(), io::Error>;
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+// Discards all sound. Used when there is no audio device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn frame(&mut self, _beeping: bool) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+// Endless square wave at the beep frequency and volume
+struct SquareWave {
+    beep: Beep,
+    phase: f32, // Position within the current cycle, from 0.0 to 1.0
+}
+
+impl SquareWave {
+    fn new(beep: Beep) -> SquareWave {
+        SquareWave { beep, phase: 0.0 }
+    }
+}
+
+impl Iterator for SquareWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let level = if self.phase < 0.5 {
+            self.beep.volume
+        } else {
+            -self.beep.volume
+        };
+
+        self.phase += self.beep.frequency / (SAMPLE_RATE as f32);
+        self.phase = self.phase.fract();
+        Some(level)
+    }
+}
+
+impl Source for SquareWave {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Plays the beep on the default output device
+pub struct DeviceSink {
+    sink: Sink,
+}
+
+impl DeviceSink {
+    // Returns None if the machine has no audio output device
+    pub fn open(beep: Beep) -> Option<DeviceSink> {
+        let device = rodio::default_output_device()?;
+        let sink = Sink::new(&device);
+
+        sink.pause();
+        sink.append(SquareWave::new(beep));
+        Some(DeviceSink { sink })
+    }
+}
+
+impl AudioSink for DeviceSink {
+    fn frame(&mut self, beeping: bool) -> Result<(), io::Error> {
+        if beeping && self.sink.is_paused() {
+            self.sink.play();
+        } else if !beeping && !self.sink.is_paused() {
+            self.sink.pause();
+        }
+        Ok(())
+    }
+}
+
+// Writes the beep to a 16-bit mono PCM WAV file, one frame at a time.
+// Silent frames are written too, so the file lines up with gameplay
+pub struct WavSink {
+    file: BufWriter<File>,
+    wave: SquareWave,
+    samples: u32,
+}
+
+const WAV_HEADER_LEN: u32 = 44;
+
+impl WavSink {
+    pub fn create(fname: &str, beep: Beep) -> Result<WavSink, io::Error> {
+        let mut file = BufWriter::new(File::create(fname)?);
+
+        // Sizes are filled in by finish()
+        file.write_all(&wav_header(0))?;
+        Ok(WavSink {
+            file,
+            wave: SquareWave::new(beep),
+            samples: 0,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn frame(&mut self, beeping: bool) -> Result<(), io::Error> {
+        for _ in 0..(SAMPLE_RATE / 60) {
+            let level = self.wave.next().unwrap_or(0.0);
+            let sample = if beeping {
+                (level * (i16::max_value() as f32)) as i16
+            } else {
+                0
+            };
+            self.file.write_all(&sample.to_le_bytes())?;
+            self.samples += 1;
+        }
+        Ok(())
+    }
+
+    // Patch the header with the final data size and flush the file
+    fn finish(&mut self) -> Result<(), io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&wav_header(self.samples))?;
+        self.file.flush()
+    }
+}
+
+fn wav_header(samples: u32) -> Vec<u8> {
+    let data_len = samples * 2;
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk length
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // Byte rate
+    header.extend_from_slice(&2u16.to_le_bytes()); // Block align
+    header.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_wav_sink() {
+        let path = env::temp_dir().join("chip8_test_wav_sink.wav");
+        let fname = path.to_str().unwrap();
+
+        let mut sink = WavSink::create(fname, Beep::default()).unwrap();
+        sink.frame(true).unwrap();
+        sink.frame(false).unwrap();
+        sink.finish().unwrap();
+
+        let wav = fs::read(fname).unwrap();
+        fs::remove_file(fname).unwrap();
+
+        let samples = (SAMPLE_RATE / 60) as usize * 2;
+        assert_eq!(wav.len(), 44 + samples * 2);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[40..44], ((samples * 2) as u32).to_le_bytes());
+
+        // Beeping frame starts high, silent frame is all zero
+        assert!(i16::from_le_bytes([wav[44], wav[45]]) > 0);
+        assert!(wav[(44 + samples)..].iter().all(|&b| b == 0));
+    }
+}
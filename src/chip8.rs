@@ -1,10 +1,17 @@
+use crate::instruction::{decode, Instruction};
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::savestate::{self, Reader, Writer};
+use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
 #[derive(Debug)]
 pub struct Chip8 {
-    memory: Vec<u8>,     // 4,096 bytes of RAM
+    memory: Vec<u8>,     // 4,096 (or 65,536) bytes of RAM
     v: Vec<u8>,          // 16 general-purpose registers
     i: u16,              // 1 I-register
     delay_timer: u8,     // Decrements at a rate of 60Hz
@@ -13,7 +20,22 @@ pub struct Chip8 {
     sp: u8,              // Stack pointer
     stack: Vec<u16>,     // 16 stack levels
     keyboard: Vec<bool>, // 16-key hexadecimal keypad
-    display: Vec<bool>,  // 64 x 32 monochrome display
+    display: Vec<u8>,    // 64 x 32 (or 128 x 64) pixels, one bit per plane
+    hires: bool,         // SUPER-CHIP 128 x 64 display mode
+    rpl: Vec<u8>,        // SUPER-CHIP RPL user flags
+    exited: bool,        // SUPER-CHIP 00FD has stopped the interpreter
+    planes: u8,          // XO-CHIP bitplanes selected for drawing
+    pattern: Vec<u8>,    // XO-CHIP 128-bit audio pattern buffer
+    pitch: u8,           // XO-CHIP audio pattern playback rate
+    quirks: Quirks,      // Interpretation of ambiguous instructions
+    vblank_wait: bool,   // Dxyn is waiting for the next vertical blank
+    key_wait: KeyWait,   // Progress of a blocked Fx0A
+    rng: Rng,            // Source of Cxkk random bytes
+    watching: bool,      // Record memory accesses made by instructions
+    // Keypad at the last vertical blank, to find presses and releases
+    last_keyboard: Vec<bool>,
+    // Accesses made by the last instruction executed, if watching
+    accesses: Vec<MemoryAccess>,
 }
 
 // Hexadecimal sprites. Stored in area of RAM reserved for interpreter
@@ -36,12 +58,111 @@ const HEX_SPRITES: &[u8; 80] = &[
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SUPER-CHIP 8x10 hexadecimal sprites. Stored after the small sprites
+const BIG_HEX_SPRITES: &[u8; 160] = &[
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+const BIG_HEX_SPRITES_ADDR: usize = 0x50;
+
 pub const DISPLAY_W: usize = 64;
 pub const DISPLAY_H: usize = 32;
+pub const HIRES_DISPLAY_W: usize = 128;
+pub const HIRES_DISPLAY_H: usize = 64;
+
+/// XO-CHIP draws to two bitplanes, giving four colours per pixel
+pub const PLANE_COUNT: usize = 2;
+
+/// Result of successfully executing a single instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    Executed,         // Instruction completed and PC advanced
+    WaitingForKey,    // Fx0A is blocked until a key is pressed and released
+    WaitingForVBlank, // Dxyn is blocked until the next vertical blank
+    Exit,             // 00FD has stopped the interpreter
+}
+
+// Fx0A waits for a key to go down and then come up again, like the COSMAC
+// VIP. This is how far it has got
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyWait {
+    Idle,        // No Fx0A is blocked
+    Press,       // Waiting for a key to go down
+    Release(u8), // Waiting for this key to come up
+}
+
+/// How an instruction used a byte of RAM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Fetch, // Read as part of an instruction
+    Read,  // Read as data, including sprites drawn by Dxyn
+    Write,
+}
+
+/// A single byte of RAM read or written by the instruction at `pc`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub pc: u16,
+    pub addr: usize,
+    pub kind: AccessKind,
+    pub value: u8, // Value read, or the new value written
+}
+
+/// Conditions that stop execution. Each carries the address and opcode of
+/// the offending instruction so frontends can report where the ROM failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16, opcode: u16 },
+    MemoryOutOfBounds { pc: u16, opcode: u16, addr: usize },
+    PcOutOfRange { pc: u16, opcode: u16 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:04X} at {:03X}", opcode, pc)
+            }
+            Chip8Error::StackOverflow { pc, opcode } => {
+                write!(f, "Stack overflow by {:04X} at {:03X}", opcode, pc)
+            }
+            Chip8Error::StackUnderflow { pc, opcode } => {
+                write!(f, "Stack underflow by {:04X} at {:03X}", opcode, pc)
+            }
+            Chip8Error::MemoryOutOfBounds { pc, opcode, addr } => write!(
+                f,
+                "Memory access to {:X} out of bounds by {:04X} at {:03X}",
+                addr, opcode, pc
+            ),
+            Chip8Error::PcOutOfRange { pc, opcode } => {
+                write!(f, "PC out of range after {:04X} at {:03X}", opcode, pc)
+            }
+        }
+    }
+}
+
+impl error::Error for Chip8Error {}
 
 impl Chip8 {
     fn init() -> Chip8 {
         let mut memory = HEX_SPRITES.to_vec();
+        memory.extend_from_slice(BIG_HEX_SPRITES);
         memory.resize(4096, 0);
 
         Chip8 {
@@ -54,103 +175,388 @@ impl Chip8 {
             sp: 0,
             stack: vec![0; 16],
             keyboard: vec![false; 16],
-            display: vec![false; DISPLAY_W * DISPLAY_H],
+            display: vec![0; DISPLAY_W * DISPLAY_H],
+            hires: false,
+            rpl: vec![0; 16],
+            exited: false,
+            planes: 0x1,
+            pattern: Vec::new(),
+            pitch: 64,
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            key_wait: KeyWait::Idle,
+            rng: Rng::new(rand::random()),
+            watching: false,
+            last_keyboard: vec![false; 16],
+            accesses: Vec::new(),
         }
     }
 
-    pub fn load_rom(fname: &str) -> Result<Chip8, io::Error> {
+    /// Create an interpreter with no program loaded. Memory is sized for
+    /// `platform` and holds only the built-in font sprites
+    pub fn new(platform: Platform, quirks: Quirks) -> Chip8 {
         let mut chip8 = Chip8::init();
+        chip8.memory.resize(platform.memory_size(), 0);
+        chip8.quirks = quirks;
+        chip8
+    }
 
-        let mut f = File::open(fname)?;
-        let mut buf = Vec::new();
+    /// Create an interpreter with `rom` loaded at 0x200, ready to run
+    pub fn from_bytes(
+        rom: &[u8],
+        platform: Platform,
+        quirks: Quirks,
+    ) -> Result<Chip8, io::Error> {
+        let mut chip8 = Chip8::new(platform, quirks);
 
-        f.read_to_end(&mut buf)?;
-        if buf.len() > 4096 - 0x200 {
+        if rom.len() > chip8.memory.len() - 0x200 {
             Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "ROM too big for RAM",
             ))
         } else {
-            chip8.memory[0x200..(0x200 + buf.len())].copy_from_slice(&buf[..]);
+            chip8.memory[0x200..(0x200 + rom.len())].copy_from_slice(rom);
             Ok(chip8)
         }
     }
 
-    pub fn display(&self) -> &[bool] {
+    /// Create an interpreter with the ROM file `fname` loaded at 0x200
+    pub fn load_rom(
+        fname: &str,
+        platform: Platform,
+        quirks: Quirks,
+    ) -> Result<Chip8, io::Error> {
+        let mut f = File::open(fname)?;
+        let mut buf = Vec::new();
+
+        f.read_to_end(&mut buf)?;
+        Chip8::from_bytes(&buf, platform, quirks)
+    }
+
+    /// Serialize the entire machine state, including the quirks and the
+    /// random number generator, in a versioned binary format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+
+        w.sized_bytes(&self.memory);
+        w.bytes(&self.v);
+        w.u16(self.i);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        w.u16(self.pc);
+        w.u8(self.sp);
+        for &addr in &self.stack {
+            w.u16(addr);
+        }
+        for &key in self.keyboard.iter().chain(&self.last_keyboard) {
+            w.bool(key);
+        }
+        w.bool(self.hires);
+        w.sized_bytes(&self.display);
+        w.bytes(&self.rpl);
+        w.bool(self.exited);
+        w.u8(self.planes);
+        w.sized_bytes(&self.pattern);
+        w.u8(self.pitch);
+        w.bool(self.quirks.shift_uses_vy);
+        w.bool(self.quirks.load_store_increments_i);
+        w.bool(self.quirks.jump_uses_vx);
+        w.bool(self.quirks.logic_resets_vf);
+        w.bool(self.quirks.clip_sprites);
+        w.bool(self.quirks.display_wait);
+        w.bool(self.quirks.key_wait_stops_timers);
+        w.bool(self.vblank_wait);
+        w.u8(match self.key_wait {
+            KeyWait::Idle => 0,
+            KeyWait::Press => 1,
+            KeyWait::Release(key) => 0x10 | key,
+        });
+        self.rng.save(&mut w);
+        w.finish()
+    }
+
+    /// Restore a machine from a state written by `save_state`
+    pub fn from_state(state: &[u8]) -> Result<Chip8, io::Error> {
+        let mut r = Reader::new(state)?;
+        let mut c = Chip8::init();
+
+        c.memory = r.sized_bytes()?.to_vec();
+        if c.memory.len() != Platform::Chip8.memory_size()
+            && c.memory.len() != Platform::XoChip.memory_size()
+        {
+            return Err(savestate::invalid_data(format!(
+                "Invalid memory size {} in save state",
+                c.memory.len()
+            )));
+        }
+
+        c.v.copy_from_slice(r.bytes(16)?);
+        c.i = r.u16()?;
+        c.delay_timer = r.u8()?;
+        c.sound_timer = r.u8()?;
+        c.pc = r.u16()?;
+        c.sp = r.u8()?;
+        if (c.sp as usize) > c.stack.len() {
+            return Err(savestate::invalid_data(format!(
+                "Invalid stack pointer {} in save state",
+                c.sp
+            )));
+        }
+        for addr in &mut c.stack {
+            *addr = r.u16()?;
+        }
+        for key in c.keyboard.iter_mut().chain(&mut c.last_keyboard) {
+            *key = r.bool()?;
+        }
+
+        c.hires = r.bool()?;
+        c.display = r.sized_bytes()?.to_vec();
+        if c.display.len() != c.display_width() * c.display_height() {
+            return Err(savestate::invalid_data(format!(
+                "Invalid display size {} in save state",
+                c.display.len()
+            )));
+        }
+
+        c.rpl.copy_from_slice(r.bytes(16)?);
+        c.exited = r.bool()?;
+        c.planes = r.u8()?;
+        c.pattern = r.sized_bytes()?.to_vec();
+        c.pitch = r.u8()?;
+        c.quirks = Quirks {
+            shift_uses_vy: r.bool()?,
+            load_store_increments_i: r.bool()?,
+            jump_uses_vx: r.bool()?,
+            logic_resets_vf: r.bool()?,
+            clip_sprites: r.bool()?,
+            display_wait: r.bool()?,
+            key_wait_stops_timers: r.bool()?,
+        };
+        c.vblank_wait = r.bool()?;
+        c.key_wait = match r.u8()? {
+            0 => KeyWait::Idle,
+            1 => KeyWait::Press,
+            key @ 0x10..=0x1F => KeyWait::Release(key & 0xF),
+            n => {
+                return Err(savestate::invalid_data(format!(
+                    "Invalid key wait {} in save state",
+                    n
+                )))
+            }
+        };
+        c.rng = Rng::load(&mut r)?;
+        r.finish()?;
+
+        Ok(c)
+    }
+
+    /// Pixels of the current display mode, row by row. Each pixel holds
+    /// one bit per plane; bit 0 is plane 1
+    pub fn display(&self) -> &[u8] {
         &self.display[..]
     }
 
+    /// Whether the SUPER-CHIP 128 x 64 display mode is active
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn display_width(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_W
+        } else {
+            DISPLAY_W
+        }
+    }
+
+    pub fn display_height(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_H
+        } else {
+            DISPLAY_H
+        }
+    }
+
+    /// Press `key` (0x0 to 0xF) on the hexadecimal keypad
     pub fn set_key(&mut self, key: u8) {
         self.keyboard[key as usize] = true;
     }
 
+    /// Release `key` (0x0 to 0xF) on the hexadecimal keypad
+    pub fn release_key(&mut self, key: u8) {
+        self.keyboard[key as usize] = false;
+    }
+
+    /// Release every key on the keypad
     pub fn reset_keys(&mut self) {
         for key in &mut self.keyboard {
             *key = false
         }
     }
 
-    pub fn execute_opcode(&mut self) {
-        // Instructions are 2 bytes long and are stored most
-        // significant byte first
-        let hi = self.memory[self.pc as usize] as u16;
-        let lo = self.memory[(self.pc as usize) + 1] as u16;
-        let opcode = hi << 8 | lo;
+    /// Whether `key` (0x0 to 0xF) is held down
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keyboard[key as usize]
+    }
+
+    /// State of all 16 keys, indexed by key
+    pub fn keys(&self) -> &[bool] {
+        &self.keyboard[..]
+    }
 
-        self.execute_opcode_internal(opcode);
-    }
-
-    fn execute_opcode_internal(&mut self, opcode: u16) {
-        match opcode & 0xF000 {
-            0x0000 => match opcode {
-                0x00E0 => self.cls(),
-                0x00EE => self.ret(),
-                _ => panic!("Unknown opcode: {:X?}", opcode),
-            },
-            0x1000 => self.jp1(opcode),
-            0x2000 => self.call(opcode),
-            0x3000 => self.se1(opcode),
-            0x4000 => self.sne1(opcode),
-            0x5000 => self.se2(opcode),
-            0x6000 => self.ld01(opcode),
-            0x7000 => self.add1(opcode),
-            0x8000 => match opcode & 0x000F {
-                0x0000 => self.ld02(opcode),
-                0x0001 => self.or(opcode),
-                0x0002 => self.and(opcode),
-                0x0003 => self.xor(opcode),
-                0x0004 => self.add2(opcode),
-                0x0005 => self.sub(opcode),
-                0x0006 => self.shr(opcode),
-                0x0007 => self.subn(opcode),
-                0x000E => self.shl(opcode),
-                _ => panic!("Unknown opcode: {:X?}", opcode),
-            },
-            0x9000 => self.sne2(opcode),
-            0xA000 => self.ld03(opcode),
-            0xB000 => self.jp2(opcode),
-            0xC000 => self.rnd(opcode),
-            0xD000 => self.drw(opcode),
-            0xE000 => match opcode & 0x00FF {
-                0x009E => self.skp(opcode),
-                0x00A1 => self.sknp(opcode),
-                _ => panic!("Unknown opcode: {:X?}", opcode),
-            },
-            0xF000 => match opcode & 0x00FF {
-                0x0007 => self.ld04(opcode),
-                0x000A => self.ld05(opcode),
-                0x0015 => self.ld06(opcode),
-                0x0018 => self.ld07(opcode),
-                0x001E => self.add3(opcode),
-                0x0029 => self.ld08(opcode),
-                0x0033 => self.ld09(opcode),
-                0x0055 => self.ld10(opcode),
-                0x0065 => self.ld11(opcode),
-                _ => panic!("Unknown opcode: {:X?}", opcode),
-            },
-            _ => panic!("Unknown opcode: {:X?}", opcode),
+    /// Set the whole keypad at once. Bit n of `keys` holds key n
+    pub fn set_keypad(&mut self, keys: u16) {
+        for (n, key) in self.keyboard.iter_mut().enumerate() {
+            *key = keys & (1 << n) != 0;
         }
+    }
+
+    /// State of the whole keypad. Bit n holds key n
+    pub fn keypad(&self) -> u16 {
+        self.keyboard
+            .iter()
+            .enumerate()
+            .filter(|&(_, &down)| down)
+            .fold(0, |keys, (n, _)| keys | 1 << n)
+    }
+
+    /// Whether `key` has gone down since the last vertical blank
+    pub fn key_pressed(&self, key: u8) -> bool {
+        self.keyboard[key as usize] && !self.last_keyboard[key as usize]
+    }
+
+    /// Whether `key` has come up since the last vertical blank
+    pub fn key_released(&self, key: u8) -> bool {
+        !self.keyboard[key as usize] && self.last_keyboard[key as usize]
+    }
+
+    /// Whether an Fx0A instruction is blocked waiting for a key, so a
+    /// frontend can show that input is expected
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait != KeyWait::Idle
+    }
+
+    /// Program counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// I-register
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    /// General-purpose registers V0 to VF
+    pub fn v(&self) -> &[u8] {
+        &self.v[..]
+    }
+
+    /// Set register Vx. Panics if `x` is greater than 0xF
+    pub fn set_v(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+    }
+
+    /// Addresses of the 2nnn calls currently on the stack, oldest first.
+    /// 00EE returns to the instruction after the top one
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..(self.sp as usize)]
+    }
+
+    /// Replace the stack contents, oldest first. Panics if `stack` holds
+    /// more than 16 addresses
+    pub fn set_stack(&mut self, stack: &[u16]) {
+        assert!(stack.len() <= self.stack.len(), "Stack holds 16 addresses");
+
+        self.stack[..stack.len()].copy_from_slice(stack);
+        for addr in &mut self.stack[stack.len()..] {
+            *addr = 0;
+        }
+        self.sp = stack.len() as u8;
+    }
+
+    /// All of RAM, including the font sprites below 0x200
+    pub fn memory(&self) -> &[u8] {
+        &self.memory[..]
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[..]
+    }
+
+    /// The two bytes at `addr` as an opcode, most significant byte first.
+    /// Bytes past the end of memory read as 0
+    pub fn opcode_at(&self, addr: u16) -> u16 {
+        let byte = |a: usize| self.memory.get(a).cloned().unwrap_or(0) as u16;
+        byte(addr as usize) << 8 | byte(addr as usize + 1)
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    /// Replace the random number generator used by Cxkk, e.g. with a
+    /// seeded one for reproducible runs
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Record the memory accesses made by each instruction, for
+    /// `memory_accesses`. Off by default, as it slows execution down
+    pub fn set_watch_memory(&mut self, enabled: bool) {
+        self.watching = enabled;
+        self.accesses.clear();
+    }
+
+    /// Every byte of RAM read or written by the last instruction executed,
+    /// in order, including the instruction fetch. Empty unless enabled
+    /// with `set_watch_memory`
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    /// Whether the ROM has stopped the interpreter with 00FD
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
 
+    /// Decrement the delay and sound timers. Must be called at 60Hz,
+    /// independently of how many instructions are executed per frame
+    pub fn tick_timers(&mut self) {
+        if self.quirks.key_wait_stops_timers && self.waiting_for_key() {
+            return;
+        }
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -159,340 +565,894 @@ impl Chip8 {
         }
     }
 
+    /// Signal the start of a new frame to instructions that wait for it.
+    /// Key presses and releases after this are edges for the next frame
+    pub fn vblank(&mut self) {
+        self.vblank_wait = false;
+        self.last_keyboard.copy_from_slice(&self.keyboard);
+    }
+
+    /// XO-CHIP audio pattern, if the ROM has loaded one with F002
+    pub fn audio_pattern(&self) -> Option<&[u8]> {
+        if self.pattern.is_empty() {
+            None
+        } else {
+            Some(&self.pattern[..])
+        }
+    }
+
+    /// Playback rate of the audio pattern in bits per second
+    pub fn audio_pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf(((self.pitch as f32) - 64.0) / 48.0)
+    }
+
+    /// Fetch and execute the instruction at the PC. Timers are not
+    /// ticked; frontends call `tick_timers` and `vblank` once per frame, or
+    /// use `run_frame`
+    pub fn execute_opcode(&mut self) -> Result<StepOutcome, Chip8Error> {
+        self.accesses.clear();
+        if self.exited {
+            return Ok(StepOutcome::Exit);
+        }
+        if self.vblank_wait {
+            return Ok(StepOutcome::WaitingForVBlank);
+        }
+
+        // The PC can only be out of range here if it was already out of
+        // range before this call, in which case there is no opcode to report
+        let pc = self.pc as usize;
+        if pc + 1 >= self.memory.len() {
+            return Err(Chip8Error::PcOutOfRange {
+                pc: self.pc,
+                opcode: 0,
+            });
+        }
+
+        // Instructions are 2 bytes long and are stored most
+        // significant byte first
+        let hi = self.read(pc, AccessKind::Fetch) as u16;
+        let lo = self.read(pc + 1, AccessKind::Fetch) as u16;
+        let opcode = hi << 8 | lo;
+
+        self.execute_opcode_internal(opcode)
+    }
+
+    /// Run one 60Hz frame: execute up to `instructions` instructions,
+    /// stopping early if the ROM blocks or exits, then tick the timers
+    pub fn run_frame(
+        &mut self,
+        instructions: u32,
+    ) -> Result<StepOutcome, Chip8Error> {
+        let outcome = self.run_frame_with(instructions, |chip8| {
+            chip8.execute_opcode().map(Some)
+        })?;
+        Ok(outcome.unwrap_or(StepOutcome::Executed))
+    }
+
+    /// Run one frame like `run_frame`, with `step` executing each
+    /// instruction so frontends can log, debug or check them as they run.
+    /// If `step` returns None the frame stops there without ticking the
+    /// timers, and None is returned
+    pub fn run_frame_with<E, F>(
+        &mut self,
+        instructions: u32,
+        mut step: F,
+    ) -> Result<Option<StepOutcome>, E>
+    where
+        F: FnMut(&mut Chip8) -> Result<Option<StepOutcome>, E>,
+    {
+        let mut outcome = StepOutcome::Executed;
+        for _ in 0..instructions {
+            outcome = match step(self)? {
+                Some(outcome) => outcome,
+                None => return Ok(None),
+            };
+            if outcome != StepOutcome::Executed {
+                break;
+            }
+        }
+
+        self.tick_timers();
+        self.vblank();
+        Ok(Some(outcome))
+    }
+
+    /// Execute an instruction as if it had been fetched from the PC. As
+    /// with `execute_opcode`, the timers are not ticked. F000 nnnn reads
+    /// its operand from the two bytes after the PC
+    pub fn execute(
+        &mut self,
+        instruction: Instruction,
+    ) -> Result<StepOutcome, Chip8Error> {
+        self.accesses.clear();
+        self.execute_instruction(instruction)
+    }
+
+    fn execute_opcode_internal(
+        &mut self,
+        opcode: u16,
+    ) -> Result<StepOutcome, Chip8Error> {
+        let instruction =
+            decode(opcode).map_err(|_| Chip8Error::UnknownOpcode {
+                pc: self.pc,
+                opcode,
+            })?;
+        self.execute_instruction(instruction)
+    }
+
+    fn execute_instruction(
+        &mut self,
+        instruction: Instruction,
+    ) -> Result<StepOutcome, Chip8Error> {
+        use crate::instruction::Instruction::*;
+
+        let pc = self.pc;
+        let opcode = instruction.encode();
+        let mut outcome = StepOutcome::Executed;
+
+        match instruction {
+            Cls => self.cls(opcode)?,
+            Ret => self.ret(opcode)?,
+            ScrollDown { n } => self.scroll_down(opcode, n)?,
+            ScrollUp { n } => self.scroll_up(opcode, n)?,
+            ScrollRight => self.scroll_right(opcode)?,
+            ScrollLeft => self.scroll_left(opcode)?,
+            Exit => outcome = self.exit(),
+            LowRes => self.low_res(opcode)?,
+            HighRes => self.high_res(opcode)?,
+            Jump { nnn } => self.jump(nnn),
+            Call { nnn } => self.call(opcode, nnn)?,
+            SkipEqImm { x, kk } => self.skip_eq_imm(opcode, x, kk)?,
+            SkipNeImm { x, kk } => self.skip_ne_imm(opcode, x, kk)?,
+            SkipEqReg { x, y } => self.skip_eq_reg(opcode, x, y)?,
+            StoreRange { x, y } => self.store_range(opcode, x, y)?,
+            LoadRange { x, y } => self.load_range(opcode, x, y)?,
+            LoadImm { x, kk } => self.load_imm(opcode, x, kk)?,
+            AddImm { x, kk } => self.add_imm(opcode, x, kk)?,
+            LoadReg { x, y } => self.load_reg(opcode, x, y)?,
+            Or { x, y } => self.or(opcode, x, y)?,
+            And { x, y } => self.and(opcode, x, y)?,
+            Xor { x, y } => self.xor(opcode, x, y)?,
+            AddReg { x, y } => self.add_reg(opcode, x, y)?,
+            Sub { x, y } => self.sub(opcode, x, y)?,
+            ShiftRight { x, y } => self.shift_right(opcode, x, y)?,
+            SubN { x, y } => self.sub_n(opcode, x, y)?,
+            ShiftLeft { x, y } => self.shift_left(opcode, x, y)?,
+            SkipNeReg { x, y } => self.skip_ne_reg(opcode, x, y)?,
+            LoadI { nnn } => self.load_i(opcode, nnn)?,
+            JumpV0 { nnn } => self.jump_v0(nnn),
+            Random { x, kk } => self.random(opcode, x, kk)?,
+            Draw { x, y, n } => self.draw(opcode, x, y, n)?,
+            SkipKey { x } => self.skip_key(opcode, x)?,
+            SkipNotKey { x } => self.skip_not_key(opcode, x)?,
+            LoadILong => self.load_i_long()?,
+            Plane { n } => self.plane(opcode, n)?,
+            Audio => self.audio(opcode)?,
+            GetDelay { x } => self.get_delay(opcode, x)?,
+            WaitKey { x } => outcome = self.wait_key(opcode, x)?,
+            SetDelay { x } => self.set_delay(opcode, x)?,
+            SetSound { x } => self.set_sound(opcode, x)?,
+            AddI { x } => self.add_i(opcode, x)?,
+            Font { x } => self.font(opcode, x)?,
+            BigFont { x } => self.big_font(opcode, x)?,
+            Bcd { x } => self.bcd(opcode, x)?,
+            Pitch { x } => self.pitch(opcode, x)?,
+            Store { x } => self.store(opcode, x)?,
+            Load { x } => self.load(opcode, x)?,
+            StoreFlags { x } => self.store_flags(opcode, x)?,
+            LoadFlags { x } => self.load_flags(opcode, x)?,
+        }
+
+        // Jumps and skips can move the PC past the end of RAM, where the
+        // next instruction fetch would fail
+        if (self.pc as usize) + 1 >= self.memory.len() {
+            return Err(Chip8Error::PcOutOfRange { pc, opcode });
+        }
+
+        Ok(outcome)
+    }
+
+    // Instructions read and write RAM through these, so that the accesses
+    // can be recorded. Addresses must already have been checked
+    fn read(&mut self, addr: usize, kind: AccessKind) -> u8 {
+        let value = self.memory[addr];
+        if self.watching {
+            self.accesses.push(MemoryAccess {
+                pc: self.pc,
+                addr,
+                kind,
+                value,
+            });
+        }
+        value
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self.memory[addr] = value;
+        if self.watching {
+            self.accesses.push(MemoryAccess {
+                pc: self.pc,
+                addr,
+                kind: AccessKind::Write,
+                value,
+            });
+        }
+    }
+
+    // Check that `len` bytes starting at `addr` lie within RAM
+    fn check_memory(
+        &self,
+        opcode: u16,
+        addr: usize,
+        len: usize,
+    ) -> Result<(), Chip8Error> {
+        if addr + len > self.memory.len() {
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: self.pc,
+                opcode,
+                addr: addr.max(self.memory.len()),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Skip the next instruction if `cond` holds. XO-CHIP's F000 nnnn is
+    // four bytes long, so skipping it also skips over its operand
+    fn skip_if(&mut self, opcode: u16, cond: bool) -> Result<(), Chip8Error> {
+        let next = (self.pc as usize) + 2;
+        let len = if !cond {
+            0
+        } else if self.memory.get(next) == Some(&0xF0)
+            && self.memory.get(next + 1) == Some(&0x00)
+        {
+            4
+        } else {
+            2
+        };
+        self.move_pc(opcode, next + len)
+    }
+
+    // Move the PC to `addr`. Near the top of XO-CHIP's 64 KiB, moving past
+    // an instruction can take it beyond what 16 bits can hold
+    fn move_pc(&mut self, opcode: u16, addr: usize) -> Result<(), Chip8Error> {
+        if addr > 0xFFFF {
+            return Err(Chip8Error::PcOutOfRange {
+                pc: self.pc,
+                opcode,
+            });
+        }
+        self.pc = addr as u16;
+        Ok(())
+    }
+
+    // Move on to the next instruction
+    fn next(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        self.move_pc(opcode, (self.pc as usize) + 2)
+    }
+
+    // Move the selected planes by (dx, dy) pixels, filling with blanks
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let w = self.display_width() as isize;
+        let h = self.display_height() as isize;
+        let old = self.display.clone();
+
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = (x - dx, y - dy);
+                let src = if sx >= 0 && sx < w && sy >= 0 && sy < h {
+                    old[(sy * w + sx) as usize]
+                } else {
+                    0
+                };
+                let i = (y * w + x) as usize;
+                self.display[i] = (old[i] & !self.planes) | (src & self.planes);
+            }
+        }
+    }
+
     // 00E0 : Clear the display
-    fn cls(&mut self) {
+    fn cls(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         for displayed in &mut self.display {
-            *displayed = false;
+            *displayed &= !self.planes;
         }
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // 00EE : Return from a subroutine
-    fn ret(&mut self) {
+    fn ret(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow {
+                pc: self.pc,
+                opcode,
+            });
+        }
+
+        let addr = self.stack[self.sp as usize - 1] as usize;
+        self.move_pc(opcode, addr + 2)?;
         self.sp -= 1;
-        self.pc = self.stack[self.sp as usize];
-        self.pc += 2;
+        Ok(())
+    }
+
+    // 00Cn : Scroll display down n lines
+    fn scroll_down(&mut self, opcode: u16, n: u8) -> Result<(), Chip8Error> {
+        let n = n as isize;
+
+        self.scroll(0, n);
+        self.next(opcode)
+    }
+
+    // 00Dn : Scroll display up n lines
+    fn scroll_up(&mut self, opcode: u16, n: u8) -> Result<(), Chip8Error> {
+        let n = n as isize;
+
+        self.scroll(0, -n);
+        self.next(opcode)
+    }
+
+    // 00FB : Scroll display right 4 pixels
+    fn scroll_right(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        self.scroll(4, 0);
+        self.next(opcode)
+    }
+
+    // 00FC : Scroll display left 4 pixels
+    fn scroll_left(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        self.scroll(-4, 0);
+        self.next(opcode)
+    }
+
+    // 00FD : Exit the interpreter
+    fn exit(&mut self) -> StepOutcome {
+        self.exited = true;
+        StepOutcome::Exit
+    }
+
+    // 00FE : Disable high-resolution mode
+    fn low_res(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        self.set_hires(false);
+        self.next(opcode)
+    }
+
+    // 00FF : Enable high-resolution mode
+    fn high_res(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        self.set_hires(true);
+        self.next(opcode)
+    }
+
+    // Switching resolution resizes and clears the display
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.display = vec![0; self.display_width() * self.display_height()];
     }
 
     // 1nnn : Jump to location nnn
-    fn jp1(&mut self, opcode: u16) {
-        let nnn = opcode & 0x0FFF;
+    fn jump(&mut self, nnn: u16) {
         self.pc = nnn;
     }
 
     // 2nnn : Call subroutine at nnn
-    fn call(&mut self, opcode: u16) {
+    fn call(&mut self, opcode: u16, nnn: u16) -> Result<(), Chip8Error> {
+        if (self.sp as usize) >= self.stack.len() {
+            return Err(Chip8Error::StackOverflow {
+                pc: self.pc,
+                opcode,
+            });
+        }
+
         self.stack[self.sp as usize] = self.pc;
         self.sp += 1;
-        self.pc = opcode & 0x0FFF;
+        self.pc = nnn;
+        Ok(())
     }
 
     // 3xkk : Skip next instruction if Vx == kk
-    fn se1(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-
-        self.pc += if self.v[x] == kk { 4 } else { 2 };
+    fn skip_eq_imm(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        kk: u8,
+    ) -> Result<(), Chip8Error> {
+        self.skip_if(opcode, self.v[x] == kk)
     }
 
     // 4xkk : Skip next instruction if Vx != kk
-    fn sne1(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-
-        self.pc += if self.v[x] != kk { 4 } else { 2 };
+    fn skip_ne_imm(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        kk: u8,
+    ) -> Result<(), Chip8Error> {
+        self.skip_if(opcode, self.v[x] != kk)
     }
 
     // 5xy0 : Skip next instruction if Vx == Vy
-    fn se2(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
+    fn skip_eq_reg(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        self.skip_if(opcode, self.v[x] == self.v[y])
+    }
+
+    // 5xy2 : Store registers Vx through Vy in memory starting at location I
+    fn store_range(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        let n = if x <= y { y - x } else { x - y } + 1;
+
+        self.check_memory(opcode, self.i as usize, n)?;
+
+        // Registers are stored in descending order if x > y
+        for offset in 0..n {
+            let r = if x <= y { x + offset } else { x - offset };
+            self.write((self.i as usize) + offset, self.v[r]);
+        }
+        self.next(opcode)
+    }
+
+    // 5xy3 : Read registers Vx through Vy from memory starting at location I
+    fn load_range(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        let n = if x <= y { y - x } else { x - y } + 1;
 
-        self.pc += if self.v[x] == self.v[y] { 4 } else { 2 };
+        self.check_memory(opcode, self.i as usize, n)?;
+
+        for offset in 0..n {
+            let r = if x <= y { x + offset } else { x - offset };
+            self.v[r] = self.read((self.i as usize) + offset, AccessKind::Read);
+        }
+        self.next(opcode)
     }
 
     // 6xkk : Set Vx = kk
-    fn ld01(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-
+    fn load_imm(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        kk: u8,
+    ) -> Result<(), Chip8Error> {
         self.v[x] = kk;
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // 7xkk : Set Vx = Vx + kk
-    fn add1(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-
-        self.v[x] += kk;
-        self.pc += 2;
+    fn add_imm(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        kk: u8,
+    ) -> Result<(), Chip8Error> {
+        self.v[x] = self.v[x].wrapping_add(kk);
+        self.next(opcode)
     }
 
     // 8xy0 : Set Vx = Vy
-    fn ld02(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn load_reg(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         self.v[x] = self.v[y];
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // 8xy1 : Set Vx = Vx OR Vy
-    fn or(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn or(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         self.v[x] |= self.v[y];
-        self.pc += 2;
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+        self.next(opcode)
     }
 
     // 8xy2 : Set Vx = Vx AND Vy
-    fn and(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn and(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         self.v[x] &= self.v[y];
-        self.pc += 2;
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+        self.next(opcode)
     }
 
     // 8xy3 : Set Vx = Vx XOR Vy
-    fn xor(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn xor(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         self.v[x] ^= self.v[y];
-        self.pc += 2;
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+        self.next(opcode)
     }
 
     // 8xy4 : Set Vx = Vx + Vy, set VF = carry
-    fn add2(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn add_reg(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         let res = (self.v[x] as u16) + (self.v[y] as u16);
 
-        self.v[0xF] = if (res & 0xFF00) > 0 { 1 } else { 0 };
+        // The flag is written last, so it wins when x is F
         self.v[x] = (res & 0x00FF) as u8;
-        self.pc += 2;
+        self.v[0xF] = if (res & 0xFF00) > 0 { 1 } else { 0 };
+        self.next(opcode)
     }
 
     // 8xy5 : Set Vx = Vx - Vy, set VF = NOT borrow
-    fn sub(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn sub(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         let res = (self.v[x] as i16) - (self.v[y] as i16);
 
-        self.v[0xF] = if res > 0 { 1 } else { 0 };
         self.v[x] = (res & 0x00FF) as u8;
-        self.pc += 2;
+        self.v[0xF] = if res >= 0 { 1 } else { 0 };
+        self.next(opcode)
     }
 
     // 8xy6 : Set Vx = Vx SHR 1
-    fn shr(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn shift_right(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        let val = if self.quirks.shift_uses_vy {
+            self.v[y]
+        } else {
+            self.v[x]
+        };
 
-        self.v[0xF] = self.v[x] & 0x01;
-        self.v[x] >>= 1;
-        self.pc += 2;
+        self.v[x] = val >> 1;
+        self.v[0xF] = val & 0x01;
+        self.next(opcode)
     }
 
     // 8xy7 : Set Vx = Vy - Vx, set VF = NOT borrow
-    fn subn(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
+    fn sub_n(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
         let res = (self.v[y] as i16) - (self.v[x] as i16);
 
-        self.v[0xF] = if res > 0 { 1 } else { 0 };
         self.v[x] = (res & 0x00FF) as u8;
-        self.pc += 2;
+        self.v[0xF] = if res >= 0 { 1 } else { 0 };
+        self.next(opcode)
     }
 
     // 8xyE : Set Vx = Vx SHL 1
-    fn shl(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn shift_left(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        let val = if self.quirks.shift_uses_vy {
+            self.v[y]
+        } else {
+            self.v[x]
+        };
 
-        self.v[0xF] = self.v[x] >> 7;
-        self.v[x] <<= 1;
-        self.pc += 2;
+        self.v[x] = val << 1;
+        self.v[0xF] = val >> 7;
+        self.next(opcode)
     }
 
     // 9xy0 : Skip next instruction if Vx != Vy
-    fn sne2(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-        self.pc += if self.v[x] != self.v[y] { 4 } else { 2 };
+    fn skip_ne_reg(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+    ) -> Result<(), Chip8Error> {
+        self.skip_if(opcode, self.v[x] != self.v[y])
     }
 
     // Annn : Set I = nnn
-    fn ld03(&mut self, opcode: u16) {
-        self.i = opcode & 0x0FFF;
-        self.pc += 2;
+    fn load_i(&mut self, opcode: u16, nnn: u16) -> Result<(), Chip8Error> {
+        self.i = nnn;
+        self.next(opcode)
     }
 
     // Bnnn : Jump to location nnn + V0
-    fn jp2(&mut self, opcode: u16) {
-        self.pc = (opcode & 0x0FFF) + (self.v[0] as u16);
+    // (Bxnn : Jump to location xnn + Vx, with the jump_uses_vx quirk)
+    fn jump_v0(&mut self, nnn: u16) {
+        let x = if self.quirks.jump_uses_vx {
+            (nnn >> 8) as usize
+        } else {
+            0
+        };
+
+        self.pc = nnn + (self.v[x] as u16);
     }
 
     // Cxkk : Set Vx = random byte AND kk
-    fn rnd(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-        let rand: u8 = rand::prelude::random();
+    fn random(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        kk: u8,
+    ) -> Result<(), Chip8Error> {
+        let rand = self.rng.next_u8();
 
         self.v[x] = rand & kk;
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // Dxyn : Display n-byte sprite starting at memory location I
     // at (Vx, Vy), set VF = collision.
-    fn drw(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-        let n = (opcode & 0x000F) as usize;
+    // (Dxy0 : Display 16x16 sprite, two bytes per row)
+    // With several planes selected, the sprite for each plane follows the
+    // sprite for the previous plane in memory
+    fn draw(
+        &mut self,
+        opcode: u16,
+        x: usize,
+        y: usize,
+        n: u8,
+    ) -> Result<(), Chip8Error> {
+        let n = n as usize;
+        let (rows, cols) = if n == 0 { (16, 16) } else { (n, 8) };
+        let w = self.display_width();
+        let h = self.display_height();
+        let len = rows * cols / 8;
+        let planes = self.planes.count_ones() as usize;
+
+        self.check_memory(opcode, self.i as usize, len * planes)?;
+
+        // The starting coordinates always wrap
+        let x0 = (self.v[x] as usize) % w;
+        let y0 = (self.v[y] as usize) % h;
 
         self.v[0xF] = 0;
-        for dy in 0..n {
-            let sprite = self.memory[(self.i as usize) + dy];
-            for dx in 0..8 {
-                if sprite & (0x80 >> dx) != 0 {
-                    // Modulo display width and height to wrap parts of
-                    // sprite that fall outside the display coordinates
-                    let xpos = ((self.v[x] as usize) + dx) % DISPLAY_W;
-                    let ypos = ((self.v[y] as usize) + dy) % DISPLAY_H;
-
-                    let displayed = self.display[ypos * DISPLAY_W + xpos];
-                    if displayed {
-                        self.v[0xF] = 1;
+        let mut addr = self.i as usize;
+        for plane in 0..PLANE_COUNT {
+            let bit = 1 << plane;
+            if self.planes & bit == 0 {
+                continue;
+            }
+            for dy in 0..rows {
+                if self.quirks.clip_sprites && y0 + dy >= h {
+                    break;
+                }
+                let sprite = if cols == 16 {
+                    let row = addr + dy * 2;
+                    (self.read(row, AccessKind::Read) as u16) << 8
+                        | (self.read(row + 1, AccessKind::Read) as u16)
+                } else {
+                    (self.read(addr + dy, AccessKind::Read) as u16) << 8
+                };
+                for dx in 0..cols {
+                    if self.quirks.clip_sprites && x0 + dx >= w {
+                        break;
+                    }
+                    if sprite & (0x8000 >> dx) != 0 {
+                        // Modulo display width and height to wrap parts of
+                        // sprite that fall outside the display coordinates
+                        let xpos = (x0 + dx) % w;
+                        let ypos = (y0 + dy) % h;
+
+                        let displayed = self.display[ypos * w + xpos];
+                        if displayed & bit != 0 {
+                            self.v[0xF] = 1;
+                        }
+                        self.display[ypos * w + xpos] = displayed ^ bit;
                     }
-                    self.display[ypos * DISPLAY_W + xpos] = !displayed;
                 }
             }
+            addr += len;
+        }
+        if self.quirks.display_wait {
+            self.vblank_wait = true;
         }
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // Ex9E : Skip next instruction if key with the value of Vx is pressed
-    fn skp(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn skip_key(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
         let val = self.v[x] as usize;
 
-        self.pc += if let Some(true) = self.keyboard.get(val) {
-            4
-        } else {
-            2
-        };
+        self.skip_if(opcode, self.keyboard.get(val) == Some(&true))
     }
 
     // ExA1 : Skip next instruction if key with the value of Vx is not pressed
-    fn sknp(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn skip_not_key(
+        &mut self,
+        opcode: u16,
+        x: usize,
+    ) -> Result<(), Chip8Error> {
         let val = self.v[x] as usize;
 
-        self.pc += if let Some(false) = self.keyboard.get(val) {
-            4
-        } else {
-            2
-        };
+        self.skip_if(opcode, self.keyboard.get(val) == Some(&false))
     }
 
-    // Fx07 : Set Vx = delay timer value
-    fn ld04(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    // F000 nnnn : Set I = nnnn
+    fn load_i_long(&mut self) -> Result<(), Chip8Error> {
+        let opcode = 0xF000;
+        let addr = (self.pc as usize) + 2;
 
-        self.v[x] = self.delay_timer;
-        self.pc += 2;
+        self.check_memory(opcode, addr, 2)?;
+
+        let hi = self.read(addr, AccessKind::Fetch) as u16;
+        let lo = self.read(addr + 1, AccessKind::Fetch) as u16;
+        self.move_pc(opcode, (self.pc as usize) + 4)?;
+        self.i = hi << 8 | lo;
+        Ok(())
     }
 
-    // Fx0A : Wait for a key press, store the value of the key in Vx
-    fn ld05(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    // Fn01 : Select drawing planes n
+    fn plane(&mut self, opcode: u16, n: u8) -> Result<(), Chip8Error> {
+        self.planes = n & ((1 << PLANE_COUNT) - 1);
+        self.next(opcode)
+    }
 
-        let mut key_pressed = false;
-        for (i, &key) in self.keyboard.iter().enumerate() {
-            if key {
-                self.v[x] = i as u8;
-                key_pressed = true;
-            }
-        }
+    // F002 : Load 16-byte audio pattern starting at memory location I
+    fn audio(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        let i = self.i as usize;
 
-        // Skip cycle. All execution stops until a key is pressed
-        if !key_pressed {
-            return;
-        } else {
-            self.pc += 2;
+        self.check_memory(opcode, i, 16)?;
+
+        self.pattern = (i..(i + 16))
+            .map(|addr| self.read(addr, AccessKind::Read))
+            .collect();
+        self.next(opcode)
+    }
+
+    // Fx07 : Set Vx = delay timer value
+    fn get_delay(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.v[x] = self.delay_timer;
+        self.next(opcode)
+    }
+
+    // Fx0A : Wait for a key press and release, store the value of the key
+    // in Vx
+    fn wait_key(
+        &mut self,
+        opcode: u16,
+        x: usize,
+    ) -> Result<StepOutcome, Chip8Error> {
+        // Skip cycle. All execution stops until the key is released
+        match self.key_wait {
+            // Only a key that went down since the last frame counts, so one
+            // still held from before doesn't end the wait straight away
+            KeyWait::Idle | KeyWait::Press => {
+                self.key_wait = match (0..16).find(|&k| self.key_pressed(k)) {
+                    Some(key) => KeyWait::Release(key),
+                    None => KeyWait::Press,
+                };
+                Ok(StepOutcome::WaitingForKey)
+            }
+            KeyWait::Release(key) if self.keyboard[key as usize] => {
+                Ok(StepOutcome::WaitingForKey)
+            }
+            KeyWait::Release(key) => {
+                self.v[x] = key;
+                self.key_wait = KeyWait::Idle;
+                self.next(opcode)?;
+                Ok(StepOutcome::Executed)
+            }
         }
     }
 
     // Fx15 : Set delay timer = Vx
-    fn ld06(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-
+    fn set_delay(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
         self.delay_timer = self.v[x];
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // Fx18 : Set sound timer = Vx
-    fn ld07(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-
+    fn set_sound(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
         self.sound_timer = self.v[x];
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // Fx1E : Set I = I + Vx
-    fn add3(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-
-        self.i += self.v[x] as u16;
-        self.pc += 2;
+    fn add_i(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.i = self.i.wrapping_add(self.v[x] as u16);
+        self.next(opcode)
     }
 
     // Fx29 : Set I = location of sprite for digit Vx
-    fn ld08(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-
+    fn font(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
         self.i = (self.v[x] as u16) * 0x5; // Sprites are 5 bytes long
-        self.pc += 2;
+        self.next(opcode)
+    }
+
+    // Fx30 : Set I = location of 8x10 sprite for digit Vx
+    fn big_font(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        let digit = (self.v[x] & 0x0F) as usize;
+
+        self.i = (BIG_HEX_SPRITES_ADDR + digit * 10) as u16;
+        self.next(opcode)
     }
 
     // Fx33 : Store BCD representation of Vx in memory locations
     // I, I+1, and I+2
-    fn ld09(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn bcd(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
         let i = self.i as usize;
         let val = self.v[x];
 
-        self.memory[i] = val / 100;
-        self.memory[i + 1] = (val / 10) % 10;
-        self.memory[i + 2] = val % 10;
-        self.pc += 2;
+        self.check_memory(opcode, i, 3)?;
+
+        self.write(i, val / 100);
+        self.write(i + 1, (val / 10) % 10);
+        self.write(i + 2, val % 10);
+        self.next(opcode)
     }
 
-    // Fx55 : Store registers V0 through Vx in memory starting at location I
-    fn ld10(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    // Fx3A : Set audio pattern pitch = Vx
+    fn pitch(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.pitch = self.v[x];
+        self.next(opcode)
+    }
+
+    // Fx55 : Store registers V0 through Vx in memory starting at location I
+    fn store(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.check_memory(opcode, self.i as usize, x + 1)?;
 
         for i in 0..=x {
             let index = (self.i as usize) + i;
-            self.memory[index] = self.v[i];
+            self.write(index, self.v[i]);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add((x as u16) + 1);
         }
-        self.pc += 2;
+        self.next(opcode)
     }
 
     // Fx65 : Read registers V0 through Vx from memory starting at location I
-    fn ld11(&mut self, opcode: u16) {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
+    fn load(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.check_memory(opcode, self.i as usize, x + 1)?;
 
         for i in 0..=x {
             let index = (self.i as usize) + i;
-            self.v[i] = self.memory[index];
+            self.v[i] = self.read(index, AccessKind::Read);
         }
-        self.pc += 2;
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add((x as u16) + 1);
+        }
+        self.next(opcode)
+    }
+
+    // Fx75 : Store registers V0 through Vx in RPL user flags
+    fn store_flags(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.rpl[..=x].copy_from_slice(&self.v[..=x]);
+        self.next(opcode)
+    }
+
+    // Fx85 : Read registers V0 through Vx from RPL user flags
+    fn load_flags(&mut self, opcode: u16, x: usize) -> Result<(), Chip8Error> {
+        self.v[..=x].copy_from_slice(&self.rpl[..=x]);
+        self.next(opcode)
     }
 }
 
@@ -506,7 +1466,8 @@ mod test {
 
         assert_eq!(c.memory.len(), 4096);
         assert_eq!(c.memory[..80], HEX_SPRITES[..]);
-        assert!(c.memory[80..].iter().all(|&x| x == 0));
+        assert_eq!(c.memory[80..240], BIG_HEX_SPRITES[..]);
+        assert!(c.memory[240..].iter().all(|&x| x == 0));
 
         assert_eq!(c.v.len(), 16);
         assert!(c.v.iter().all(|&x| x == 0));
@@ -522,16 +1483,179 @@ mod test {
         assert_eq!(c.display.len(), 64 * 32);
     }
 
+    #[test]
+    fn test_from_bytes() {
+        let c = Chip8::from_bytes(
+            &[0x12, 0x00],
+            Platform::XoChip,
+            Quirks::xo_chip(),
+        )
+        .unwrap();
+
+        assert_eq!(c.memory().len(), 0x10000);
+        assert_eq!(c.memory()[0x200..0x202], [0x12, 0x00]);
+        assert_eq!(c.quirks(), Quirks::xo_chip());
+        assert_eq!(c.pc(), 0x200);
+
+        let rom = vec![0; 0x1000 - 0x200 + 1];
+        assert!(Chip8::from_bytes(&rom, Platform::Chip8, Quirks::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_state_accessors() {
+        let mut c = Chip8::new(Platform::Chip8, Quirks::default());
+
+        c.set_v(0xF, 0x12);
+        c.set_i(0x345);
+        c.set_pc(0x678);
+        c.set_delay_timer(9);
+        c.set_sound_timer(10);
+        c.memory_mut()[0x300] = 0xAB;
+        c.set_key(0xC);
+
+        assert_eq!(c.v()[0xF], 0x12);
+        assert_eq!(c.i(), 0x345);
+        assert_eq!(c.pc(), 0x678);
+        assert_eq!(c.delay_timer(), 9);
+        assert_eq!(c.sound_timer(), 10);
+        assert_eq!(c.memory()[0x300], 0xAB);
+        assert!(c.is_key_down(0xC));
+        assert_eq!(c.keys().iter().filter(|&&k| k).count(), 1);
+
+        c.set_stack(&[0x202, 0x404]);
+        assert_eq!(c.stack(), [0x202, 0x404]);
+        assert_eq!(c.sp, 2);
+
+        // Stacked addresses are those of the 2nnn calls, so 00EE returns
+        // to the instruction after the top one
+        c.set_stack(&[0x202]);
+        c.execute_opcode_internal(0x00EE).unwrap();
+        assert_eq!(c.pc(), 0x204);
+        assert!(c.stack().is_empty());
+    }
+
+    #[test]
+    fn test_keypad() {
+        let mut c = Chip8::new(Platform::Chip8, Quirks::default());
+
+        // Several keys can be held at once
+        c.set_keypad(0x8012);
+        assert_eq!(c.keypad(), 0x8012);
+        assert!(c.is_key_down(0x1) && c.is_key_down(0x4) && c.is_key_down(0xF));
+        assert!(c.key_pressed(0x1) && c.key_pressed(0xF));
+        assert!(!c.key_released(0x1));
+
+        // Edges are relative to the keypad at the last vertical blank
+        c.vblank();
+        assert!(!c.key_pressed(0x1));
+        c.set_keypad(0x8002);
+        assert!(c.key_released(0x4));
+        assert!(!c.key_released(0x1) && !c.key_pressed(0x1));
+        assert!(c.is_key_down(0x1));
+
+        c.release_key(0xF);
+        c.set_key(0x0);
+        assert_eq!(c.keypad(), 0x0003);
+        assert!(c.key_pressed(0x0) && c.key_released(0xF));
+
+        let restored = Chip8::from_state(&c.save_state()).unwrap();
+        assert!(restored.key_pressed(0x0) && restored.key_released(0x4));
+    }
+
+    #[test]
+    fn test_memory_accesses() {
+        // Fx33 at 0x200, Dxy1 at 0x202
+        let rom = [0xF0, 0x33, 0xD0, 0x01];
+        let mut c = Chip8::from_bytes(&rom, Platform::Chip8, Quirks::default())
+            .unwrap();
+        c.set_i(0x300);
+        c.set_v(0, 123);
+
+        c.execute_opcode().unwrap();
+        assert!(c.memory_accesses().is_empty());
+
+        c.set_watch_memory(true);
+        c.set_pc(0x200);
+        c.execute_opcode().unwrap();
+        let access = |addr, kind, value| MemoryAccess {
+            pc: 0x200,
+            addr,
+            kind,
+            value,
+        };
+        assert_eq!(
+            c.memory_accesses(),
+            [
+                access(0x200, AccessKind::Fetch, 0xF0),
+                access(0x201, AccessKind::Fetch, 0x33),
+                access(0x300, AccessKind::Write, 1),
+                access(0x301, AccessKind::Write, 2),
+                access(0x302, AccessKind::Write, 3),
+            ]
+        );
+
+        c.execute_opcode().unwrap();
+        let last = c.memory_accesses()[2];
+        assert_eq!(c.memory_accesses().len(), 3);
+        assert_eq!(
+            (last.pc, last.addr, last.kind),
+            (0x202, 0x300, AccessKind::Read)
+        );
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut c = Chip8::new(Platform::XoChip, Quirks::xo_chip());
+
+        c.memory[0x200..0x206]
+            .copy_from_slice(&[0xC0, 0xFF, 0x00, 0xFF, 0x22, 0x00]);
+        c.set_v(3, 0x33);
+        c.set_i(0x1234);
+        c.set_delay_timer(5);
+        c.set_key(0xA);
+        c.execute_opcode().unwrap();
+        c.execute_opcode().unwrap();
+        c.execute_opcode().unwrap();
+
+        let state = c.save_state();
+        let mut restored = Chip8::from_state(&state).unwrap();
+
+        assert_eq!(restored.memory, c.memory);
+        assert_eq!(restored.v, c.v);
+        assert_eq!(restored.i, 0x1234);
+        assert_eq!(restored.delay_timer, 5);
+        assert_eq!(restored.pc, 0x200);
+        assert_eq!(restored.stack(), [0x204]);
+        assert!(restored.is_key_down(0xA));
+        assert!(restored.hires);
+        assert_eq!(restored.display, c.display);
+        assert_eq!(restored.quirks, Quirks::xo_chip());
+        assert_eq!(restored.save_state(), state);
+
+        // Both copies draw the same random numbers from here on
+        c.execute_opcode().unwrap();
+        restored.execute_opcode().unwrap();
+        assert_eq!(restored.v[0], c.v[0]);
+
+        assert!(Chip8::from_state(&state[..state.len() - 1]).is_err());
+
+        let mut bad_sp = state.clone();
+        let sp_offset = 6 + 4 + 0x10000 + 16 + 2 + 1 + 1 + 2;
+        bad_sp[sp_offset] = 17;
+        assert!(Chip8::from_state(&bad_sp).is_err());
+    }
+
     #[test]
     fn test_cls() {
         let mut c = Chip8::init();
 
-        c.display[0] = true;
-        c.display[31 * DISPLAY_W + 63] = true;
+        c.display[0] = 1;
+        c.display[31 * DISPLAY_W + 63] = 1;
 
-        c.execute_opcode_internal(0x00E0);
+        c.execute_opcode_internal(0x00E0).unwrap();
 
-        assert!(c.display.iter().all(|&x| x == false));
+        assert!(c.display.iter().all(|&x| x == 0));
     }
 
     #[test]
@@ -541,17 +1665,17 @@ mod test {
         c.sp = 5;
         c.stack[(c.sp - 1) as usize] = 0xEEE;
 
-        c.execute_opcode_internal(0x00EE);
+        c.execute_opcode_internal(0x00EE).unwrap();
 
         assert_eq!(c.sp, 4);
         assert_eq!(c.pc, 0xEEE + 2);
     }
 
     #[test]
-    fn test_jp1() {
+    fn test_jump() {
         let mut c = Chip8::init();
 
-        c.execute_opcode_internal(0x1ABC);
+        c.execute_opcode_internal(0x1ABC).unwrap();
 
         assert_eq!(c.pc, 0xABC);
     }
@@ -560,7 +1684,7 @@ mod test {
     fn test_call() {
         let mut c = Chip8::init();
 
-        c.execute_opcode_internal(0x2ABC);
+        c.execute_opcode_internal(0x2ABC).unwrap();
 
         assert_eq!(c.sp, 1);
         assert_eq!(c.stack[0], 0x200);
@@ -568,590 +1692,1415 @@ mod test {
     }
 
     #[test]
-    fn test_se1_skip() {
+    fn test_skip_eq_imm_skip() {
         let mut c = Chip8::init();
 
         c.v[0xA] = 0xBC;
 
-        c.execute_opcode_internal(0x3ABC);
+        c.execute_opcode_internal(0x3ABC).unwrap();
 
         assert_eq!(c.pc, 0x200 + 4);
     }
 
     #[test]
-    fn test_se1_noskip() {
+    fn test_skip_eq_imm_noskip() {
         let mut c = Chip8::init();
 
         c.v[0xA] = 0xBD;
 
-        c.execute_opcode_internal(0x3ABC);
+        c.execute_opcode_internal(0x3ABC).unwrap();
 
         assert_eq!(c.pc, 0x200 + 2);
     }
 
     #[test]
-    fn test_sne1_skip() {
+    fn test_skip_ne_imm_skip() {
         let mut c = Chip8::init();
 
         c.v[0xA] = 0xBD;
 
-        c.execute_opcode_internal(0x4ABC);
+        c.execute_opcode_internal(0x4ABC).unwrap();
 
         assert_eq!(c.pc, 0x200 + 4);
     }
 
     #[test]
-    fn test_sne1_noskip() {
+    fn test_skip_ne_imm_noskip() {
         let mut c = Chip8::init();
 
         c.v[0xA] = 0xBC;
 
-        c.execute_opcode_internal(0x4ABC);
+        c.execute_opcode_internal(0x4ABC).unwrap();
 
         assert_eq!(c.pc, 0x200 + 2);
     }
 
     #[test]
-    fn test_se2_skip() {
+    fn test_skip_eq_reg_skip() {
         let mut c = Chip8::init();
 
         c.v[0xA] = 0xCD;
         c.v[0xB] = 0xCD;
 
-        c.execute_opcode_internal(0x5AB0);
+        c.execute_opcode_internal(0x5AB0).unwrap();
 
         assert_eq!(c.pc, 0x200 + 4);
     }
 
     #[test]
-    fn test_se2_noskip() {
+    fn test_skip_eq_reg_noskip() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xCE;
+
+        c.execute_opcode_internal(0x5AB0).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 2);
+    }
+
+    #[test]
+    fn test_load_imm() {
+        let mut c = Chip8::init();
+
+        c.execute_opcode_internal(0x6ABC).unwrap();
+
+        assert_eq!(c.v[0xA], 0xBC);
+    }
+
+    #[test]
+    fn test_add_imm() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x10;
+
+        c.execute_opcode_internal(0x7ABC).unwrap();
+
+        assert_eq!(c.v[0xA], 0x10 + 0xBC);
+    }
+
+    #[test]
+    fn test_load_reg() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xEF;
+
+        c.execute_opcode_internal(0x8AB0).unwrap();
+
+        assert_eq!(c.v[0xA], 0xEF);
+    }
+
+    #[test]
+    fn test_or() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xC0;
+        c.v[0xB] = 0x0D;
+
+        c.execute_opcode_internal(0x8AB1).unwrap();
+
+        assert_eq!(c.v[0xA], 0xCD);
+    }
+
+    #[test]
+    fn test_and() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xCE;
+
+        c.execute_opcode_internal(0x8AB2).unwrap();
+
+        assert_eq!(c.v[0xA], 0xCC);
+    }
+
+    #[test]
+    fn test_xor() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xCE;
+
+        c.execute_opcode_internal(0x8AB3).unwrap();
+
+        assert_eq!(c.v[0xA], 0x03);
+    }
+
+    #[test]
+    fn test_add_reg_nocarry() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x11;
+        c.v[0xB] = 0x12;
+
+        c.execute_opcode_internal(0x8AB4).unwrap();
+
+        assert_eq!(c.v[0xA], 0x23);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_add_reg_carry() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xFF;
+        c.v[0xB] = 0xFF;
+
+        c.execute_opcode_internal(0x8AB4).unwrap();
+
+        assert_eq!(c.v[0xA], 0xFE);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_sub_noborrow() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xFF;
+        c.v[0xB] = 0xFE;
+
+        c.execute_opcode_internal(0x8AB5).unwrap();
+
+        assert_eq!(c.v[0xA], 0x01);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_sub_borrow() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x11;
+        c.v[0xB] = 0x12;
+
+        c.execute_opcode_internal(0x8AB5).unwrap();
+
+        assert_eq!(c.v[0xA], 0xFF);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_sub_equal_and_vf() {
+        let mut c = Chip8::init();
+
+        // Equal values don't borrow
+        c.v[0xA] = 0x12;
+        c.v[0xB] = 0x12;
+        c.execute_opcode_internal(0x8AB5).unwrap();
+        assert_eq!(c.v[0xA], 0x00);
+        assert_eq!(c.v[0xF], 1);
+
+        // The flag replaces the result when VF is the destination
+        c.v[0xF] = 0x05;
+        c.v[0x0] = 0x03;
+        c.execute_opcode_internal(0x8F05).unwrap();
+        assert_eq!(c.v[0xF], 1);
+        c.execute_opcode_internal(0x8F07).unwrap();
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_sub_n_equal() {
+        let mut c = Chip8::init();
+
+        c.v[0x1] = 0x34;
+        c.v[0x2] = 0x34;
+        c.execute_opcode_internal(0x8127).unwrap();
+        assert_eq!(c.v[0x1], 0x00);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_vf_destination() {
+        let mut c = Chip8::init();
+
+        // 8xy4, 8xy6 and 8xyE with x = F keep the flag, not the result
+        c.v[0xF] = 0x10;
+        c.v[0x1] = 0x20;
+        c.execute_opcode_internal(0x8F14).unwrap();
+        assert_eq!(c.v[0xF], 0);
+
+        c.v[0xF] = 0x02;
+        c.execute_opcode_internal(0x8F06).unwrap();
+        assert_eq!(c.v[0xF], 0);
+
+        c.v[0xF] = 0x81;
+        c.execute_opcode_internal(0x8F0E).unwrap();
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_shift_right_nolsb() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x22;
+
+        c.execute_opcode_internal(0x8AB6).unwrap();
+
+        assert_eq!(c.v[0xA], 0x11);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_shift_right_lsb() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x21;
+
+        c.execute_opcode_internal(0x8AB6).unwrap();
+
+        assert_eq!(c.v[0xA], 0x10);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_sub_n_borrow() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xFF;
+        c.v[0xB] = 0xFE;
+
+        c.execute_opcode_internal(0x8AB7).unwrap();
+
+        assert_eq!(c.v[0xA], 0xFF);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_sub_n_noborrow() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x11;
+        c.v[0xB] = 0x12;
+
+        c.execute_opcode_internal(0x8AB7).unwrap();
+
+        assert_eq!(c.v[0xA], 0x01);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_shift_left_nomsb() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x22;
+
+        c.execute_opcode_internal(0x8ABE).unwrap();
+
+        assert_eq!(c.v[0xA], 0x44);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_shift_left_msb() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xC0;
+
+        c.execute_opcode_internal(0x8ABE).unwrap();
+
+        assert_eq!(c.v[0xA], 0x80);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_skip_ne_reg_noskip() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xCD;
+
+        c.execute_opcode_internal(0x9AB0).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 2);
+    }
+
+    #[test]
+    fn test_skip_ne_reg_skip() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.v[0xB] = 0xCE;
+
+        c.execute_opcode_internal(0x9AB0).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 4);
+    }
+
+    #[test]
+    fn test_load_i() {
+        let mut c = Chip8::init();
+
+        c.execute_opcode_internal(0xA123).unwrap();
+
+        assert_eq!(c.i, 0x123);
+    }
+
+    #[test]
+    fn test_jump_v0() {
+        let mut c = Chip8::init();
+
+        c.v[0] = 0x55;
+
+        c.execute_opcode_internal(0xB123).unwrap();
+
+        assert_eq!(c.pc, 0x178);
+    }
+
+    #[test]
+    fn test_add_i() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x10;
+        c.i = 0xAB0C;
+
+        c.execute_opcode_internal(0xFA1E).unwrap();
+
+        assert_eq!(c.i, 0xAB1C);
+    }
+
+    #[test]
+    fn test_draw_nowrap() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.memory[0x500] = 0b00011000;
+        c.memory[0x501] = 0b00100100;
+        c.memory[0x502] = 0b01000010;
+        c.memory[0x503] = 0b10000001;
+        c.v[0xA] = 0x05;
+        c.v[0xB] = 0x0A;
+
+        c.execute_opcode_internal(0xDAB4).unwrap();
+
+        let i = 0x0A * DISPLAY_W;
+        assert!(c.display[..i].iter().all(|&x| x == 0));
+
+        let i = (0x0A + 4) * DISPLAY_W;
+        assert!(c.display[i..].iter().all(|&x| x == 0));
+
+        let i = 0x0A * DISPLAY_W + 0x05;
+        assert_eq!(&c.display[i..(i + 8)], &[0, 0, 0, 1, 1, 0, 0, 0]);
+
+        let i = 0x0B * DISPLAY_W + 0x05;
+        assert_eq!(&c.display[i..(i + 8)], &[0, 0, 1, 0, 0, 1, 0, 0]);
+
+        let i = 0x0C * DISPLAY_W + 0x05;
+        assert_eq!(&c.display[i..(i + 8)], &[0, 1, 0, 0, 0, 0, 1, 0]);
+
+        let i = 0x0D * DISPLAY_W + 0x05;
+        assert_eq!(&c.display[i..(i + 8)], &[1, 0, 0, 0, 0, 0, 0, 1]);
+
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_draw_wrapx() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.memory[0x500] = 0b10101011;
+        c.v[0xA] = 60;
+        c.v[0xB] = 0;
+
+        c.execute_opcode_internal(0xDAB1).unwrap();
+
+        assert_eq!(&c.display[60..DISPLAY_W], &[1, 0, 1, 0]);
+        assert_eq!(&c.display[..4], &[1, 0, 1, 1]);
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_draw_wrapy() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.memory[0x500] = 0b10000000;
+        c.memory[0x501] = 0b01000000;
+        c.memory[0x502] = 0b00100000;
+        c.memory[0x503] = 0b00010000;
+        c.v[0xA] = 0;
+        c.v[0xB] = 30;
+
+        c.execute_opcode_internal(0xDAB4).unwrap();
+
+        let i = 30 * DISPLAY_W;
+        assert_eq!(&c.display[i..(i + 4)], &[1, 0, 0, 0]);
+
+        let i = 31 * DISPLAY_W;
+        assert_eq!(&c.display[i..(i + 4)], &[0, 1, 0, 0]);
+
+        let i = 0 * DISPLAY_W;
+        assert_eq!(&c.display[i..(i + 4)], &[0, 0, 1, 0]);
+
+        let i = 1 * DISPLAY_W;
+        assert_eq!(&c.display[i..(i + 4)], &[0, 0, 0, 1]);
+
+        assert_eq!(c.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_draw_collision() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.memory[0x500] = 0b11000000;
+        c.v[0xA] = 0;
+        c.v[0xB] = 0;
+        c.display[0] = 1;
+
+        c.execute_opcode_internal(0xDAB1).unwrap();
+
+        assert_eq!(c.display[0], 0);
+        assert_eq!(c.display[1], 1);
+        assert_eq!(c.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_skip_key_press() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x05;
+        c.keyboard[0x05] = true;
+
+        c.execute_opcode_internal(0xEA9E).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 4);
+    }
+
+    #[test]
+    fn test_skip_key_nopress() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x05;
+        c.keyboard[0x05] = false;
+
+        c.execute_opcode_internal(0xEA9E).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 2);
+    }
+
+    #[test]
+    fn test_skip_not_key_press() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x05;
+        c.keyboard[0x05] = true;
+
+        c.execute_opcode_internal(0xEAA1).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 2);
+    }
+
+    #[test]
+    fn test_skip_not_key_nopress() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x05;
+        c.keyboard[0x05] = false;
+
+        c.execute_opcode_internal(0xEAA1).unwrap();
+
+        assert_eq!(c.pc, 0x200 + 4);
+    }
+
+    #[test]
+    fn test_get_delay() {
+        let mut c = Chip8::init();
+
+        c.delay_timer = 0xAB;
+        c.execute_opcode_internal(0xFA07).unwrap();
+
+        assert_eq!(c.v[0xA], 0xAB);
+    }
+
+    #[test]
+    fn test_wait_key_press() {
+        let mut c = Chip8::init();
+
+        c.keyboard[0x05] = true;
+
+        assert_eq!(
+            c.execute_opcode_internal(0xFA0A),
+            Ok(StepOutcome::WaitingForKey)
+        );
+        assert!(c.waiting_for_key());
+        assert_eq!(c.pc, 0x200);
+
+        // Holding the key keeps waiting, releasing it completes the wait
+        c.vblank();
+        c.execute_opcode_internal(0xFA0A).unwrap();
+        assert_eq!(c.pc, 0x200);
+        c.vblank();
+        c.keyboard[0x05] = false;
+        c.keyboard[0x03] = true;
+        assert_eq!(
+            c.execute_opcode_internal(0xFA0A),
+            Ok(StepOutcome::Executed)
+        );
+
+        assert_eq!(c.v[0xA], 0x05);
+        assert_eq!(c.pc, 0x200 + 2);
+        assert!(!c.waiting_for_key());
+    }
+
+    #[test]
+    fn test_wait_key_held() {
+        let mut c = Chip8::init();
+
+        // A key held since before the last frame is not a new press
+        c.keyboard[0x05] = true;
+        c.vblank();
+        c.execute_opcode_internal(0xFA0A).unwrap();
+        c.keyboard[0x05] = false;
+        c.vblank();
+        c.execute_opcode_internal(0xFA0A).unwrap();
+        assert_eq!(c.pc, 0x200);
+
+        c.keyboard[0x05] = true;
+        c.execute_opcode_internal(0xFA0A).unwrap();
+        c.keyboard[0x05] = false;
+        c.execute_opcode_internal(0xFA0A).unwrap();
+        assert_eq!(c.v[0xA], 0x05);
+        assert_eq!(c.pc, 0x200 + 2);
+    }
+
+    #[test]
+    fn test_wait_key_timers() {
+        // Fx0A at 0x200
+        let rom = [0xF0, 0x0A];
+
+        let mut quirks = Quirks::default();
+        let mut c = Chip8::from_bytes(&rom, Platform::Chip8, quirks).unwrap();
+        c.set_delay_timer(10);
+        c.run_frame(10).unwrap();
+        assert_eq!(c.delay_timer(), 9);
+
+        quirks.key_wait_stops_timers = true;
+        let mut c = Chip8::from_bytes(&rom, Platform::Chip8, quirks).unwrap();
+        c.set_delay_timer(10);
+        c.set_sound_timer(10);
+        assert_eq!(c.run_frame(10), Ok(StepOutcome::WaitingForKey));
+        assert_eq!((c.delay_timer(), c.sound_timer()), (10, 10));
+
+        let restored = Chip8::from_state(&c.save_state()).unwrap();
+        assert!(restored.waiting_for_key());
+    }
+
+    #[test]
+    fn test_wait_key_nopress() {
+        let mut c = Chip8::init();
+
+        c.execute_opcode_internal(0xFA0A).unwrap();
+
+        assert_eq!(c.v[0xA], 0x0);
+        assert_eq!(c.pc, 0x200);
+    }
+
+    #[test]
+    fn test_set_delay() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.execute_opcode_internal(0xFA15).unwrap();
+
+        assert_eq!(c.delay_timer, 0xCD);
+    }
+
+    #[test]
+    fn test_set_sound() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0xCD;
+        c.execute_opcode_internal(0xFA18).unwrap();
+
+        assert_eq!(c.sound_timer, 0xCD);
+    }
+
+    #[test]
+    fn test_tick_timers() {
+        let mut c = Chip8::init();
+
+        c.delay_timer = 2;
+        c.sound_timer = 1;
+
+        c.execute_opcode_internal(0x6A01).unwrap();
+
+        assert_eq!(c.delay_timer, 2);
+        assert_eq!(c.sound_timer, 1);
+
+        c.tick_timers();
+        c.tick_timers();
+
+        assert_eq!(c.delay_timer, 0);
+        assert_eq!(c.sound_timer, 0);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut c = Chip8::init();
+
+        // 7A01 (add VA, 1) repeated, with the delay timer running
+        for addr in (0x200..0x220).step_by(2) {
+            c.memory[addr] = 0x7A;
+            c.memory[addr + 1] = 0x01;
+        }
+        c.delay_timer = 5;
+
+        assert_eq!(c.run_frame(10), Ok(StepOutcome::Executed));
+
+        assert_eq!(c.v[0xA], 10);
+        assert_eq!(c.pc, 0x200 + 20);
+        assert_eq!(c.delay_timer, 4);
+    }
+
+    #[test]
+    fn test_run_frame_display_wait() {
+        let mut c = Chip8::init();
+
+        c.quirks.display_wait = true;
+        c.memory[0x200] = 0xD0;
+        c.memory[0x201] = 0x01;
+        c.memory[0x202] = 0xD0;
+        c.memory[0x203] = 0x01;
+
+        assert_eq!(c.run_frame(10), Ok(StepOutcome::WaitingForVBlank));
+        assert_eq!(c.pc, 0x202);
+
+        assert_eq!(c.run_frame(1), Ok(StepOutcome::Executed));
+        assert_eq!(c.pc, 0x204);
+    }
+
+    #[test]
+    fn test_font() {
+        let mut c = Chip8::init();
+
+        c.v[0xA] = 0x2;
+        c.execute_opcode_internal(0xFA29).unwrap();
+
+        assert_eq!(c.i, 0xA);
+        assert_eq!(c.memory[(c.i as usize)], 0xF0);
+        assert_eq!(c.memory[(c.i as usize) + 1], 0x10);
+        assert_eq!(c.memory[(c.i as usize) + 2], 0xF0);
+        assert_eq!(c.memory[(c.i as usize) + 3], 0x80);
+        assert_eq!(c.memory[(c.i as usize) + 4], 0xF0);
+    }
+
+    #[test]
+    fn test_bcd() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.v[0xA] = 234;
+        c.execute_opcode_internal(0xFA33).unwrap();
+
+        assert_eq!(c.memory[0x500], 0x2);
+        assert_eq!(c.memory[0x501], 0x3);
+        assert_eq!(c.memory[0x502], 0x4);
+        assert_eq!(c.memory[0x503], 0x0);
+    }
+
+    #[test]
+    fn test_store() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.v[0x0] = 0x1;
+        c.v[0x1] = 0xA;
+        c.v[0x2] = 0xF;
+        c.execute_opcode_internal(0xF255).unwrap();
+
+        assert_eq!(c.memory[0x500], 0x1);
+        assert_eq!(c.memory[0x501], 0xA);
+        assert_eq!(c.memory[0x502], 0xF);
+        assert_eq!(c.memory[0x503], 0x0);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut c = Chip8::init();
+
+        c.i = 0x500;
+        c.memory[0x500] = 0x1;
+        c.memory[0x501] = 0xA;
+        c.memory[0x502] = 0xF;
+        c.execute_opcode_internal(0xF265).unwrap();
+
+        assert_eq!(c.v[0x0], 0x1);
+        assert_eq!(c.v[0x1], 0xA);
+        assert_eq!(c.v[0x2], 0xF);
+        assert_eq!(c.v[0x3], 0x0);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut c = Chip8::init();
+
+        assert_eq!(
+            c.execute_opcode_internal(0x8AB8),
+            Err(Chip8Error::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x8AB8
+            })
+        );
+        assert_eq!(
+            c.execute_opcode_internal(0xFAFF),
+            Err(Chip8Error::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0xFAFF
+            })
+        );
+    }
+
+    #[test]
+    fn test_ret_underflow() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xCE;
-
-        c.execute_opcode_internal(0x5AB0);
-
-        assert_eq!(c.pc, 0x200 + 2);
+        assert_eq!(
+            c.execute_opcode_internal(0x00EE),
+            Err(Chip8Error::StackUnderflow {
+                pc: 0x200,
+                opcode: 0x00EE
+            })
+        );
+        assert_eq!(c.sp, 0);
     }
 
     #[test]
-    fn test_ld01() {
+    fn test_call_overflow() {
         let mut c = Chip8::init();
 
-        c.execute_opcode_internal(0x6ABC);
+        c.sp = 16;
 
-        assert_eq!(c.v[0xA], 0xBC);
+        assert_eq!(
+            c.execute_opcode_internal(0x2ABC),
+            Err(Chip8Error::StackOverflow {
+                pc: 0x200,
+                opcode: 0x2ABC
+            })
+        );
+        assert_eq!(c.pc, 0x200);
     }
 
     #[test]
-    fn test_add1() {
+    fn test_jump_pc_out_of_range() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x10;
-
-        c.execute_opcode_internal(0x7ABC);
-
-        assert_eq!(c.v[0xA], 0x10 + 0xBC);
+        assert_eq!(
+            c.execute_opcode_internal(0x1FFF),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0x200,
+                opcode: 0x1FFF
+            })
+        );
     }
 
     #[test]
-    fn test_ld02() {
+    fn test_execute_opcode_pc_out_of_range() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xEF;
-
-        c.execute_opcode_internal(0x8AB0);
+        c.pc = 0xFFF;
 
-        assert_eq!(c.v[0xA], 0xEF);
+        assert_eq!(
+            c.execute_opcode(),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0xFFF,
+                opcode: 0
+            })
+        );
     }
 
     #[test]
-    fn test_or() {
+    fn test_add_imm_wrap() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xC0;
-        c.v[0xB] = 0x0D;
+        c.v[0xA] = 0xFF;
 
-        c.execute_opcode_internal(0x8AB1);
+        c.execute_opcode_internal(0x7A02).unwrap();
 
-        assert_eq!(c.v[0xA], 0xCD);
+        assert_eq!(c.v[0xA], 0x01);
+        assert_eq!(c.v[0xF], 0);
     }
 
     #[test]
-    fn test_and() {
+    fn test_draw_out_of_bounds() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xCE;
+        c.i = 0xFFE;
 
-        c.execute_opcode_internal(0x8AB2);
-
-        assert_eq!(c.v[0xA], 0xCC);
+        assert_eq!(
+            c.execute_opcode_internal(0xDAB4),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xDAB4,
+                addr: 0x1000
+            })
+        );
     }
 
     #[test]
-    fn test_xor() {
+    fn test_bcd_out_of_bounds() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xCE;
-
-        c.execute_opcode_internal(0x8AB3);
+        c.i = 0xFFF;
 
-        assert_eq!(c.v[0xA], 0x03);
+        assert_eq!(
+            c.execute_opcode_internal(0xFA33),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xFA33,
+                addr: 0x1000
+            })
+        );
+        assert_eq!(c.memory[0xFFF], 0);
     }
 
     #[test]
-    fn test_add2_nocarry() {
+    fn test_store_out_of_bounds() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x11;
-        c.v[0xB] = 0x12;
-
-        c.execute_opcode_internal(0x8AB4);
+        c.i = 0x1000;
 
-        assert_eq!(c.v[0xA], 0x23);
-        assert_eq!(c.v[0xF], 0);
+        assert_eq!(
+            c.execute_opcode_internal(0xF255),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xF255,
+                addr: 0x1000
+            })
+        );
     }
 
     #[test]
-    fn test_add2_carry() {
+    fn test_load_out_of_bounds() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xFF;
-        c.v[0xB] = 0xFF;
+        c.i = 0xFFE;
 
-        c.execute_opcode_internal(0x8AB4);
-
-        assert_eq!(c.v[0xA], 0xFE);
-        assert_eq!(c.v[0xF], 1);
+        assert_eq!(
+            c.execute_opcode_internal(0xF265),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xF265,
+                addr: 0x1000
+            })
+        );
+        assert_eq!(c.v[0x0], 0);
     }
 
     #[test]
-    fn test_sub_noborrow() {
+    fn test_shift_right_quirk_vy() {
         let mut c = Chip8::init();
 
+        c.quirks.shift_uses_vy = true;
         c.v[0xA] = 0xFF;
-        c.v[0xB] = 0xFE;
+        c.v[0xB] = 0x21;
 
-        c.execute_opcode_internal(0x8AB5);
+        c.execute_opcode_internal(0x8AB6).unwrap();
 
-        assert_eq!(c.v[0xA], 0x01);
+        assert_eq!(c.v[0xA], 0x10);
         assert_eq!(c.v[0xF], 1);
     }
 
     #[test]
-    fn test_sub_borrow() {
+    fn test_shift_left_quirk_vy() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x11;
-        c.v[0xB] = 0x12;
+        c.quirks.shift_uses_vy = true;
+        c.v[0xA] = 0x01;
+        c.v[0xB] = 0x41;
 
-        c.execute_opcode_internal(0x8AB5);
+        c.execute_opcode_internal(0x8ABE).unwrap();
 
-        assert_eq!(c.v[0xA], 0xFF);
+        assert_eq!(c.v[0xA], 0x82);
         assert_eq!(c.v[0xF], 0);
     }
 
     #[test]
-    fn test_shr_nolsb() {
+    fn test_or_quirk_resets_vf() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x22;
+        c.quirks.logic_resets_vf = true;
+        c.v[0xA] = 0xC0;
+        c.v[0xB] = 0x0D;
+        c.v[0xF] = 0x01;
 
-        c.execute_opcode_internal(0x8AB6);
+        c.execute_opcode_internal(0x8AB1).unwrap();
 
-        assert_eq!(c.v[0xA], 0x11);
+        assert_eq!(c.v[0xA], 0xCD);
         assert_eq!(c.v[0xF], 0);
     }
 
     #[test]
-    fn test_shr_lsb() {
+    fn test_jump_v0_quirk_vx() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x21;
+        c.quirks.jump_uses_vx = true;
+        c.v[0] = 0x55;
+        c.v[1] = 0x10;
 
-        c.execute_opcode_internal(0x8AB6);
+        c.execute_opcode_internal(0xB123).unwrap();
 
-        assert_eq!(c.v[0xA], 0x10);
-        assert_eq!(c.v[0xF], 1);
+        assert_eq!(c.pc, 0x133);
     }
 
     #[test]
-    fn test_subn_borrow() {
+    fn test_store_quirk_increments_i() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xFF;
-        c.v[0xB] = 0xFE;
+        c.quirks.load_store_increments_i = true;
+        c.i = 0x500;
 
-        c.execute_opcode_internal(0x8AB7);
+        c.execute_opcode_internal(0xF255).unwrap();
 
-        assert_eq!(c.v[0xA], 0xFF);
-        assert_eq!(c.v[0xF], 0);
+        assert_eq!(c.i, 0x503);
     }
 
     #[test]
-    fn test_subn_noborrow() {
+    fn test_load_quirk_increments_i() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x11;
-        c.v[0xB] = 0x12;
+        c.quirks.load_store_increments_i = true;
+        c.i = 0x500;
 
-        c.execute_opcode_internal(0x8AB7);
+        c.execute_opcode_internal(0xF265).unwrap();
 
-        assert_eq!(c.v[0xA], 0x01);
-        assert_eq!(c.v[0xF], 1);
+        assert_eq!(c.i, 0x503);
     }
 
     #[test]
-    fn test_shr_nomsb() {
+    fn test_draw_quirk_clip() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x22;
+        c.quirks.clip_sprites = true;
+        c.i = 0x500;
+        c.memory[0x500] = 0b10101011;
+        c.memory[0x501] = 0b11111111;
+        c.v[0xA] = 60;
+        c.v[0xB] = 31;
 
-        c.execute_opcode_internal(0x8ABE);
+        c.execute_opcode_internal(0xDAB2).unwrap();
 
-        assert_eq!(c.v[0xA], 0x44);
-        assert_eq!(c.v[0xF], 0);
+        let i = 31 * DISPLAY_W;
+        assert_eq!(&c.display[(i + 60)..(i + 64)], &[1, 0, 1, 0]);
+        assert!(c.display[..i].iter().all(|&x| x == 0));
+        assert!(c.display[i..(i + 60)].iter().all(|&x| x == 0));
     }
 
     #[test]
-    fn test_shr_msb() {
+    fn test_draw_quirk_clip_wraps_start() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xC0;
+        c.quirks.clip_sprites = true;
+        c.i = 0x500;
+        c.memory[0x500] = 0b10000000;
+        c.v[0xA] = 64 + 3;
+        c.v[0xB] = 32 + 2;
 
-        c.execute_opcode_internal(0x8ABE);
+        c.execute_opcode_internal(0xDAB1).unwrap();
 
-        assert_eq!(c.v[0xA], 0x80);
-        assert_eq!(c.v[0xF], 1);
+        assert_eq!(c.display[2 * DISPLAY_W + 3], 1);
     }
 
     #[test]
-    fn test_sne2_noskip() {
+    fn test_draw_quirk_display_wait() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xCD;
+        c.quirks.display_wait = true;
+        c.memory[0x200] = 0xDA;
+        c.memory[0x201] = 0xB1;
+        c.memory[0x202] = 0x6A;
+        c.memory[0x203] = 0x01;
 
-        c.execute_opcode_internal(0x9AB0);
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::Executed));
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::WaitingForVBlank));
+        assert_eq!(c.pc, 0x202);
 
-        assert_eq!(c.pc, 0x200 + 2);
+        c.vblank();
+
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::Executed));
+        assert_eq!(c.v[0xA], 0x01);
     }
 
     #[test]
-    fn test_sne2_skip() {
+    fn test_scroll_down() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0xCD;
-        c.v[0xB] = 0xCE;
+        c.display[0] = 1;
+        c.display[DISPLAY_W + 1] = 1;
+        c.display[31 * DISPLAY_W] = 1;
 
-        c.execute_opcode_internal(0x9AB0);
+        c.execute_opcode_internal(0x00C2).unwrap();
 
-        assert_eq!(c.pc, 0x200 + 4);
+        assert_eq!(c.display[2 * DISPLAY_W], 1);
+        assert_eq!(c.display[3 * DISPLAY_W + 1], 1);
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 2);
     }
 
     #[test]
-    fn test_ld03() {
+    fn test_scroll_right() {
         let mut c = Chip8::init();
 
-        c.execute_opcode_internal(0xA123);
+        c.display[0] = 1;
+        c.display[DISPLAY_W - 1] = 1;
 
-        assert_eq!(c.i, 0x123);
+        c.execute_opcode_internal(0x00FB).unwrap();
+
+        assert_eq!(c.display[4], 1);
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 1);
     }
 
     #[test]
-    fn test_jp2() {
+    fn test_scroll_left() {
         let mut c = Chip8::init();
 
-        c.v[0] = 0x55;
+        c.display[DISPLAY_W + 5] = 1;
+        c.display[DISPLAY_W + 2] = 1;
 
-        c.execute_opcode_internal(0xB123);
+        c.execute_opcode_internal(0x00FC).unwrap();
 
-        assert_eq!(c.pc, 0x178);
+        assert_eq!(c.display[DISPLAY_W + 1], 1);
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 1);
     }
 
     #[test]
-    fn test_add3() {
+    fn test_exit() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x10;
-        c.i = 0xAB0C;
-
-        c.execute_opcode_internal(0xFA1E);
+        c.memory[0x200] = 0x00;
+        c.memory[0x201] = 0xFD;
 
-        assert_eq!(c.i, 0xAB1C);
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::Exit));
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::Exit));
+        assert_eq!(c.pc, 0x200);
     }
 
     #[test]
-    fn test_drw_nowrap() {
+    fn test_high_low_res() {
         let mut c = Chip8::init();
 
-        c.i = 0x500;
-        c.memory[0x500] = 0b00011000;
-        c.memory[0x501] = 0b00100100;
-        c.memory[0x502] = 0b01000010;
-        c.memory[0x503] = 0b10000001;
-        c.v[0xA] = 0x05;
-        c.v[0xB] = 0x0A;
-
-        c.execute_opcode_internal(0xDAB4);
-
-        let i = 0x0A * DISPLAY_W;
-        assert!(c.display[..i].iter().all(|&x| x == false));
-
-        let i = (0x0A + 4) * DISPLAY_W;
-        assert!(c.display[i..].iter().all(|&x| x == false));
-
-        let i = 0x0A * DISPLAY_W + 0x05;
-        assert_eq!(
-            &c.display[i..(i + 8)],
-            &[false, false, false, true, true, false, false, false]
-        );
-
-        let i = 0x0B * DISPLAY_W + 0x05;
-        assert_eq!(
-            &c.display[i..(i + 8)],
-            &[false, false, true, false, false, true, false, false]
-        );
+        c.execute_opcode_internal(0x00FF).unwrap();
 
-        let i = 0x0C * DISPLAY_W + 0x05;
-        assert_eq!(
-            &c.display[i..(i + 8)],
-            &[false, true, false, false, false, false, true, false]
-        );
+        assert_eq!(c.display_width(), HIRES_DISPLAY_W);
+        assert_eq!(c.display_height(), HIRES_DISPLAY_H);
+        assert_eq!(c.display.len(), 128 * 64);
 
-        let i = 0x0D * DISPLAY_W + 0x05;
-        assert_eq!(
-            &c.display[i..(i + 8)],
-            &[true, false, false, false, false, false, false, true]
-        );
+        c.execute_opcode_internal(0x00FE).unwrap();
 
-        assert_eq!(c.v[0xF], 0);
+        assert_eq!(c.display_width(), DISPLAY_W);
+        assert_eq!(c.display_height(), DISPLAY_H);
+        assert_eq!(c.display.len(), 64 * 32);
     }
 
     #[test]
-    fn test_drw_wrapx() {
+    fn test_draw_hires_wrap() {
         let mut c = Chip8::init();
 
+        c.execute_opcode_internal(0x00FF).unwrap();
+
         c.i = 0x500;
-        c.memory[0x500] = 0b10101011;
-        c.v[0xA] = 60;
-        c.v[0xB] = 0;
+        c.memory[0x500] = 0b10000001;
+        c.v[0xA] = 124;
+        c.v[0xB] = 63;
 
-        c.execute_opcode_internal(0xDAB1);
+        c.execute_opcode_internal(0xDAB1).unwrap();
 
-        assert_eq!(&c.display[60..DISPLAY_W], &[true, false, true, false]);
-        assert_eq!(&c.display[..4], &[true, false, true, true]);
-        assert_eq!(c.v[0xF], 0);
+        let i = 63 * HIRES_DISPLAY_W;
+        assert_eq!(c.display[i + 124], 1);
+        assert_eq!(c.display[i + 3], 1);
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 2);
     }
 
     #[test]
-    fn test_drw_wrapy() {
+    fn test_draw_16x16() {
         let mut c = Chip8::init();
 
+        c.execute_opcode_internal(0x00FF).unwrap();
+
         c.i = 0x500;
         c.memory[0x500] = 0b10000000;
-        c.memory[0x501] = 0b01000000;
-        c.memory[0x502] = 0b00100000;
-        c.memory[0x503] = 0b00010000;
-        c.v[0xA] = 0;
-        c.v[0xB] = 30;
-
-        c.execute_opcode_internal(0xDAB4);
-
-        let i = 30 * DISPLAY_W;
-        assert_eq!(&c.display[i..(i + 4)], &[true, false, false, false]);
+        c.memory[0x501] = 0b00000001;
+        c.memory[0x51E] = 0b11000000;
+        c.memory[0x51F] = 0b00000011;
+        c.v[0xA] = 10;
+        c.v[0xB] = 20;
 
-        let i = 31 * DISPLAY_W;
-        assert_eq!(&c.display[i..(i + 4)], &[false, true, false, false]);
+        c.execute_opcode_internal(0xDAB0).unwrap();
 
-        let i = 0 * DISPLAY_W;
-        assert_eq!(&c.display[i..(i + 4)], &[false, false, true, false]);
+        let i = 20 * HIRES_DISPLAY_W + 10;
+        assert_eq!(c.display[i], 1);
+        assert_eq!(c.display[i + 15], 1);
 
-        let i = 1 * DISPLAY_W;
-        assert_eq!(&c.display[i..(i + 4)], &[false, false, false, true]);
+        let i = 35 * HIRES_DISPLAY_W + 10;
+        assert_eq!(&c.display[i..(i + 2)], &[1, 1]);
+        assert_eq!(&c.display[(i + 14)..(i + 16)], &[1, 1]);
 
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 6);
         assert_eq!(c.v[0xF], 0);
     }
 
     #[test]
-    fn test_drw_collision() {
+    fn test_big_font() {
         let mut c = Chip8::init();
 
-        c.i = 0x500;
-        c.memory[0x500] = 0b11000000;
-        c.v[0xA] = 0;
-        c.v[0xB] = 0;
-        c.display[0] = true;
+        c.v[0xA] = 0x3;
+        c.execute_opcode_internal(0xFA30).unwrap();
 
-        c.execute_opcode_internal(0xDAB1);
-
-        assert_eq!(c.display[0], false);
-        assert_eq!(c.display[1], true);
-        assert_eq!(c.v[0xF], 1);
+        assert_eq!(c.i, 0x50 + 30);
+        assert_eq!(
+            c.memory[(c.i as usize)..(c.i as usize + 10)],
+            BIG_HEX_SPRITES[30..40]
+        );
     }
 
     #[test]
-    fn test_skp_press() {
+    fn test_store_flags_load_flags() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x05;
-        c.keyboard[0x05] = true;
+        c.v[0x0] = 0x1;
+        c.v[0x1] = 0xA;
+        c.v[0x2] = 0xF;
+        c.execute_opcode_internal(0xF275).unwrap();
 
-        c.execute_opcode_internal(0xEA9E);
+        c.v[0x0] = 0;
+        c.v[0x1] = 0;
+        c.v[0x2] = 0;
+        c.execute_opcode_internal(0xF185).unwrap();
 
-        assert_eq!(c.pc, 0x200 + 4);
+        assert_eq!(c.v[0x0], 0x1);
+        assert_eq!(c.v[0x1], 0xA);
+        assert_eq!(c.v[0x2], 0x0);
     }
 
     #[test]
-    fn test_skp_nopress() {
+    fn test_scroll_up() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x05;
-        c.keyboard[0x05] = false;
+        c.display[3 * DISPLAY_W] = 1;
+        c.display[DISPLAY_W] = 1;
 
-        c.execute_opcode_internal(0xEA9E);
+        c.execute_opcode_internal(0x00D2).unwrap();
 
-        assert_eq!(c.pc, 0x200 + 2);
+        assert_eq!(c.display[DISPLAY_W], 1);
+        assert_eq!(c.display.iter().filter(|&&x| x != 0).count(), 1);
     }
 
     #[test]
-    fn test_sknp_press() {
+    fn test_store_range() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x05;
-        c.keyboard[0x05] = true;
+        c.i = 0x500;
+        c.v[0x2] = 0x1;
+        c.v[0x3] = 0xA;
+        c.v[0x4] = 0xF;
+        c.execute_opcode_internal(0x5242).unwrap();
 
-        c.execute_opcode_internal(0xEAA1);
+        assert_eq!(c.memory[0x500..0x504], [0x1, 0xA, 0xF, 0x0]);
+        assert_eq!(c.i, 0x500);
 
-        assert_eq!(c.pc, 0x200 + 2);
+        c.execute_opcode_internal(0x5422).unwrap();
+
+        assert_eq!(c.memory[0x500..0x504], [0xF, 0xA, 0x1, 0x0]);
     }
 
     #[test]
-    fn test_sknp_nopress() {
+    fn test_load_range() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x05;
-        c.keyboard[0x05] = false;
-
-        c.execute_opcode_internal(0xEAA1);
+        c.i = 0x500;
+        c.memory[0x500] = 0x1;
+        c.memory[0x501] = 0xA;
+        c.memory[0x502] = 0xF;
+        c.execute_opcode_internal(0x5313).unwrap();
 
-        assert_eq!(c.pc, 0x200 + 4);
+        assert_eq!(c.v[0x1], 0xF);
+        assert_eq!(c.v[0x2], 0xA);
+        assert_eq!(c.v[0x3], 0x1);
+        assert_eq!(c.i, 0x500);
     }
 
     #[test]
-    fn test_ld04() {
+    fn test_load_i_long() {
         let mut c = Chip8::init();
 
-        c.delay_timer = 0xAB;
-        c.execute_opcode_internal(0xFA07);
+        c.memory[0x202] = 0xAB;
+        c.memory[0x203] = 0xCD;
+        c.execute_opcode_internal(0xF000).unwrap();
 
-        assert_eq!(c.v[0xA], 0xAB);
+        assert_eq!(c.i, 0xABCD);
+        assert_eq!(c.pc, 0x200 + 4);
     }
 
     #[test]
-    fn test_ld05_press() {
+    fn test_skip_eq_imm_skip_load_i_long() {
         let mut c = Chip8::init();
 
-        c.keyboard[0x05] = true;
+        c.memory[0x202] = 0xF0;
+        c.memory[0x203] = 0x00;
+        c.v[0xA] = 0xBC;
 
-        c.execute_opcode_internal(0xFA0A);
+        c.execute_opcode_internal(0x3ABC).unwrap();
 
-        assert_eq!(c.v[0xA], 0x05);
-        assert_eq!(c.pc, 0x200 + 2);
+        assert_eq!(c.pc, 0x200 + 6);
     }
 
     #[test]
-    fn test_ld05_nopress() {
-        let mut c = Chip8::init();
+    fn test_load_i_long_pc_out_of_range() {
+        let mut c = Chip8::new(Platform::XoChip, Quirks::xo_chip());
 
-        c.execute_opcode_internal(0xFA0A);
+        c.pc = 0xFFFC;
+        c.memory[0xFFFC] = 0xF0;
+        c.memory[0xFFFD] = 0x00;
 
-        assert_eq!(c.v[0xA], 0x0);
-        assert_eq!(c.pc, 0x200);
+        assert_eq!(
+            c.execute_opcode(),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0xFFFC,
+                opcode: 0xF000
+            })
+        );
+        assert_eq!(c.pc, 0xFFFC);
     }
 
     #[test]
-    fn test_ld06() {
-        let mut c = Chip8::init();
+    fn test_last_instruction() {
+        let mut c = Chip8::new(Platform::XoChip, Quirks::xo_chip());
+
+        // A jump or call in the last word of RAM runs
+        c.pc = 0xFFFE;
+        c.memory[0xFFFE] = 0x22;
+        c.memory[0xFFFF] = 0x00;
+        assert_eq!(c.execute_opcode(), Ok(StepOutcome::Executed));
+        assert_eq!(c.pc, 0x200);
+        assert_eq!(c.stack(), [0xFFFE]);
 
-        c.v[0xA] = 0xCD;
-        c.execute_opcode_internal(0xFA15);
+        // Returning to it would step past the end
+        assert_eq!(
+            c.execute_opcode_internal(0x00EE),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0x200,
+                opcode: 0x00EE
+            })
+        );
 
-        assert_eq!(c.delay_timer, 0xCD - 1);
+        // Anything else has no next instruction to move on to
+        c.pc = 0xFFFE;
+        c.memory[0xFFFE] = 0x60;
+        c.memory[0xFFFF] = 0x05;
+        assert_eq!(
+            c.execute_opcode(),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0xFFFE,
+                opcode: 0x6005
+            })
+        );
     }
 
     #[test]
-    fn test_ld07() {
-        let mut c = Chip8::init();
-
-        c.v[0xA] = 0xCD;
-        c.execute_opcode_internal(0xFA18);
+    fn test_skip_pc_out_of_range() {
+        let mut c = Chip8::new(Platform::XoChip, Quirks::xo_chip());
+
+        // A skip at 0xFFFA lands on the last instruction. One at 0xFFFC
+        // has nowhere to go
+        c.pc = 0xFFFA;
+        c.execute_opcode_internal(0x3000).unwrap();
+        assert_eq!(c.pc, 0xFFFE);
+
+        c.pc = 0xFFFC;
+        c.memory[0xFFFC] = 0x30;
+        c.memory[0xFFFD] = 0x00;
+        assert_eq!(
+            c.execute_opcode(),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0xFFFC,
+                opcode: 0x3000
+            })
+        );
 
-        assert_eq!(c.sound_timer, 0xCD - 1);
+        // Skipping an F000 nnnn at 0xFFFC would leave the PC at 0x10000
+        c.pc = 0xFFFA;
+        c.memory[0xFFFA] = 0x30;
+        c.memory[0xFFFB] = 0x00;
+        c.memory[0xFFFC] = 0xF0;
+        c.memory[0xFFFD] = 0x00;
+        assert_eq!(
+            c.execute_opcode(),
+            Err(Chip8Error::PcOutOfRange {
+                pc: 0xFFFA,
+                opcode: 0x3000
+            })
+        );
     }
 
     #[test]
-    fn test_ld08() {
+    fn test_draw_planes() {
         let mut c = Chip8::init();
 
-        c.v[0xA] = 0x2;
-        c.execute_opcode_internal(0xFA29);
+        c.i = 0x500;
+        c.memory[0x500] = 0b11000000;
+        c.memory[0x501] = 0b01100000;
+        c.display[0] = 0x1;
 
-        assert_eq!(c.i, 0xA);
-        assert_eq!(c.memory[(c.i as usize)], 0xF0);
-        assert_eq!(c.memory[(c.i as usize) + 1], 0x10);
-        assert_eq!(c.memory[(c.i as usize) + 2], 0xF0);
-        assert_eq!(c.memory[(c.i as usize) + 3], 0x80);
-        assert_eq!(c.memory[(c.i as usize) + 4], 0xF0);
+        c.execute_opcode_internal(0xF301).unwrap();
+        c.execute_opcode_internal(0xD001).unwrap();
+
+        assert_eq!(&c.display[..3], &[0x0, 0x3, 0x2]);
+        assert_eq!(c.v[0xF], 1);
     }
 
     #[test]
-    fn test_ld09() {
+    fn test_cls_plane() {
         let mut c = Chip8::init();
 
-        c.i = 0x500;
-        c.v[0xA] = 234;
-        c.execute_opcode_internal(0xFA33);
+        c.display[0] = 0x3;
+        c.display[1] = 0x2;
 
-        assert_eq!(c.memory[0x500], 0x2);
-        assert_eq!(c.memory[0x501], 0x3);
-        assert_eq!(c.memory[0x502], 0x4);
-        assert_eq!(c.memory[0x503], 0x0);
+        c.execute_opcode_internal(0xF201).unwrap();
+        c.execute_opcode_internal(0x00E0).unwrap();
+
+        assert_eq!(&c.display[..2], &[0x1, 0x0]);
     }
 
     #[test]
-    fn test_ld10() {
+    fn test_audio_pitch() {
         let mut c = Chip8::init();
 
+        assert_eq!(c.audio_pattern(), None);
+
         c.i = 0x500;
-        c.v[0x0] = 0x1;
-        c.v[0x1] = 0xA;
-        c.v[0x2] = 0xF;
-        c.execute_opcode_internal(0xF255);
+        c.memory[0x500] = 0xFF;
+        c.v[0xA] = 112;
+        c.execute_opcode_internal(0xF002).unwrap();
+        c.execute_opcode_internal(0xFA3A).unwrap();
 
-        assert_eq!(c.memory[0x500], 0x1);
-        assert_eq!(c.memory[0x501], 0xA);
-        assert_eq!(c.memory[0x502], 0xF);
-        assert_eq!(c.memory[0x503], 0x0);
+        let pattern = c.audio_pattern().unwrap();
+        assert_eq!(pattern.len(), 16);
+        assert_eq!(pattern[0], 0xFF);
+        assert_eq!(c.audio_pattern_rate(), 8000.0);
     }
 
     #[test]
-    fn test_ld11() {
+    fn test_execute() {
         let mut c = Chip8::init();
 
-        c.i = 0x500;
-        c.memory[0x500] = 0x1;
-        c.memory[0x501] = 0xA;
-        c.memory[0x502] = 0xF;
-        c.execute_opcode_internal(0xF265);
+        c.execute(Instruction::AddImm { x: 0xA, kk: 5 }).unwrap();
+        assert_eq!(c.v[0xA], 5);
+        assert_eq!(c.pc, 0x202);
 
-        assert_eq!(c.v[0x0], 0x1);
-        assert_eq!(c.v[0x1], 0xA);
-        assert_eq!(c.v[0x2], 0xF);
-        assert_eq!(c.v[0x3], 0x0);
+        // Errors report the instruction's opcode
+        assert_eq!(
+            c.execute(Instruction::Ret),
+            Err(Chip8Error::StackUnderflow {
+                pc: 0x202,
+                opcode: 0x00EE
+            })
+        );
+        assert_eq!(
+            c.execute_opcode_internal(0x5AB4),
+            Err(Chip8Error::UnknownOpcode {
+                pc: 0x202,
+                opcode: 0x5AB4
+            })
+        );
     }
 }
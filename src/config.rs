@@ -0,0 +1,498 @@
+use minifb::Key;
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+// Read if it exists and no other config file is given
+pub const DEFAULT_PATH: &str = "chip8.toml";
+
+// Host keys for the CHIP-8 keypad, indexed by CHIP-8 key. The 4x4 keypad
+// is laid out over 1-4, Q-R, A-F and Z-V on a QWERTY keyboard:
+//
+//     1 2 3 C      1 2 3 4
+//     4 5 6 D  ->  Q W E R
+//     7 8 9 E      A S D F
+//     A 0 B F      Z X C V
+const DEFAULT_KEYPAD: [Key; 16] = [
+    Key::X,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Q,
+    Key::W,
+    Key::E,
+    Key::A,
+    Key::S,
+    Key::D,
+    Key::Z,
+    Key::C,
+    Key::Key4,
+    Key::R,
+    Key::F,
+    Key::V,
+];
+
+// Host keys added for the controls of a ROM database key layout. Player 2
+// gets the keys around I on the right of the keyboard
+const LAYOUT_KEYS: &[(&str, Key)] = &[
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("a", Key::Space),
+    ("b", Key::Enter),
+    ("player2Up", Key::I),
+    ("player2Down", Key::K),
+    ("player2Left", Key::J),
+    ("player2Right", Key::L),
+    ("player2A", Key::U),
+    ("player2B", Key::O),
+];
+
+// Names of the hotkeys, matching the field names
+pub const HOTKEY_NAMES: &[&str] = &[
+    "quit", "pause", "reset", "save", "load", "mute", "rewind", "palette",
+];
+
+// Host keys for the CHIP-8 keypad and the emulator's own controls
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bindings {
+    keypad: Vec<Vec<Key>>, // Host keys for each CHIP-8 key
+    pub quit: Key,         // Close the window
+    pub pause: Key,        // Pause or resume execution
+    pub reset: Key,        // Restart the ROM
+    pub save: Key,         // Save to the last save state slot used
+    pub load: Key,         // Load the last save state slot used
+    pub mute: Key,         // Mute or unmute the sound
+    pub rewind: Key,       // Step back in time while held
+    pub palette: Key,      // Switch to the next colour palette
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        Bindings {
+            keypad: DEFAULT_KEYPAD.iter().map(|&key| vec![key]).collect(),
+            quit: Key::Escape,
+            pause: Key::Pause,
+            reset: Key::F12,
+            save: Key::F10,
+            load: Key::F11,
+            mute: Key::M,
+            rewind: Key::Backspace,
+            palette: Key::Tab,
+        }
+    }
+}
+
+impl Bindings {
+    // The default bindings, plus host keys for a ROM's controls from the ROM
+    // database. `layout` pairs control names such as "up" with CHIP-8 keys
+    pub fn with_layout(layout: &[(String, u8)]) -> Bindings {
+        let mut bindings = Bindings::default();
+        for (control, chip8_key) in layout {
+            let host_key = LAYOUT_KEYS
+                .iter()
+                .find(|&&(name, _)| name == control)
+                .map(|&(_, key)| key);
+            if let Some(key) = host_key {
+                let keys = &mut bindings.keypad[*chip8_key as usize];
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        bindings
+    }
+
+    // Change these bindings with those for the ROM with SHA-1 hash
+    // `rom_sha1` in a config file. Without one, `DEFAULT_PATH` is read if
+    // it exists
+    pub fn load(
+        self,
+        fname: Option<&str>,
+        rom_sha1: &str,
+    ) -> Result<Bindings, io::Error> {
+        let fname = match fname {
+            Some(fname) => fname,
+            None if Path::new(DEFAULT_PATH).exists() => DEFAULT_PATH,
+            None => return Ok(self),
+        };
+
+        self.parse(&fs::read_to_string(fname)?, rom_sha1)
+            .map_err(|e| invalid_data(format!("{}: {}", fname, e)))
+    }
+
+    // Parse a config file. `[keypad]` and `[hotkeys]` change the defaults,
+    // and `[rom.SHA1.keypad]` and `[rom.SHA1.hotkeys]` change them again
+    // for one ROM:
+    //
+    //     [keypad]
+    //     5 = ["W", "Up"]
+    //
+    //     [hotkeys]
+    //     pause = "P"
+    //
+    //     [rom.0123456789abcdef0123456789abcdef01234567.keypad]
+    //     4 = "Left"
+    pub fn parse(
+        mut self,
+        config: &str,
+        rom_sha1: &str,
+    ) -> Result<Bindings, String> {
+        let config = config.parse::<Value>().map_err(|e| e.to_string())?;
+        self.apply(&config, "")?;
+
+        let roms = match config.get("rom") {
+            Some(roms) => table(roms, "rom")?,
+            None => return Ok(self),
+        };
+        for (sha1, rom) in roms {
+            if sha1.eq_ignore_ascii_case(rom_sha1) {
+                self.apply(rom, &format!("rom.{}.", sha1))?;
+            }
+        }
+        Ok(self)
+    }
+
+    // Keypad state for a set of held host keys. Bit n holds CHIP-8 key n
+    pub fn keypad_state(&self, held: &[Key]) -> u16 {
+        self.keypad
+            .iter()
+            .enumerate()
+            .filter(|&(_, keys)| keys.iter().any(|key| held.contains(key)))
+            .fold(0, |state, (n, _)| state | 1 << n)
+    }
+
+    // Apply the `keypad` and `hotkeys` tables of `config`. `prefix` places
+    // them in the file for error messages
+    fn apply(&mut self, config: &Value, prefix: &str) -> Result<(), String> {
+        if let Some(keypad) = config.get("keypad") {
+            let name = format!("{}keypad", prefix);
+            let mut listed = Vec::new();
+            for (chip8_key, keys) in table(keypad, &name)? {
+                let n = usize::from_str_radix(chip8_key, 16)
+                    .ok()
+                    .filter(|&n| n < 16)
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown CHIP-8 key '{}' in [{}]. Expected 0 to F",
+                            chip8_key, name
+                        )
+                    })?;
+                self.keypad[n] = host_keys(keys)?;
+                listed.push(n);
+            }
+
+            // A host key moved to another CHIP-8 key no longer presses the
+            // one it had before, unless both are listed
+            let bound: Vec<Key> = listed
+                .iter()
+                .flat_map(|&n| self.keypad[n].clone())
+                .collect();
+            for (n, keys) in self.keypad.iter_mut().enumerate() {
+                if !listed.contains(&n) {
+                    keys.retain(|key| !bound.contains(key));
+                }
+            }
+        }
+
+        if let Some(hotkeys) = config.get("hotkeys") {
+            let name = format!("{}hotkeys", prefix);
+            for (hotkey, key) in table(hotkeys, &name)? {
+                let key = match *key {
+                    Value::String(ref key) => host_key(key)?,
+                    _ => {
+                        return Err(format!(
+                            "Hotkey '{}' must be a key name",
+                            hotkey
+                        ))
+                    }
+                };
+                *self.hotkey_mut(hotkey).ok_or_else(|| {
+                    format!(
+                        "Unknown hotkey '{}' in [{}]. Expected one of: {}",
+                        hotkey,
+                        name,
+                        HOTKEY_NAMES.join(", ")
+                    )
+                })? = key;
+            }
+        }
+        Ok(())
+    }
+
+    fn hotkey_mut(&mut self, name: &str) -> Option<&mut Key> {
+        match name {
+            "quit" => Some(&mut self.quit),
+            "pause" => Some(&mut self.pause),
+            "reset" => Some(&mut self.reset),
+            "save" => Some(&mut self.save),
+            "load" => Some(&mut self.load),
+            "mute" => Some(&mut self.mute),
+            "rewind" => Some(&mut self.rewind),
+            "palette" => Some(&mut self.palette),
+            _ => None,
+        }
+    }
+}
+
+// Name of a host key, as written in config files
+pub fn key_name(key: Key) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|&&(_, k)| k == key)
+        .map_or("Unknown", |&(name, _)| name)
+}
+
+fn table<'a>(value: &'a Value, name: &str) -> Result<&'a Table, String> {
+    value
+        .as_table()
+        .ok_or_else(|| format!("'{}' must be a table", name))
+}
+
+// A key name or an array of them
+fn host_keys(value: &Value) -> Result<Vec<Key>, String> {
+    match *value {
+        Value::String(ref name) => Ok(vec![host_key(name)?]),
+        Value::Array(ref names) => names
+            .iter()
+            .map(|name| match *name {
+                Value::String(ref name) => host_key(name),
+                _ => Err(format!("Expected a key name, got {}", name)),
+            })
+            .collect(),
+        _ => Err(format!("Expected a key name or an array, got {}", value)),
+    }
+}
+
+// Key names are those of minifb's `Key`, ignoring case. The number keys
+// above the letters can also be written as plain digits
+fn host_key(name: &str) -> Result<Key, String> {
+    KEY_NAMES
+        .iter()
+        .find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+        .or_else(|| {
+            let digit = format!("Key{}", name);
+            KEY_NAMES.iter().find(|&&(n, _)| n == digit)
+        })
+        .map(|&(_, key)| key)
+        .ok_or_else(|| format!("Unknown key name '{}'", name))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("Key0", Key::Key0),
+    ("Key1", Key::Key1),
+    ("Key2", Key::Key2),
+    ("Key3", Key::Key3),
+    ("Key4", Key::Key4),
+    ("Key5", Key::Key5),
+    ("Key6", Key::Key6),
+    ("Key7", Key::Key7),
+    ("Key8", Key::Key8),
+    ("Key9", Key::Key9),
+    ("A", Key::A),
+    ("B", Key::B),
+    ("C", Key::C),
+    ("D", Key::D),
+    ("E", Key::E),
+    ("F", Key::F),
+    ("G", Key::G),
+    ("H", Key::H),
+    ("I", Key::I),
+    ("J", Key::J),
+    ("K", Key::K),
+    ("L", Key::L),
+    ("M", Key::M),
+    ("N", Key::N),
+    ("O", Key::O),
+    ("P", Key::P),
+    ("Q", Key::Q),
+    ("R", Key::R),
+    ("S", Key::S),
+    ("T", Key::T),
+    ("U", Key::U),
+    ("V", Key::V),
+    ("W", Key::W),
+    ("X", Key::X),
+    ("Y", Key::Y),
+    ("Z", Key::Z),
+    ("F1", Key::F1),
+    ("F2", Key::F2),
+    ("F3", Key::F3),
+    ("F4", Key::F4),
+    ("F5", Key::F5),
+    ("F6", Key::F6),
+    ("F7", Key::F7),
+    ("F8", Key::F8),
+    ("F9", Key::F9),
+    ("F10", Key::F10),
+    ("F11", Key::F11),
+    ("F12", Key::F12),
+    ("Down", Key::Down),
+    ("Left", Key::Left),
+    ("Right", Key::Right),
+    ("Up", Key::Up),
+    ("Apostrophe", Key::Apostrophe),
+    ("Backquote", Key::Backquote),
+    ("Backslash", Key::Backslash),
+    ("Comma", Key::Comma),
+    ("Equal", Key::Equal),
+    ("LeftBracket", Key::LeftBracket),
+    ("Minus", Key::Minus),
+    ("Period", Key::Period),
+    ("RightBracket", Key::RightBracket),
+    ("Semicolon", Key::Semicolon),
+    ("Slash", Key::Slash),
+    ("Backspace", Key::Backspace),
+    ("Delete", Key::Delete),
+    ("End", Key::End),
+    ("Enter", Key::Enter),
+    ("Escape", Key::Escape),
+    ("Home", Key::Home),
+    ("Insert", Key::Insert),
+    ("Menu", Key::Menu),
+    ("PageDown", Key::PageDown),
+    ("PageUp", Key::PageUp),
+    ("Pause", Key::Pause),
+    ("Space", Key::Space),
+    ("Tab", Key::Tab),
+    ("NumLock", Key::NumLock),
+    ("CapsLock", Key::CapsLock),
+    ("ScrollLock", Key::ScrollLock),
+    ("LeftShift", Key::LeftShift),
+    ("RightShift", Key::RightShift),
+    ("LeftCtrl", Key::LeftCtrl),
+    ("RightCtrl", Key::RightCtrl),
+    ("NumPad0", Key::NumPad0),
+    ("NumPad1", Key::NumPad1),
+    ("NumPad2", Key::NumPad2),
+    ("NumPad3", Key::NumPad3),
+    ("NumPad4", Key::NumPad4),
+    ("NumPad5", Key::NumPad5),
+    ("NumPad6", Key::NumPad6),
+    ("NumPad7", Key::NumPad7),
+    ("NumPad8", Key::NumPad8),
+    ("NumPad9", Key::NumPad9),
+    ("NumPadDot", Key::NumPadDot),
+    ("NumPadSlash", Key::NumPadSlash),
+    ("NumPadAsterisk", Key::NumPadAsterisk),
+    ("NumPadMinus", Key::NumPadMinus),
+    ("NumPadPlus", Key::NumPadPlus),
+    ("NumPadEnter", Key::NumPadEnter),
+    ("LeftAlt", Key::LeftAlt),
+    ("RightAlt", Key::RightAlt),
+    ("LeftSuper", Key::LeftSuper),
+    ("RightSuper", Key::RightSuper),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHA1: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn test_default() {
+        let bindings = Bindings::default().parse("", SHA1).unwrap();
+        assert_eq!(bindings, Bindings::default());
+        assert_eq!(bindings.keypad[0xC], [Key::Key4]);
+        assert_eq!(bindings.keypad_state(&[Key::Key1, Key::V]), 0x8002);
+        assert_eq!(bindings.keypad_state(&[Key::Escape]), 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = r#"
+            [keypad]
+            4 = "A"          # AZERTY
+            5 = ["z", "Up"]
+            c = "Apostrophe"
+
+            [hotkeys]
+            pause = "P"
+
+            [rom.0123456789ABCDEF0123456789ABCDEF01234567.keypad]
+            6 = "Right"
+            [rom.0123456789ABCDEF0123456789ABCDEF01234567.hotkeys]
+            reset = "Tab"
+
+            [rom.ffffffffffffffffffffffffffffffffffffffff.hotkeys]
+            quit = "Q"
+        "#;
+        let bindings = Bindings::default().parse(config, SHA1).unwrap();
+
+        assert_eq!(bindings.keypad[0x4], [Key::A]);
+        assert_eq!(bindings.keypad[0x5], [Key::Z, Key::Up]);
+        assert_eq!(bindings.keypad[0x6], [Key::Right]);
+        assert_eq!(bindings.keypad[0xC], [Key::Apostrophe]);
+
+        // A and Z moved to other CHIP-8 keys, so 7 and A are left unbound
+        assert!(bindings.keypad[0x7].is_empty());
+        assert!(bindings.keypad[0xA].is_empty());
+        assert_eq!(bindings.keypad_state(&[Key::A, Key::Up]), 0x0030);
+
+        assert_eq!(bindings.pause, Key::P);
+        assert_eq!(bindings.reset, Key::Tab);
+        assert_eq!(bindings.quit, Key::Escape);
+
+        let other = Bindings::default().parse(config, &"f".repeat(40)).unwrap();
+        assert_eq!(other.keypad[0x6], [Key::E]);
+        assert_eq!(other.quit, Key::Q);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error =
+            |config: &str| Bindings::default().parse(config, SHA1).unwrap_err();
+
+        assert_eq!(
+            error("[keypad]\n10 = \"A\""),
+            "Unknown CHIP-8 key '10' in [keypad]. Expected 0 to F"
+        );
+        assert_eq!(error("[keypad]\n1 = \"Foo\""), "Unknown key name 'Foo'");
+        assert_eq!(
+            error("[hotkeys]\nfire = \"Space\""),
+            "Unknown hotkey 'fire' in [hotkeys]. Expected one of: quit, \
+             pause, reset, save, load, mute, rewind, palette"
+        );
+        assert_eq!(
+            error("[hotkeys]\npause = 1"),
+            "Hotkey 'pause' must be a key name"
+        );
+        assert_eq!(error("keypad = 1"), "'keypad' must be a table");
+        assert!(error("[keypad").contains("line 1"));
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = [
+            ("up".to_string(), 5),
+            ("a".to_string(), 6),
+            ("player2Down".to_string(), 0xD),
+            ("select".to_string(), 0),
+        ];
+        let bindings = Bindings::with_layout(&layout);
+        assert_eq!(bindings.keypad[0x5], [Key::W, Key::Up]);
+        assert_eq!(bindings.keypad[0x6], [Key::E, Key::Space]);
+        assert_eq!(bindings.keypad[0xD], [Key::R, Key::K]);
+        assert_eq!(bindings.keypad[0x0], [Key::X]);
+
+        // The config file still has the last word
+        let bindings = bindings.parse("[keypad]\n5 = \"Up\"", SHA1).unwrap();
+        assert_eq!(bindings.keypad[0x5], [Key::Up]);
+        assert_eq!(bindings.keypad[0x6], [Key::E, Key::Space]);
+    }
+
+    #[test]
+    fn test_key_name() {
+        assert_eq!(key_name(Key::Escape), "Escape");
+        assert_eq!(host_key("1"), Ok(Key::Key1));
+        assert_eq!(host_key("numpad5"), Ok(Key::NumPad5));
+        assert_eq!(key_name(host_key("pageup").unwrap()), "PageUp");
+    }
+}
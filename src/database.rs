@@ -0,0 +1,441 @@
+use crate::movie;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Bundled with the emulator, covering the ROMs in roms/
+const BUNDLED: &str = include_str!("../database/programs.json");
+
+/// What is known about a ROM, looked up by its SHA-1 hash. The database is
+/// read in the programs.json format of the CHIP-8 community's
+/// chip-8-database (https://github.com/chip-8/chip-8-database): an array of
+/// programs, each with the ROMs that are versions of it:
+///
+/// ```text
+/// [{
+///     "title": "Pong",
+///     "authors": ["Paul Vervalin"],
+///     "release": "1990",
+///     "roms": {
+///         "b232ef880bd6060fb45fa6effed7edf0ae95670e": {
+///             "platforms": ["chip48"],
+///             "tickrate": 15,
+///             "keys": { "up": 1, "down": 4 },
+///             "colors": { "pixels": ["#000000", "#ffffff"] },
+///             "quirkyPlatforms": { "chip48": { "vblank": true } }
+///         }
+///     }
+/// }]
+/// ```
+///
+/// The first platform listed that this emulator supports is used, along
+/// with any quirks the ROM needs on it. Other fields are ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>, // Instructions per frame
+    pub keys: Vec<(String, u8)>, // Controls such as "up", and their keys
+    pub colours: Vec<u32>,     // 0xRRGGBB for each plane combination
+}
+
+/// ROM entries read from one or more database files
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    entries: HashMap<String, Entry>, // By lowercase SHA-1
+}
+
+impl Database {
+    /// The database that comes with the emulator
+    pub fn bundled() -> Database {
+        Database::parse(BUNDLED).expect("Bundled ROM database is invalid")
+    }
+
+    /// Read a database file, e.g. a local programs.json
+    pub fn load(fname: &str) -> Result<Database, io::Error> {
+        Database::parse(&fs::read_to_string(fname)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", fname, e)))
+    }
+
+    /// Parse a database in the programs.json format. Programs and ROMs
+    /// that can't be read are skipped with a warning, so that one bad
+    /// entry doesn't lose the rest of the file
+    pub fn parse(json: &str) -> Result<Database, io::Error> {
+        let programs: Value = serde_json::from_str(json)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let programs = programs
+            .as_array()
+            .ok_or_else(|| invalid_data("Expected an array of programs"))?;
+
+        let mut entries = HashMap::new();
+        for program in programs {
+            let title = match program.get("title").and_then(Value::as_str) {
+                Some(title) => title,
+                None => {
+                    eprintln!("Skipping a database program without a title");
+                    continue;
+                }
+            };
+            let roms = match program.get("roms").and_then(Value::as_object) {
+                Some(roms) => roms,
+                None => {
+                    eprintln!("Skipping database program '{}': No roms", title);
+                    continue;
+                }
+            };
+
+            for (sha1, rom) in roms {
+                match parse_rom(program, title, rom) {
+                    Ok(Some(entry)) => {
+                        entries.insert(sha1.to_lowercase(), entry);
+                    }
+                    Ok(None) => (),
+                    Err(e) => eprintln!(
+                        "Skipping database ROM {} ('{}'): {}",
+                        sha1, title, e
+                    ),
+                }
+            }
+        }
+        Ok(Database { entries })
+    }
+
+    /// Add the entries of `other`, replacing any for the same ROMs
+    pub fn merge(&mut self, other: Database) {
+        self.entries.extend(other.entries);
+    }
+
+    /// Look up a ROM by its SHA-1 hash, in hexadecimal
+    pub fn get(&self, sha1: &str) -> Option<&Entry> {
+        self.entries.get(&sha1.to_lowercase())
+    }
+
+    /// Look up a ROM image by its hash
+    pub fn identify(&self, rom: &[u8]) -> Option<&Entry> {
+        self.get(&movie::rom_sha1(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.title)?;
+        if !self.authors.is_empty() {
+            write!(f, " by {}", self.authors.join(", "))?;
+        }
+        if let Some(ref release) = self.release {
+            write!(f, " ({})", release)?;
+        }
+        Ok(())
+    }
+}
+
+// Returns None if the ROM is only for platforms this emulator lacks
+fn parse_rom(
+    program: &Value,
+    title: &str,
+    rom: &Value,
+) -> Result<Option<Entry>, String> {
+    let platforms = rom
+        .get("platforms")
+        .and_then(Value::as_array)
+        .ok_or("No platforms")?;
+    let found = platforms.iter().find_map(|id| {
+        let id = id.as_str()?;
+        platform(id).map(|(platform, quirks)| (id, platform, quirks))
+    });
+    let (id, platform, mut quirks) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let quirky = rom.get("quirkyPlatforms").and_then(|q| q.get(id));
+    if let Some(quirky) = quirky.and_then(Value::as_object) {
+        for (name, value) in quirky {
+            let value = value.as_bool().ok_or_else(|| {
+                format!("Quirk '{}' must be true or false", name)
+            })?;
+            set_quirk(&mut quirks, name, value);
+        }
+    }
+
+    let tickrate = match rom.get("tickrate") {
+        Some(tickrate) => Some(
+            tickrate
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .filter(|&n| n > 0)
+                .ok_or("The tickrate must be a positive number")?,
+        ),
+        None => None,
+    };
+
+    let mut keys = Vec::new();
+    if let Some(controls) = rom.get("keys").and_then(Value::as_object) {
+        for (control, key) in controls {
+            let key = key.as_u64().filter(|&k| k < 16).ok_or_else(|| {
+                format!("Key for '{}' must be from 0 to 15", control)
+            })?;
+            keys.push((control.clone(), key as u8));
+        }
+    }
+
+    let mut colours = Vec::new();
+    let pixels = rom.get("colors").and_then(|c| c.get("pixels"));
+    if let Some(pixels) = pixels.and_then(Value::as_array) {
+        for colour in pixels {
+            colours.push(colour.as_str().and_then(parse_colour).ok_or_else(
+                || format!("Invalid colour {}. Expected \"#RRGGBB\"", colour),
+            )?);
+        }
+    }
+
+    // Two colours are the background and foreground, and four or more
+    // cover every combination of the XO-CHIP planes
+    if colours.len() == 1 || colours.len() == 3 {
+        return Err(format!(
+            "Expected 2 or at least 4 colours, not {}",
+            colours.len()
+        ));
+    }
+
+    let authors = program
+        .get("authors")
+        .and_then(Value::as_array)
+        .map(|authors| {
+            authors
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let release = program
+        .get("release")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    Ok(Some(Entry {
+        title: title.to_string(),
+        authors,
+        release,
+        platform,
+        quirks,
+        tickrate,
+        keys,
+        colours,
+    }))
+}
+
+// The database's platform IDs, with the nearest of this emulator's
+// platforms and quirks profiles
+fn platform(id: &str) -> Option<(Platform, Quirks)> {
+    match id {
+        "originalChip8" | "hybridVIP" | "chip8x" => {
+            Some((Platform::Chip8, Quirks::cosmac_vip()))
+        }
+        "modernChip8" => Some((
+            Platform::Chip8,
+            Quirks {
+                logic_resets_vf: false,
+                display_wait: false,
+                ..Quirks::cosmac_vip()
+            },
+        )),
+        "chip48" => Some((Platform::Chip8, Quirks::chip48())),
+        "superchip1" | "superchip" => {
+            Some((Platform::Chip8, Quirks::superchip()))
+        }
+        "xochip" => Some((Platform::XoChip, Quirks::xo_chip())),
+        _ => None,
+    }
+}
+
+// Set one of the database's quirks. CHIP-48's I += x for Fx55/Fx65
+// ("memoryIncrementByX") can't be expressed, so it is ignored, as are
+// quirks this emulator doesn't know
+fn set_quirk(quirks: &mut Quirks, name: &str, value: bool) {
+    match name {
+        "shift" => quirks.shift_uses_vy = !value,
+        "memoryLeaveIUnchanged" => quirks.load_store_increments_i = !value,
+        "wrap" => quirks.clip_sprites = !value,
+        "jump" => quirks.jump_uses_vx = value,
+        "vblank" => quirks.display_wait = value,
+        "logic" => quirks.logic_resets_vf = value,
+        _ => (),
+    }
+}
+
+/// Parse a colour written "#RRGGBB" or "RRGGBB" as 0xRRGGBB
+pub fn parse_colour(colour: &str) -> Option<u32> {
+    let hex = colour.trim_start_matches('#');
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn invalid_data<E: Into<String>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[
+        {
+            "title": "Game",
+            "authors": ["A. Author", "B. Author"],
+            "release": "1991",
+            "roms": {
+                "0123456789ABCDEF0123456789ABCDEF01234567": {
+                    "file": "game.ch8",
+                    "platforms": ["megachip8", "chip48", "xochip"],
+                    "tickrate": 30,
+                    "keys": { "up": 5, "a": 10 },
+                    "colors": { "pixels": ["#102030", "#FFB000"] },
+                    "quirkyPlatforms": {
+                        "chip48": { "vblank": true, "shift": false },
+                        "xochip": { "wrap": false }
+                    }
+                },
+                "89abcdef0123456789abcdef0123456789abcdef": {
+                    "platforms": ["xochip"]
+                },
+                "ffffffffffffffffffffffffffffffffffffffff": {
+                    "platforms": ["megachip8"]
+                }
+            }
+        }
+    ]"##;
+
+    #[test]
+    fn test_parse() {
+        let db = Database::parse(PROGRAMS).unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.get(&"f".repeat(40)).is_none());
+
+        let entry = db.get("0123456789abcdef0123456789abcdef01234567").unwrap();
+        assert_eq!(entry.title, "Game");
+        assert_eq!(entry.to_string(), "Game by A. Author, B. Author (1991)");
+        assert_eq!(entry.platform, Platform::Chip8);
+        assert_eq!(
+            entry.quirks,
+            Quirks {
+                display_wait: true,
+                shift_uses_vy: true,
+                ..Quirks::chip48()
+            }
+        );
+        assert_eq!(entry.tickrate, Some(30));
+        assert_eq!(entry.keys, [("a".to_string(), 10), ("up".to_string(), 5)]);
+        assert_eq!(entry.colours, [0x102030, 0xFFB000]);
+
+        let entry = db.get("89abcdef0123456789abcdef0123456789abcdef").unwrap();
+        assert_eq!(entry.platform, Platform::XoChip);
+        assert_eq!(entry.quirks, Quirks::xo_chip());
+        assert_eq!(entry.tickrate, None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut db = Database::parse(PROGRAMS).unwrap();
+        let local = r#"[{
+            "title": "Game (fixed)",
+            "roms": { "89ABCDEF0123456789ABCDEF0123456789ABCDEF": {
+                "platforms": ["originalChip8"]
+            } }
+        }]"#;
+        db.merge(Database::parse(local).unwrap());
+
+        let entry = db.get("89abcdef0123456789abcdef0123456789abcdef").unwrap();
+        assert_eq!(entry.to_string(), "Game (fixed)");
+        assert_eq!(entry.quirks, Quirks::cosmac_vip());
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |json: &str| Database::parse(json).unwrap_err().to_string();
+
+        assert_eq!(error("{}"), "Expected an array of programs");
+        assert!(error("[").contains("line 1"));
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let error = |rom: &str| {
+            let program = serde_json::json!({ "title": "T" });
+            let rom: Value = serde_json::from_str(rom).unwrap();
+            parse_rom(&program, "T", &rom).unwrap_err()
+        };
+
+        assert_eq!(error("{}"), "No platforms");
+        assert_eq!(
+            error(r#"{ "platforms": ["chip48"], "keys": { "up": 16 } }"#),
+            "Key for 'up' must be from 0 to 15"
+        );
+        assert_eq!(
+            error(
+                r#"{ "platforms": ["chip48"], "colors": { "pixels": [0] } }"#
+            ),
+            "Invalid colour 0. Expected \"#RRGGBB\""
+        );
+        assert_eq!(
+            error(
+                r##"{
+                    "platforms": ["chip48"],
+                    "colors": { "pixels": ["#000000", "#FFFFFF", "#AAAAAA"] }
+                }"##
+            ),
+            "Expected 2 or at least 4 colours, not 3"
+        );
+    }
+
+    #[test]
+    fn test_skip_invalid() {
+        let db = Database::parse(
+            r#"[
+                { "roms": { "01": { "platforms": ["chip48"] } } },
+                { "title": "No ROMs" },
+                { "title": "T", "roms": {
+                    "02": { "platforms": ["chip48"], "tickrate": 0 },
+                    "03": { "platforms": ["xochip"] }
+                } },
+                { "title": "U", "roms": { "04": { "platforms": ["chip48"] } } }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get("03").unwrap().title, "T");
+        assert_eq!(db.get("04").unwrap().title, "U");
+    }
+
+    #[test]
+    fn test_bundled() {
+        let db = Database::bundled();
+        assert!(!db.is_empty());
+
+        let pong = [0x6A, 0x02, 0x6B, 0x0C, 0x6C, 0x3F, 0x6D, 0x0C, 0xA2, 0xEA];
+        assert!(db.identify(&pong).is_none());
+        let entry = db.get("b232ef880bd6060fb45fa6effed7edf0ae95670e").unwrap();
+        assert_eq!(entry.to_string(), "Pong by Paul Vervalin (1990)");
+    }
+}
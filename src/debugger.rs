@@ -0,0 +1,939 @@
+use crate::chip8::{AccessKind, Chip8, Chip8Error, StepOutcome};
+use crate::instruction::{decode, Instruction, Syntax};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+
+pub const HELP: &str = "\
+Commands (all numbers but watchpoint numbers are hexadecimal):
+  c, continue          Resume execution
+  p, pause             Pause execution
+  s, step [n]          Execute n instructions (1 by default)
+  n, next              Step over a 2nnn call
+  o, out               Run until the current subroutine returns
+  u, until ADDR        Run until the PC reaches ADDR
+  b, break ADDR        Set a breakpoint
+  d, delete [ADDR]     Delete a breakpoint, or all of them
+  bl, breakpoints      List breakpoints
+  watch ADDR [LEN]     Break when memory is written
+  rwatch ADDR [LEN]    Break when memory is read, including as code
+  awatch ADDR [LEN]    Break when memory is read or written
+  watch REG [VALUE]    Break when v0-vf, i, dt or st changes (to VALUE)
+  wl, watches          List watchpoints
+  unwatch [N]          Delete watchpoint N, or all of them
+  r, regs              Show the registers
+  m, mem ADDR [LEN]    Show LEN bytes of memory (0x40 by default)
+  w, write ADDR BYTE.. Write bytes to memory
+  set REG VALUE        Set v0-vf, i, pc, sp, dt or st
+  set stack ADDR..     Replace the stack, oldest first
+  h, help              Show this help
+An empty line repeats the last command";
+
+// What the debugger is waiting for while the ROM runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // Stop once the call at the given depth returns to `ret`
+    StepOver { depth: usize, ret: u16 },
+    // Stop once the stack is shallower than `depth`
+    StepOut { depth: usize },
+    RunTo(u16),
+}
+
+// A condition checked after every instruction while running
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Watch {
+    // Accesses to `len` bytes of RAM from `addr`
+    Memory {
+        addr: usize,
+        len: usize,
+        reads: bool,
+        writes: bool,
+    },
+    // Changes to a register, or only those that set it to `value`
+    Register {
+        register: Register,
+        value: Option<u16>,
+    },
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Watch::Memory {
+                addr,
+                len,
+                reads,
+                writes,
+            } => {
+                let kind = match (reads, writes) {
+                    (true, true) => "access",
+                    (true, false) => "read",
+                    _ => "write",
+                };
+                write!(f, "{} {:03X}", kind, addr)?;
+                if len > 1 {
+                    write!(f, "-{:03X}", addr + len - 1)?;
+                }
+                Ok(())
+            }
+            Watch::Register {
+                register,
+                value: Some(value),
+            } => write!(f, "{} becomes {:02X}", register, value),
+            Watch::Register {
+                register,
+                value: None,
+            } => write!(f, "{} changes", register),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Register {
+    V(usize),
+    I,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Register> {
+        match name.to_lowercase().as_str() {
+            "i" => Some(Register::I),
+            "dt" => Some(Register::DelayTimer),
+            "st" => Some(Register::SoundTimer),
+            name if name.len() == 2 && name.starts_with('v') => {
+                usize::from_str_radix(&name[1..], 16).ok().map(Register::V)
+            }
+            _ => None,
+        }
+    }
+
+    fn get(self, chip8: &Chip8) -> u16 {
+        match self {
+            Register::V(x) => chip8.v()[x] as u16,
+            Register::I => chip8.i(),
+            Register::DelayTimer => chip8.delay_timer() as u16,
+            Register::SoundTimer => chip8.sound_timer() as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::DelayTimer => write!(f, "DT"),
+            Register::SoundTimer => write!(f, "ST"),
+        }
+    }
+}
+
+/// Breakpoints and single-stepping around `Chip8::execute_opcode`. The
+/// frontend calls `run_frame` instead of `Chip8::run_frame` while the
+/// debugger is attached, and passes lines typed at the prompt to `command`
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    // Don't stop at a breakpoint on the first instruction after resuming,
+    // which would be the one that was just stopped at
+    resuming: bool,
+    stop_reason: Option<String>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(paused: bool) -> Debugger {
+        Debugger {
+            mode: if paused { Mode::Paused } else { Mode::Running },
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            resuming: true,
+            stop_reason: None,
+            last_command: String::new(),
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self, reason: String) {
+        self.mode = Mode::Paused;
+        self.stop_reason = Some(reason);
+    }
+
+    pub fn resume(&mut self) {
+        self.mode = Mode::Running;
+        self.resuming = true;
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &u16> {
+        self.breakpoints.iter()
+    }
+
+    /// Why execution last stopped, if it has stopped since this was last
+    /// called
+    pub fn take_stop_reason(&mut self) -> Option<String> {
+        self.stop_reason.take()
+    }
+
+    /// Run one 60Hz frame, like `Chip8::run_frame`, but stop before any
+    /// instruction at a breakpoint, after one that triggers a watchpoint or
+    /// once a step has finished. The timers are only ticked if the whole
+    /// frame runs
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions: u32,
+    ) -> Result<StepOutcome, Chip8Error> {
+        let mut outcome = StepOutcome::Executed;
+        let mut before = self.watched_values(chip8);
+        self.watch_memory(chip8);
+
+        let ended = chip8.run_frame_with(instructions, |chip8| {
+            if self.paused() {
+                return Ok(None);
+            }
+
+            let pc = chip8.pc();
+            if !self.resuming && self.breakpoints.contains(&pc) {
+                self.pause(format!("Breakpoint at {:03X}", pc));
+                return Ok(None);
+            }
+            self.resuming = false;
+
+            outcome = self.execute(chip8)?;
+            if !self.paused() {
+                self.check_mode(chip8);
+            }
+            if self.paused() {
+                return Ok(None);
+            }
+            // Watches on the timers compare them with their values after
+            // the last instruction of the frame
+            before = self.watched_values(chip8);
+            Ok(Some(outcome))
+        })?;
+
+        if ended.is_some() {
+            self.check_watches(chip8, &before, "at the end of the frame");
+        }
+        Ok(outcome)
+    }
+
+    // Memory accesses are only recorded while they are being watched
+    fn watch_memory(&self, chip8: &mut Chip8) {
+        let watch_memory = self.watches.iter().any(|w| match *w {
+            Watch::Memory { .. } => true,
+            _ => false,
+        });
+        chip8.set_watch_memory(watch_memory);
+    }
+
+    // Execute one instruction, pausing if it triggers a watchpoint.
+    // Stepping and running both go through here
+    fn execute(
+        &mut self,
+        chip8: &mut Chip8,
+    ) -> Result<StepOutcome, Chip8Error> {
+        let pc = chip8.pc();
+        let opcode = chip8.opcode_at(pc);
+        let before = self.watched_values(chip8);
+        let outcome = chip8.execute_opcode()?;
+
+        let cause = format!("by {:04X} at {:03X}", opcode, pc);
+        self.check_watches(chip8, &before, &cause);
+        Ok(outcome)
+    }
+
+    // Current values of the watched registers, in the order of `watches`
+    fn watched_values(&self, chip8: &Chip8) -> Vec<u16> {
+        self.watches
+            .iter()
+            .map(|watch| match *watch {
+                Watch::Register { register, .. } => register.get(chip8),
+                Watch::Memory { .. } => 0,
+            })
+            .collect()
+    }
+
+    // Pause at the first watchpoint triggered since `before` was taken.
+    // `cause` says what triggered it. Returns whether one was
+    fn check_watches(
+        &mut self,
+        chip8: &Chip8,
+        before: &[u16],
+        cause: &str,
+    ) -> bool {
+        for (n, watch) in self.watches.iter().enumerate() {
+            let hit = match *watch {
+                Watch::Memory {
+                    addr,
+                    len,
+                    reads,
+                    writes,
+                } => chip8
+                    .memory_accesses()
+                    .iter()
+                    .find(|a| {
+                        a.addr >= addr
+                            && a.addr < addr + len
+                            && if a.kind == AccessKind::Write {
+                                writes
+                            } else {
+                                reads
+                            }
+                    })
+                    .map(|a| {
+                        let action = match a.kind {
+                            AccessKind::Fetch => "fetched",
+                            AccessKind::Read => "read",
+                            AccessKind::Write => "wrote",
+                        };
+                        format!(
+                            "{} {:02X} at {:03X} {}",
+                            action, a.value, a.addr, cause
+                        )
+                    }),
+                Watch::Register { register, value } => {
+                    let after = register.get(chip8);
+                    if after != before[n] && value.map_or(true, |v| v == after)
+                    {
+                        Some(format!(
+                            "{} changed from {:02X} to {:02X} {}",
+                            register, before[n], after, cause
+                        ))
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            if let Some(hit) = hit {
+                self.pause(format!(
+                    "Watchpoint {} ({}): {}",
+                    n + 1,
+                    watch,
+                    hit
+                ));
+                return true;
+            }
+        }
+        false
+    }
+
+    // Pause if the step or run-to in progress has finished
+    fn check_mode(&mut self, chip8: &Chip8) {
+        let pc = chip8.pc();
+        let depth = chip8.stack().len();
+
+        match self.mode {
+            Mode::StepOver { depth: d, ret } if depth == d && pc == ret => {
+                self.pause(format!("Stepped over to {:03X}", pc))
+            }
+            Mode::StepOut { depth: d } if depth < d => {
+                self.pause(format!("Returned to {:03X}", pc))
+            }
+            Mode::RunTo(addr) if pc == addr => {
+                self.pause(format!("Reached {:03X}", pc))
+            }
+            _ => (),
+        }
+    }
+
+    /// Run a command typed at the prompt and return its output
+    pub fn command(
+        &mut self,
+        chip8: &mut Chip8,
+        line: &str,
+    ) -> Result<String, io::Error> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match fields.split_first() {
+            Some((&name, args)) => (name, args),
+            None => return Ok(String::new()),
+        };
+
+        match (name, args.len()) {
+            ("c", 0) | ("continue", 0) => {
+                self.resume();
+                Ok("Continuing".to_string())
+            }
+            ("p", 0) | ("pause", 0) => {
+                self.mode = Mode::Paused;
+                Ok(registers(chip8))
+            }
+            ("s", 0) | ("step", 0) => self.step(chip8, 1),
+            ("s", 1) | ("step", 1) => {
+                let count = parse_number(args[0])?;
+                self.step(chip8, count)
+            }
+            ("n", 0) | ("next", 0) => {
+                let pc = chip8.pc();
+                let is_call = match decode(chip8.opcode_at(pc)) {
+                    Ok(Instruction::Call { .. }) => true,
+                    _ => false,
+                };
+                if !is_call {
+                    return self.step(chip8, 1);
+                }
+                self.resume();
+                self.mode = Mode::StepOver {
+                    depth: chip8.stack().len(),
+                    ret: pc.wrapping_add(2),
+                };
+                Ok(format!("Stepping over the call at {:03X}", pc))
+            }
+            ("o", 0) | ("out", 0) => {
+                let depth = chip8.stack().len();
+                if depth == 0 {
+                    return Err(invalid_input("Not in a subroutine"));
+                }
+                self.resume();
+                self.mode = Mode::StepOut { depth };
+                Ok("Running until the subroutine returns".to_string())
+            }
+            ("u", 1) | ("until", 1) => {
+                let addr = parse_address(args[0])?;
+                self.resume();
+                self.mode = Mode::RunTo(addr);
+                Ok(format!("Running until {:03X}", addr))
+            }
+            ("b", 1) | ("break", 1) => {
+                let addr = parse_address(args[0])?;
+                self.breakpoints.insert(addr);
+                Ok(format!("Breakpoint set at {:03X}", addr))
+            }
+            ("d", 0) | ("delete", 0) => {
+                self.breakpoints.clear();
+                Ok("Deleted all breakpoints".to_string())
+            }
+            ("d", 1) | ("delete", 1) => {
+                let addr = parse_address(args[0])?;
+                if self.breakpoints.remove(&addr) {
+                    Ok(format!("Deleted breakpoint at {:03X}", addr))
+                } else {
+                    Err(invalid_input(&format!(
+                        "No breakpoint at {:03X}",
+                        addr
+                    )))
+                }
+            }
+            ("bl", 0) | ("breakpoints", 0) => {
+                if self.breakpoints.is_empty() {
+                    return Ok("No breakpoints".to_string());
+                }
+                let list: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|addr| format!("{:03X}", addr))
+                    .collect();
+                Ok(list.join(" "))
+            }
+            ("watch", 1) | ("watch", 2) => {
+                let watch = match Register::parse(args[0]) {
+                    Some(register) => Watch::Register {
+                        register,
+                        value: match args.get(1) {
+                            Some(value) => Some(parse_address(value)?),
+                            None => None,
+                        },
+                    },
+                    None => memory_watch(args, false, true)?,
+                };
+                self.add_watch(watch)
+            }
+            ("rwatch", 1) | ("rwatch", 2) => {
+                let watch = memory_watch(args, true, false)?;
+                self.add_watch(watch)
+            }
+            ("awatch", 1) | ("awatch", 2) => {
+                let watch = memory_watch(args, true, true)?;
+                self.add_watch(watch)
+            }
+            ("wl", 0) | ("watches", 0) => {
+                if self.watches.is_empty() {
+                    return Ok("No watchpoints".to_string());
+                }
+                let list: Vec<String> = self
+                    .watches
+                    .iter()
+                    .enumerate()
+                    .map(|(n, watch)| format!("{}: {}", n + 1, watch))
+                    .collect();
+                Ok(list.join("\n"))
+            }
+            ("unwatch", 0) => {
+                self.watches.clear();
+                Ok("Deleted all watchpoints".to_string())
+            }
+            ("unwatch", 1) => {
+                let n = args[0].parse().unwrap_or(0);
+                if n == 0 || n > self.watches.len() {
+                    return Err(invalid_input(&format!(
+                        "No watchpoint {}",
+                        args[0]
+                    )));
+                }
+                let watch = self.watches.remove(n - 1);
+                Ok(format!("Deleted watchpoint {} ({})", n, watch))
+            }
+            ("r", 0) | ("regs", 0) => Ok(registers(chip8)),
+            ("m", 1) | ("mem", 1) => {
+                memory(chip8, parse_address(args[0])?, 0x40)
+            }
+            ("m", 2) | ("mem", 2) => {
+                memory(chip8, parse_address(args[0])?, parse_number(args[1])?)
+            }
+            ("w", n) | ("write", n) if n >= 2 => {
+                let addr = parse_address(args[0])? as usize;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|b| parse_byte(b))
+                    .collect::<Result<Vec<u8>, io::Error>>()?;
+                if addr + bytes.len() > chip8.memory().len() {
+                    return Err(invalid_input("Write is out of memory"));
+                }
+                chip8.memory_mut()[addr..(addr + bytes.len())]
+                    .copy_from_slice(&bytes);
+                memory(chip8, addr as u16, bytes.len())
+            }
+            ("set", n) if n >= 2 && args[0] == "stack" => {
+                let stack = args[1..]
+                    .iter()
+                    .map(|a| parse_address(a))
+                    .collect::<Result<Vec<u16>, io::Error>>()?;
+                if stack.len() > 16 {
+                    return Err(invalid_input("The stack holds 16 addresses"));
+                }
+                chip8.set_stack(&stack);
+                Ok(registers(chip8))
+            }
+            ("set", 2) => {
+                set_register(chip8, args[0], args[1])?;
+                Ok(registers(chip8))
+            }
+            ("h", 0) | ("help", 0) => Ok(HELP.to_string()),
+            _ => Err(invalid_input(&format!(
+                "Unknown command '{}'. Type 'help' for a list",
+                line
+            ))),
+        }
+    }
+
+    fn add_watch(&mut self, watch: Watch) -> Result<String, io::Error> {
+        self.watches.push(watch);
+        Ok(format!("Watchpoint {} set ({})", self.watches.len(), watch))
+    }
+
+    // Execute up to `count` instructions while paused
+    fn step(
+        &mut self,
+        chip8: &mut Chip8,
+        count: usize,
+    ) -> Result<String, io::Error> {
+        self.mode = Mode::Paused;
+        self.watch_memory(chip8);
+
+        for _ in 0..count {
+            let outcome = self.execute(chip8);
+            if let Some(reason) = self.stop_reason.take() {
+                return Ok(format!("{}\n{}", reason, registers(chip8)));
+            }
+            match outcome {
+                Ok(StepOutcome::Executed) => (),
+                Ok(StepOutcome::WaitingForKey) => {
+                    return Ok(format!(
+                        "Waiting for a key to be pressed and released\n{}",
+                        registers(chip8)
+                    ))
+                }
+                Ok(StepOutcome::WaitingForVBlank) => {
+                    return Ok(format!(
+                        "Waiting for the next frame (continue to run it)\n{}",
+                        registers(chip8)
+                    ))
+                }
+                Ok(StepOutcome::Exit) => {
+                    return Ok("The ROM has exited".to_string())
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        e.to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(registers(chip8))
+    }
+}
+
+/// The registers, stack and the opcode at the PC
+pub fn registers(chip8: &Chip8) -> String {
+    let hex = |bytes: &[u8]| {
+        let bytes: Vec<String> =
+            bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        bytes.join(" ")
+    };
+    let stack: Vec<String> =
+        chip8.stack().iter().map(|a| format!("{:03X}", a)).collect();
+
+    // The instruction at the PC, e.g. "2208 CALL #208"
+    let pc = chip8.pc();
+    let opcode = chip8.opcode_at(pc);
+    let current = match decode(opcode) {
+        Ok(instruction) => {
+            let long = Some(chip8.opcode_at(pc.wrapping_add(2)));
+            let text = instruction.format(long, Syntax::Cowgod);
+            format!("{:04X} {}", opcode, text)
+        }
+        Err(_) => format!("{:04X}", opcode),
+    };
+
+    format!(
+        "PC {:03X} ({})  I {:03X}  SP {:X}  DT {:02X}  ST {:02X}\n\
+         V0-V7 {}\n\
+         V8-VF {}\n\
+         Stack [{}]",
+        pc,
+        current,
+        chip8.i(),
+        chip8.stack().len(),
+        chip8.delay_timer(),
+        chip8.sound_timer(),
+        hex(&chip8.v()[..8]),
+        hex(&chip8.v()[8..]),
+        stack.join(" ")
+    )
+}
+
+// Hex dump, 16 bytes per line
+fn memory(chip8: &Chip8, addr: u16, len: usize) -> Result<String, io::Error> {
+    let start = addr as usize;
+    let end = start.saturating_add(len).min(chip8.memory().len());
+    if start >= end {
+        return Err(invalid_input("Address is out of memory"));
+    }
+
+    let lines: Vec<String> = chip8.memory()[start..end]
+        .chunks(16)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let bytes: Vec<String> =
+                chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{:03X}: {}", start + n * 16, bytes.join(" "))
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+// Watch ADDR [LEN] bytes of memory
+fn memory_watch(
+    args: &[&str],
+    reads: bool,
+    writes: bool,
+) -> Result<Watch, io::Error> {
+    let addr = parse_address(args[0])? as usize;
+    let len = match args.get(1) {
+        Some(len) => parse_number(len)?,
+        None => 1,
+    };
+    if len == 0 {
+        return Err(invalid_input("Watchpoints cover at least one byte"));
+    }
+    // Nothing lies past the last address, so don't let `addr + len` overflow
+    let len = len.min(0x10000 - addr);
+    Ok(Watch::Memory {
+        addr,
+        len,
+        reads,
+        writes,
+    })
+}
+
+fn set_register(
+    chip8: &mut Chip8,
+    name: &str,
+    value: &str,
+) -> Result<(), io::Error> {
+    match name.to_lowercase().as_str() {
+        "pc" => chip8.set_pc(parse_address(value)?),
+        "sp" => {
+            // Entries pushed onto a deeper stack start as zero
+            let sp = parse_number(value)?;
+            if sp > 16 {
+                return Err(invalid_input("The stack holds 16 addresses"));
+            }
+            let mut stack = chip8.stack().to_vec();
+            stack.resize(sp, 0);
+            chip8.set_stack(&stack);
+        }
+        _ => match Register::parse(name) {
+            Some(Register::V(x)) => chip8.set_v(x, parse_byte(value)?),
+            Some(Register::I) => chip8.set_i(parse_address(value)?),
+            Some(Register::DelayTimer) => {
+                chip8.set_delay_timer(parse_byte(value)?)
+            }
+            Some(Register::SoundTimer) => {
+                chip8.set_sound_timer(parse_byte(value)?)
+            }
+            None => {
+                return Err(invalid_input(&format!(
+                    "Unknown register '{}'. Expected v0-vf, i, pc, sp, dt \
+                     or st",
+                    name
+                )))
+            }
+        },
+    }
+    Ok(())
+}
+
+fn parse_number(s: &str) -> Result<usize, io::Error> {
+    let digits = s.trim_start_matches("0x");
+    usize::from_str_radix(digits, 16).map_err(|_| {
+        invalid_input(&format!("Expected a hexadecimal number, got '{}'", s))
+    })
+}
+
+fn parse_address(s: &str) -> Result<u16, io::Error> {
+    let n = parse_number(s)?;
+    if n > 0xFFFF {
+        return Err(invalid_input(&format!("Address {} is too large", s)));
+    }
+    Ok(n as u16)
+}
+
+fn parse_byte(s: &str) -> Result<u8, io::Error> {
+    let n = parse_number(s)?;
+    if n > 0xFF {
+        return Err(invalid_input(&format!("Byte {} is too large", s)));
+    }
+    Ok(n as u8)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // 200: call 208, 202: add v1 1, 204: jump 202,
+    // 208: add v0 1, 20A: add v0 1, 20C: return
+    const ROM: [u8; 14] = [
+        0x22, 0x08, 0x71, 0x01, 0x12, 0x02, 0x00, 0x00, 0x70, 0x01, 0x70, 0x01,
+        0x00, 0xEE,
+    ];
+
+    fn load() -> Chip8 {
+        Chip8::from_bytes(&ROM, Platform::Chip8, Quirks::default()).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut chip8 = load();
+        let mut debugger = Debugger::new(false);
+
+        debugger.command(&mut chip8, "b 20a").unwrap();
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert!(debugger.paused());
+        assert_eq!(chip8.pc(), 0x20A);
+        assert_eq!(chip8.v()[0], 1);
+        assert_eq!(
+            debugger.take_stop_reason(),
+            Some("Breakpoint at 20A".to_string())
+        );
+        assert_eq!(chip8.delay_timer(), 0);
+
+        // Resuming doesn't stop at the same breakpoint again
+        debugger.command(&mut chip8, "c").unwrap();
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert!(!debugger.paused());
+        assert!(chip8.v()[1] > 0);
+
+        assert_eq!(debugger.command(&mut chip8, "bl").unwrap(), "20A");
+        debugger.command(&mut chip8, "d 20a").unwrap();
+        assert!(debugger.command(&mut chip8, "d 20a").is_err());
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut chip8 = load();
+        let mut debugger = Debugger::new(true);
+
+        // Nothing runs while paused
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+
+        debugger.command(&mut chip8, "s").unwrap();
+        assert_eq!(chip8.pc(), 0x208);
+        debugger.command(&mut chip8, "").unwrap();
+        assert_eq!(chip8.pc(), 0x20A);
+
+        debugger.command(&mut chip8, "out").unwrap();
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert!(debugger.paused());
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.v()[0], 2);
+
+        // Step over a call
+        chip8.set_pc(0x200);
+        debugger.command(&mut chip8, "next").unwrap();
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(chip8.v()[0], 4);
+        assert!(debugger.command(&mut chip8, "out").is_err());
+
+        // Next on anything else is a single step
+        debugger.command(&mut chip8, "n").unwrap();
+        assert_eq!(chip8.pc(), 0x204);
+
+        debugger.command(&mut chip8, "until 202").unwrap();
+        debugger.run_frame(&mut chip8, 10).unwrap();
+        assert_eq!(chip8.pc(), 0x202);
+        assert_eq!(
+            debugger.take_stop_reason(),
+            Some("Reached 202".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // 200: v0 = 5, 202: I = 300, 204: BCD of v0, 206: draw at I,
+        // 208: add v0 1, 20A: jump 204
+        let rom = [
+            0x60, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0xD0, 0x01, 0x70, 0x01, 0x12,
+            0x04,
+        ];
+        let mut chip8 =
+            Chip8::from_bytes(&rom, Platform::Chip8, Quirks::default())
+                .unwrap();
+        let mut debugger = Debugger::new(false);
+        let run = |debugger: &mut Debugger, chip8: &mut Chip8| {
+            debugger.resume();
+            debugger.run_frame(chip8, 20).unwrap();
+            debugger.take_stop_reason().unwrap_or_default()
+        };
+
+        debugger.command(&mut chip8, "watch 301").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (write 301): wrote 00 at 301 by F033 at 204"
+        );
+        assert_eq!(chip8.pc(), 0x206);
+
+        debugger.command(&mut chip8, "unwatch").unwrap();
+        debugger.command(&mut chip8, "rwatch 300 3").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (read 300-302): read 00 at 300 by D001 at 206"
+        );
+
+        debugger.command(&mut chip8, "awatch 208").unwrap();
+        debugger.command(&mut chip8, "unwatch 1").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (access 208): fetched 70 at 208 by 7001 at 208"
+        );
+
+        debugger.command(&mut chip8, "unwatch").unwrap();
+        debugger.command(&mut chip8, "watch v0 7").unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (V0 becomes 07): V0 changed from 06 to 07 by 7001 \
+             at 208"
+        );
+
+        // Timers are checked as they tick at the end of the frame
+        debugger.command(&mut chip8, "unwatch").unwrap();
+        debugger.command(&mut chip8, "watch dt").unwrap();
+        chip8.set_delay_timer(2);
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (DT changes): DT changed from 02 to 01 at the end \
+             of the frame"
+        );
+        assert_eq!(
+            debugger.command(&mut chip8, "wl").unwrap(),
+            "1: DT changes"
+        );
+
+        // Stepping stops at watchpoints too
+        debugger.command(&mut chip8, "unwatch").unwrap();
+        debugger.command(&mut chip8, "watch 301").unwrap();
+        chip8.set_pc(0x204);
+        let output = debugger.command(&mut chip8, "s 3").unwrap();
+        assert!(output.starts_with(
+            "Watchpoint 1 (write 301): wrote 01 at 301 by F033 at 204\n"
+        ));
+        assert_eq!(chip8.pc(), 0x206);
+
+        // A watch reaching past the end of memory doesn't overflow
+        debugger.command(&mut chip8, "unwatch").unwrap();
+        debugger
+            .command(&mut chip8, "rwatch 300 ffffffffffffffff")
+            .unwrap();
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "Watchpoint 1 (read 300-FFFF): read 00 at 300 by D001 at 206"
+        );
+
+        assert!(debugger.command(&mut chip8, "unwatch 2").is_err());
+        assert!(debugger.command(&mut chip8, "watch 300 0").is_err());
+        assert!(debugger.command(&mut chip8, "rwatch v0").is_err());
+    }
+
+    #[test]
+    fn test_inspect_and_modify() {
+        let mut chip8 = load();
+        let mut debugger = Debugger::new(true);
+
+        debugger.command(&mut chip8, "set v3 7f").unwrap();
+        debugger.command(&mut chip8, "set I 0x300").unwrap();
+        debugger.command(&mut chip8, "set dt 10").unwrap();
+        debugger.command(&mut chip8, "set stack 200 300").unwrap();
+        debugger.command(&mut chip8, "set sp 1").unwrap();
+        assert_eq!(chip8.v()[3], 0x7F);
+        assert_eq!(chip8.i(), 0x300);
+        assert_eq!(chip8.delay_timer(), 0x10);
+        assert_eq!(chip8.stack(), [0x200]);
+
+        let regs = debugger.command(&mut chip8, "r").unwrap();
+        assert!(regs.starts_with("PC 200 (2208 CALL #208)  I 300  SP 1  DT 10"));
+        assert!(regs.contains("V0-V7 00 00 00 7F 00 00 00 00"));
+
+        debugger.command(&mut chip8, "w 300 de ad").unwrap();
+        assert_eq!(
+            debugger.command(&mut chip8, "mem 2fe 4").unwrap(),
+            "2FE: 00 00 DE AD"
+        );
+        assert_eq!(
+            debugger
+                .command(&mut chip8, "mem ffe ffffffffffffffff")
+                .unwrap(),
+            "FFE: 00 00"
+        );
+
+        assert!(debugger.command(&mut chip8, "set v3 100").is_err());
+        assert!(debugger.command(&mut chip8, "set vg 1").is_err());
+        assert!(debugger.command(&mut chip8, "set sp 11").is_err());
+        assert!(debugger.command(&mut chip8, "w fff 1 2").is_err());
+        assert!(debugger.command(&mut chip8, "jump").is_err());
+    }
+}
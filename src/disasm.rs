@@ -0,0 +1,268 @@
+pub use crate::instruction::{Syntax, SYNTAX_NAMES};
+
+use crate::instruction::{decode, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+
+// ROMs are loaded at 0x200
+const START: usize = 0x200;
+
+// Column where the address and opcode comments start
+const COMMENT_COLUMN: usize = 28;
+
+// Code found by tracing the ROM, and the addresses that it refers to
+struct Trace {
+    instructions: BTreeMap<usize, usize>, // Address and length in bytes
+    code_targets: BTreeSet<usize>,        // Targets of jumps and calls
+    data_targets: BTreeSet<usize>,        // Addresses loaded into I
+}
+
+/// Disassemble a ROM loaded at 0x200. Code is found by following every
+/// path from 0x200 through jumps, calls and skips, and jump targets and
+/// data loaded into I are labelled. Everything that isn't reached is shown
+/// as data bytes, with the sprite they would draw alongside
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String {
+    let trace = trace(rom);
+    let end = START + rom.len();
+
+    // Instructions reached at an odd offset can overlap others, so only
+    // those that can be laid out in order are shown as code
+    let mut items = Vec::new();
+    let mut addr = START;
+    while addr < end {
+        let len = match trace.instructions.get(&addr) {
+            Some(&len) if addr + len <= end => len,
+            _ => 1,
+        };
+        items.push((addr, len));
+        addr += len;
+    }
+
+    let labels: BTreeMap<usize, String> = items
+        .iter()
+        .filter(|&&(addr, _)| {
+            trace.code_targets.contains(&addr)
+                || trace.data_targets.contains(&addr)
+                || (addr == START && syntax == Syntax::Octo)
+        })
+        .map(|&(addr, len)| {
+            let name = if addr == START && syntax == Syntax::Octo {
+                // Octo starts running at the label "main"
+                "main".to_string()
+            } else if trace.instructions.get(&addr) == Some(&len) {
+                format!("L{:03X}", addr)
+            } else {
+                format!("D{:03X}", addr)
+            };
+            (addr, name)
+        })
+        .collect();
+    let name = |addr: usize, digits: usize| match labels.get(&addr) {
+        Some(label) => label.clone(),
+        None => syntax.hex(addr, digits),
+    };
+
+    let mut out = String::new();
+    for &(addr, len) in &items {
+        if let Some(label) = labels.get(&addr) {
+            match syntax {
+                Syntax::Cowgod => out.push_str(&format!("{}:\n", label)),
+                Syntax::Octo => out.push_str(&format!(": {}\n", label)),
+            }
+        }
+
+        let bytes = &rom[(addr - START)..(addr - START + len)];
+        let (text, comment) = if len == 1 {
+            let data = match syntax {
+                Syntax::Cowgod => {
+                    format!("DB {}", syntax.hex(bytes[0] as usize, 2))
+                }
+                Syntax::Octo => syntax.hex(bytes[0] as usize, 2),
+            };
+            let bitmap: String = (0..8)
+                .map(|bit| {
+                    if bytes[0] & (0x80 >> bit) != 0 {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            (data, format!("{:03X}  {}", addr, bitmap))
+        } else {
+            let opcode = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            let long =
+                bytes.get(2..4).map(|b| (b[0] as u16) << 8 | b[1] as u16);
+            // Only instructions are traced, so the opcode always decodes
+            let text = match decode(opcode) {
+                Ok(instruction) => {
+                    instruction.format_with_labels(long, syntax, &name)
+                }
+                Err(_) => String::new(),
+            };
+            let hex: Vec<String> =
+                bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            (text, format!("{:03X}  {}", addr, hex.join("")))
+        };
+
+        let indent = match syntax {
+            Syntax::Cowgod => "    ",
+            Syntax::Octo => "  ",
+        };
+        let line = format!("{}{}", indent, text);
+        out.push_str(&format!(
+            "{:width$}{} {}\n",
+            line,
+            comment_start(syntax),
+            comment,
+            width = COMMENT_COLUMN.max(line.len() + 1)
+        ));
+    }
+    out
+}
+
+// Start of a comment, after the instruction or data
+fn comment_start(syntax: Syntax) -> &'static str {
+    match syntax {
+        Syntax::Cowgod => ";",
+        Syntax::Octo => "#",
+    }
+}
+
+// Follow every path through the code from 0x200
+fn trace(rom: &[u8]) -> Trace {
+    let mut trace = Trace {
+        instructions: BTreeMap::new(),
+        code_targets: BTreeSet::new(),
+        data_targets: BTreeSet::new(),
+    };
+    let end = START + rom.len();
+    let word = |addr: usize| {
+        if addr >= START && addr + 1 < end {
+            let offset = addr - START;
+            Some((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+        } else {
+            None
+        }
+    };
+
+    let mut pending = vec![START];
+    while let Some(addr) = pending.pop() {
+        if trace.instructions.contains_key(&addr) {
+            continue;
+        }
+        let instruction = match word(addr).map(decode) {
+            Some(Ok(instruction)) => instruction,
+            _ => continue,
+        };
+        let long = word(addr + 2);
+        // F000 nnnn is only an instruction if its operand is in the ROM
+        if instruction == Instruction::LoadILong && long.is_none() {
+            continue;
+        }
+
+        let len = instruction.size();
+        trace.instructions.insert(addr, len);
+        let next = addr + len;
+
+        match instruction {
+            // Bnnn's target depends on V0, but usually starts a table of
+            // jumps at nnn
+            Instruction::Jump { nnn } | Instruction::JumpV0 { nnn } => {
+                trace.code_targets.insert(nnn as usize);
+                pending.push(nnn as usize);
+            }
+            Instruction::Call { nnn } => {
+                trace.code_targets.insert(nnn as usize);
+                pending.push(nnn as usize);
+                pending.push(next);
+            }
+            _ if instruction.is_skip() => {
+                let skipped = match word(next).map(decode) {
+                    Some(Ok(skipped)) => skipped.size(),
+                    _ => 2,
+                };
+                pending.push(next);
+                pending.push(next + skipped);
+            }
+            Instruction::LoadI { nnn } => {
+                trace.data_targets.insert(nnn as usize);
+                pending.push(next);
+            }
+            Instruction::Ret | Instruction::Exit => (),
+            _ => {
+                if let (Instruction::LoadILong, Some(long)) =
+                    (instruction, long)
+                {
+                    trace.data_targets.insert(long as usize);
+                }
+                pending.push(next);
+            }
+        }
+    }
+    trace
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 200: clear, 202: I = 20A, 204: call 208, 206: jump 206,
+    // 208: return, 20A: sprite data
+    const ROM: [u8; 12] = [
+        0x00, 0xE0, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x06, 0x00, 0xEE, 0xF0, 0x90,
+    ];
+
+    #[test]
+    fn test_cowgod() {
+        assert_eq!(
+            disassemble(&ROM, Syntax::Cowgod),
+            "    CLS                     ; 200  00E0\n\
+             \x20   LD I, D20A              ; 202  A20A\n\
+             \x20   CALL L208               ; 204  2208\n\
+             L206:\n\
+             \x20   JP L206                 ; 206  1206\n\
+             L208:\n\
+             \x20   RET                     ; 208  00EE\n\
+             D20A:\n\
+             \x20   DB #F0                  ; 20A  ####....\n\
+             \x20   DB #90                  ; 20B  #..#....\n"
+        );
+    }
+
+    #[test]
+    fn test_octo() {
+        let octo = disassemble(&ROM, Syntax::Octo);
+        let lines: Vec<&str> = octo.lines().map(|l| l.trim_end()).collect();
+
+        assert_eq!(lines[0], ": main");
+        assert!(lines[1].starts_with("  clear "));
+        assert!(lines[2].starts_with("  i := D20A "));
+        assert!(lines[3].starts_with("  L208 "));
+        assert!(lines[5].starts_with("  jump L206 "));
+        assert_eq!(lines[9], "  0xF0                      # 20A  ####....");
+    }
+
+    #[test]
+    fn test_trace() {
+        // 200: skip if v0 == 1, 202: F000 nnnn (skipped as a whole),
+        // 206: jump to an address outside the ROM, 208: unreachable
+        let rom = [0x30, 0x01, 0xF0, 0x00, 0x02, 0x08, 0x13, 0x00, 0x60, 0x01];
+        let found = trace(&rom);
+
+        assert_eq!(
+            found.instructions.keys().cloned().collect::<Vec<usize>>(),
+            [0x200, 0x202, 0x206]
+        );
+        assert_eq!(found.instructions[&0x202], 4);
+        assert!(found.data_targets.contains(&0x208));
+
+        let cowgod = disassemble(&rom, Syntax::Cowgod);
+        assert!(cowgod.contains("LD I, LONG D208"));
+        assert!(cowgod.contains("JP #300"));
+        assert!(cowgod.contains("D208:\n    DB #60"));
+
+        // Unknown opcodes are data
+        assert!(trace(&[0x00, 0x00]).instructions.is_empty());
+        assert!(disassemble(&[0xFF], Syntax::Octo).contains("0xFF"));
+    }
+}
@@ -1,68 +1,463 @@
-use crate::chip8::{Chip8, DISPLAY_H, DISPLAY_W};
-use minifb::{Key, Scale, Window, WindowOptions};
+use crate::audio::{AudioSink, Beep, DeviceSink, NullSink, WavSink};
+use crate::config::{self, Bindings};
+use crate::palette::{self, Palette};
+use chip8::debugger::{self, Debugger};
+use chip8::disasm::Syntax;
+use chip8::movie::Movie;
+use chip8::octo;
+use chip8::rewind::Rewind;
+use chip8::rng::Rng;
+use chip8::savestate;
+use chip8::trace::{self, Tracer};
+use chip8::{Chip8, Platform, Quirks, StepOutcome, HIRES_DISPLAY_W};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use std::fs;
+use std::fs::File;
 use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn run_emulator(fname: &str) -> Result<(), io::Error> {
-    let mut chip8 = Chip8::load_rom(&fname)?;
+const TITLE: &str = "Baby's First (CHIP-8) Emulator";
 
-    let mut buf = vec![0u32; DISPLAY_W * DISPLAY_H];
+// Timers and the display run at 60Hz
+const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
 
-    let mut window = Window::new(
-        "Baby's First (CHIP-8) Emulator (ESC to exit)",
-        DISPLAY_W,
-        DISPLAY_H,
-        WindowOptions {
-            scale: Scale::X16,
-            ..WindowOptions::default()
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 15;
+
+pub const DEFAULT_REWIND_SECONDS: usize = 10;
+pub const DEFAULT_REWIND_MEGABYTES: usize = 16;
+
+// F1 to F9 load save state slots 1 to 9. With Shift held, they save them
+const SLOT_KEYS: [Key; 9] = [
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+];
+
+pub struct Options {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    pub beep: Beep,
+    pub muted: bool,
+    pub wav_file: Option<String>, // Write sound here instead of playing it
+    pub rewind_frames: usize,     // Frames of history kept for rewinding
+    pub rewind_bytes: usize,      // Memory limit for the rewind history
+    pub rng: Option<Rng>,         // Random number generator, if seeded
+    pub record: Option<String>,   // Record the keypad to this movie file
+    pub play: Option<Movie>,      // Replay the keypad from this movie
+    pub debug: bool,              // Start paused with a debugger prompt
+    pub trace: Option<String>,    // Log executed instructions to this file
+    pub trace_filter: trace::Filter,
+    pub trace_syntax: Option<Syntax>, // Disassembly in the trace, if any
+    pub bindings: Bindings,           // Host keys for the keypad and hotkeys
+    pub palette: Palette,             // The first of the colour palettes
+}
+
+// Read a ROM, compiling it first if it is Octo source
+pub fn read_rom(fname: &str) -> Result<Vec<u8>, io::Error> {
+    if Path::new(fname).extension() == Some("8o".as_ref()) {
+        octo::compile_file(fname).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })
+    } else {
+        fs::read(fname)
+    }
+}
+
+// Load the ROM and set up the interpreter as the options describe. If a
+// movie is to be recorded, it is started here, before the first frame
+pub fn start(
+    fname: &str,
+    options: &Options,
+) -> Result<(Chip8, Option<Movie>), io::Error> {
+    // Pausing and stepping would desynchronise a movie from its frames,
+    // and the debugger runs frames itself, without the tracer
+    let invalid = |message: &str| {
+        io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+    };
+    if options.debug && (options.record.is_some() || options.play.is_some()) {
+        return Err(invalid("--debug can't be used with --record or --play"));
+    }
+    if options.debug && options.trace.is_some() {
+        return Err(invalid("--debug can't be used with --trace"));
+    }
+
+    let rom = read_rom(fname)?;
+    let mut chip8 = Chip8::from_bytes(&rom, options.platform, options.quirks)?;
+    if let Some(ref rng) = options.rng {
+        chip8.set_rng(rng.clone());
+    }
+
+    let recording = options.record.as_ref().map(|_| {
+        Movie::new(
+            &rom,
+            &chip8,
+            options.platform,
+            options.instructions_per_frame,
+        )
+    });
+    Ok((chip8, recording))
+}
+
+// Open the trace file, if one was asked for
+pub fn open_trace(
+    options: &Options,
+) -> Result<Option<Tracer<BufWriter<File>>>, io::Error> {
+    match options.trace {
+        Some(ref fname) => {
+            let out = BufWriter::new(File::create(fname)?);
+            let tracer =
+                Tracer::new(out, options.trace_filter, options.trace_syntax)?;
+            Ok(Some(tracer))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn run_emulator(fname: &str, options: &Options) -> Result<(), io::Error> {
+    let (mut chip8, mut recording) = start(fname, options)?;
+    let bindings = &options.bindings;
+    let title = format!(
+        "{} ({} to exit, {} to mute)",
+        TITLE,
+        config::key_name(bindings.quit),
+        config::key_name(bindings.mute)
+    );
+    let title = title.as_str();
+
+    // Resetting goes back to the state the ROM started in
+    let initial_state = chip8.save_state();
+    let mut playing = options.play.as_ref();
+    let mut frame = 0;
+    let mut tracer = open_trace(options)?;
+
+    let mut audio: Box<dyn AudioSink> = match options.wav_file {
+        Some(ref wav_file) => {
+            Box::new(WavSink::create(wav_file, options.beep)?)
+        }
+        None => match DeviceSink::open(options.beep) {
+            Some(sink) => Box::new(sink),
+            None => {
+                eprintln!("No audio output device found. Sound is disabled");
+                Box::new(NullSink)
+            }
         },
-    )
-    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    };
+    let mut muted = options.muted;
+
+    // The palette hotkey cycles through the presets, after the palette that
+    // was asked for
+    let palettes = palette::cycle(options.palette);
+    let mut palette = 0;
+
+    let mut rewind = Rewind::new(options.rewind_frames, options.rewind_bytes);
+    rewind.push(&chip8);
+
+    // The window has the display's resolution, 64x32 or 128x64, and is
+    // opened again when the ROM switches between them
+    let mut buf = Vec::new();
+    let mut size = (chip8.display_width(), chip8.display_height());
+    let mut window = open_window(title, size)?;
+
+    // Set once the ROM hits an error. The window stays open showing the
+    // last frame so the diagnostic can be read
+    let mut halted = false;
+
+    // The debugger prompt reads commands from the terminal on its own
+    // thread, so the window keeps updating while it waits for input
+    let mut debugger = if options.debug {
+        println!("{}", debugger::registers(&chip8));
+        println!("Paused. Type 'help' for a list of commands");
+        prompt();
+        Some((Debugger::new(true), read_commands()))
+    } else {
+        None
+    };
+    let mut was_paused = false;
+
+    // Paused with the pause hotkey, when there is no debugger to do it
+    let mut user_paused = false;
+
+    // The save and load hotkeys use the last slot that was used
+    let mut last_slot = 1;
+    let mut was_waiting = false;
+
+    let mut next_frame = Instant::now() + FRAME_DURATION;
 
     while window.is_open() {
-        chip8.execute_opcode();
+        // Movies only replay correctly if every frame runs in order, so
+        // rewinding and loading states are disabled while one is active
+        let movie_active = recording.is_some() || playing.is_some();
+
+        let paused = debugger.as_ref().map_or(user_paused, |d| d.0.paused());
+
+        // Holding the rewind key steps back one frame at a time, in real
+        // time
+        let rewinding = !movie_active && window.is_key_down(bindings.rewind);
+        if rewinding {
+            if let Some(state) = rewind.pop() {
+                chip8 = state;
+                if halted {
+                    halted = false;
+                    window.set_title(title);
+                }
+            }
+        } else if !halted && !paused {
+            if let Some(movie) = playing {
+                if !movie.play_frame(frame, &mut chip8) {
+                    eprintln!("Movie finished after {} frames", frame);
+                    window.set_title(&format!("{} - Movie finished", title));
+                    playing = None;
+                }
+            }
+            if let Some(ref mut movie) = recording {
+                movie.record_frame(&chip8);
+            }
+            frame += 1;
 
-        for (i, b) in buf.iter_mut().enumerate() {
-            *b = if chip8.display()[i] { 0xFFFFFF } else { 0 };
+            let ipf = options.instructions_per_frame;
+            // `start` rejects --debug with --trace, so at most one is set
+            let result = match (&mut debugger, &mut tracer) {
+                (Some((ref mut debugger, _)), _) => {
+                    debugger.run_frame(&mut chip8, ipf)
+                }
+                (None, Some(ref mut tracer)) => {
+                    tracer.run_frame(&mut chip8, ipf)?
+                }
+                (None, None) => chip8.run_frame(ipf),
+            };
+            match result {
+                Ok(StepOutcome::Exit) => break,
+                Ok(_) => rewind.push(&chip8),
+                Err(e) => {
+                    eprintln!("Execution halted: {}", e);
+                    window.set_title(&format!("{} - Halted: {}", title, e));
+                    halted = true;
+                }
+            }
         }
 
-        if let Some(keys) = window.get_keys() {
-            match keys.first() {
-                Some(Key::Escape) => break,
-                Some(key) => {
-                    if let Some(chip8_key) = to_chip8_key(*key) {
-                        chip8.set_key(chip8_key);
+        if window.is_key_pressed(bindings.pause, KeyRepeat::No) {
+            match debugger {
+                Some((ref mut debugger, _)) if debugger.paused() => {
+                    debugger.resume()
+                }
+                Some((ref mut debugger, _)) => {
+                    debugger.pause("Paused".to_string())
+                }
+                None => user_paused = !user_paused,
+            }
+        }
+
+        if let Some((ref mut debugger, ref commands)) = debugger {
+            if let Some(reason) = debugger.take_stop_reason() {
+                println!("\n{}\n{}", reason, debugger::registers(&chip8));
+                prompt();
+            }
+            while let Ok(line) = commands.try_recv() {
+                match debugger.command(&mut chip8, &line) {
+                    Ok(output) => println!("{}", output),
+                    Err(e) => println!("{}", e),
+                }
+                prompt();
+            }
+        }
+
+        let paused = debugger.as_ref().map_or(user_paused, |d| d.0.paused());
+        if paused != was_paused {
+            was_paused = paused;
+            if was_paused {
+                window.set_title(&format!("{} - Paused", title));
+            } else {
+                window.set_title(title);
+            }
+        }
+
+        // Fx0A blocks until a key is pressed and released. Say so in the
+        // title bar, as the display alone often doesn't
+        if chip8.waiting_for_key() != was_waiting && !halted {
+            was_waiting = chip8.waiting_for_key();
+            if was_waiting {
+                window.set_title(&format!("{} - Waiting for a key", title));
+            } else {
+                window.set_title(title);
+            }
+        }
+
+        if window.is_key_pressed(bindings.palette, KeyRepeat::No) {
+            palette = (palette + 1) % palettes.len();
+            let status =
+                format!("Palette: {}", palette::name(palettes[palette]));
+            window.set_title(&format!("{} - {}", title, status));
+        }
+
+        let frame_size = (chip8.display_width(), chip8.display_height());
+        buf.clear();
+        buf.extend(
+            chip8
+                .display()
+                .iter()
+                .map(|&p| palettes[palette][p as usize]),
+        );
+
+        if window.is_key_pressed(bindings.mute, KeyRepeat::No) {
+            muted = !muted;
+        }
+        audio.frame(
+            chip8.sound_timer() > 0
+                && !muted
+                && !halted
+                && !rewinding
+                && !was_paused,
+        )?;
+
+        // Save or load a slot with the slot keys, or the last one used with
+        // the save and load hotkeys
+        let shift = window.is_key_down(Key::LeftShift)
+            || window.is_key_down(Key::RightShift);
+        let mut slot_actions: Vec<(u8, bool)> = SLOT_KEYS
+            .iter()
+            .enumerate()
+            .filter(|&(_, &key)| window.is_key_pressed(key, KeyRepeat::No))
+            .map(|(n, _)| (n as u8 + 1, shift))
+            .collect();
+        if window.is_key_pressed(bindings.save, KeyRepeat::No) {
+            slot_actions.push((last_slot, true));
+        }
+        if window.is_key_pressed(bindings.load, KeyRepeat::No) {
+            slot_actions.push((last_slot, false));
+        }
+
+        for (slot, save) in slot_actions {
+            last_slot = slot;
+            let path = savestate::slot_path(fname, slot);
+            let status = if save {
+                match fs::write(&path, chip8.save_state()) {
+                    Ok(()) => format!("Saved state {}", slot),
+                    Err(e) => format!("Failed to save state {}: {}", slot, e),
+                }
+            } else if movie_active {
+                "Save states can't be loaded during a movie".to_string()
+            } else {
+                match fs::read(&path).and_then(|s| Chip8::from_state(&s)) {
+                    Ok(state) => {
+                        chip8 = state;
+                        halted = false;
+                        rewind.push(&chip8);
+                        format!("Loaded state {}", slot)
                     }
+                    Err(e) => format!("Failed to load state {}: {}", slot, e),
                 }
-                None => chip8.reset_keys(),
+            };
+            eprintln!("{}", status);
+            window.set_title(&format!("{} - {}", title, status));
+        }
+
+        if window.is_key_pressed(bindings.reset, KeyRepeat::No) {
+            let status = if movie_active {
+                "The ROM can't be reset during a movie".to_string()
+            } else {
+                chip8 = Chip8::from_state(&initial_state)?;
+                halted = false;
+                rewind.push(&chip8);
+                "Reset".to_string()
+            };
+            eprintln!("{}", status);
+            window.set_title(&format!("{} - {}", title, status));
+        }
+
+        if let Some(keys) = window.get_keys() {
+            if keys.contains(&bindings.quit) {
+                break;
+            }
+            // The keypad is rebuilt from every key held this frame, unless
+            // a movie is driving it
+            if playing.is_none() {
+                chip8.set_keypad(bindings.keypad_state(&keys));
             }
         }
 
+        // The title is set again for the new window on the next frame
+        if frame_size != size {
+            size = frame_size;
+            window = open_window(title, size)?;
+            was_paused = false;
+            was_waiting = false;
+        }
         window
-            .update_with_buffer(&buf, DISPLAY_W, DISPLAY_H)
+            .update_with_buffer(&buf, size.0, size.1)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // Sleep until the next frame is due. If the host has fallen more
+        // than a frame behind, resynchronise instead of racing to catch up
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+            next_frame += FRAME_DURATION;
+        } else if now - next_frame > FRAME_DURATION {
+            next_frame = now + FRAME_DURATION;
+        } else {
+            next_frame += FRAME_DURATION;
+        }
+    }
+
+    audio.finish()?;
+    if let Some(ref mut tracer) = tracer {
+        tracer.flush()?;
+    }
+
+    if let (Some(movie), Some(fname)) = (recording, options.record.as_ref()) {
+        fs::write(fname, movie.to_string())?;
+        eprintln!("Recorded {} frames to {}", movie.frames, fname);
     }
 
     Ok(())
 }
 
-fn to_chip8_key(key: Key) -> Option<u8> {
-    match key {
-        Key::Key1 => Some(0x1),
-        Key::Key2 => Some(0x2),
-        Key::Key3 => Some(0x3),
-        Key::Key4 => Some(0xC),
-        Key::Q => Some(0x4),
-        Key::W => Some(0x5),
-        Key::E => Some(0x6),
-        Key::R => Some(0xD),
-        Key::A => Some(0x7),
-        Key::S => Some(0x8),
-        Key::D => Some(0x9),
-        Key::F => Some(0xE),
-        Key::Z => Some(0xA),
-        Key::X => Some(0x0),
-        Key::C => Some(0xB),
-        Key::V => Some(0xF),
-        _ => None,
-    }
+// Open a window for a display of `size`, scaled to 1024x512 on screen at
+// either resolution
+fn open_window(title: &str, size: (usize, usize)) -> Result<Window, io::Error> {
+    let scale = if size.0 < HIRES_DISPLAY_W {
+        Scale::X16
+    } else {
+        Scale::X8
+    };
+    let options = WindowOptions {
+        scale,
+        ..WindowOptions::default()
+    };
+    Window::new(title, size.0, size.1, options)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+// Read lines from the terminal on a separate thread
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let sent = line.ok().map(|line| sender.send(line).is_ok());
+            if sent != Some(true) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
 }
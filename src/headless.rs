@@ -0,0 +1,675 @@
+use crate::audio::{AudioSink, NullSink, WavSink};
+use crate::emulator;
+use crate::palette::Palette;
+use chip8::disasm::Syntax;
+use chip8::movie::Movie;
+use chip8::trace::{self, Field, Step, Tracer};
+use chip8::{Chip8, Chip8Error, StepOutcome};
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::iter::Peekable;
+
+pub const DEFAULT_FRAMES: u32 = 600;
+
+// Characters used for each combination of bitplanes in ASCII dumps
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '*'];
+
+pub struct Options {
+    pub frames: u32,               // Stop after this many 60Hz frames
+    pub until_pc: Option<u16>,     // Stop when the PC reaches this address
+    pub keys: Vec<KeyEvent>,       // Scripted key presses and releases
+    pub display: Option<String>,   // Display dump file, stdout if None
+    pub registers: Option<String>, // Register dump file, stdout if None
+}
+
+// Key change applied at the start of the given frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub frame: u32,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// Why a headless run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Frames,
+    Exit,
+    UntilPc,
+    Error(Chip8Error),
+}
+
+// Parse a key script. Each line holds a frame number, a hexadecimal key
+// and either "down" or "up", e.g. "30 5 down". Blank lines and anything
+// after a '#' are ignored
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyEvent>, io::Error> {
+    let mut events = Vec::new();
+
+    for (n, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Key script line {}: expected 'FRAME KEY down|up', \
+                     got '{}'",
+                    n + 1,
+                    line
+                ),
+            )
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(invalid());
+        }
+
+        let frame = fields[0].parse().map_err(|_| invalid())?;
+        let key = u8::from_str_radix(fields[1], 16)
+            .ok()
+            .filter(|&k| k < 16)
+            .ok_or_else(invalid)?;
+        let pressed = match fields[2] {
+            "down" => true,
+            "up" => false,
+            _ => return Err(invalid()),
+        };
+
+        events.push(KeyEvent {
+            frame,
+            key,
+            pressed,
+        });
+    }
+
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+pub fn run_headless(
+    fname: &str,
+    options: &emulator::Options,
+    headless: &Options,
+) -> Result<(), io::Error> {
+    let (mut chip8, mut recording) = emulator::start(fname, options)?;
+
+    let mut audio: Box<dyn AudioSink> = match options.wav_file {
+        Some(ref wav_file) => {
+            Box::new(WavSink::create(wav_file, options.beep)?)
+        }
+        None => Box::new(NullSink),
+    };
+
+    let mut tracer = emulator::open_trace(options)?;
+    let (frames, reason) = run(
+        &mut chip8,
+        options.instructions_per_frame,
+        headless,
+        &mut *audio,
+        recording.as_mut(),
+        tracer.as_mut(),
+    )?;
+    audio.finish()?;
+    if let Some(ref mut tracer) = tracer {
+        tracer.flush()?;
+    }
+
+    if let (Some(movie), Some(fname)) = (recording, options.record.as_ref()) {
+        fs::write(fname, movie.to_string())?;
+    }
+
+    match headless.display {
+        Some(ref f) if f.ends_with(".png") => {
+            write_png(&chip8, options.palette, f)?
+        }
+        Some(ref f) => fs::write(f, display_ascii(&chip8))?,
+        None => print!("{}", display_ascii(&chip8)),
+    }
+
+    let json = registers_json(&chip8, frames, reason);
+    match headless.registers {
+        Some(ref f) => fs::write(f, json)?,
+        None => print!("{}", json),
+    }
+
+    match reason {
+        StopReason::Error(e) => {
+            Err(io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Run until a stop condition is met. Returns the number of frames that
+// were completed and why the run stopped
+fn run(
+    chip8: &mut Chip8,
+    instructions: u32,
+    headless: &Options,
+    audio: &mut dyn AudioSink,
+    mut recording: Option<&mut Movie>,
+    mut tracer: Option<&mut Tracer<BufWriter<File>>>,
+) -> Result<(u32, StopReason), io::Error> {
+    let mut keys = headless.keys.iter().peekable();
+
+    for frame in 0..headless.frames {
+        press_keys(chip8, &mut keys, frame);
+        if let Some(ref mut movie) = recording {
+            movie.record_frame(chip8);
+        }
+
+        let mut stop = None;
+        chip8.run_frame_with::<io::Error, _>(instructions, |chip8| {
+            if headless.until_pc == Some(chip8.pc()) {
+                stop = Some(StopReason::UntilPc);
+                return Ok(None);
+            }
+            let result = match tracer {
+                Some(ref mut tracer) => tracer.execute_opcode(chip8)?,
+                None => chip8.execute_opcode(),
+            };
+            match result {
+                Ok(StepOutcome::Exit) => stop = Some(StopReason::Exit),
+                Ok(outcome) => return Ok(Some(outcome)),
+                Err(e) => stop = Some(StopReason::Error(e)),
+            }
+            Ok(None)
+        })?;
+        if let Some(reason) = stop {
+            return Ok((frame, reason));
+        }
+        audio.frame(chip8.sound_timer() > 0)?;
+    }
+
+    Ok((headless.frames, StopReason::Frames))
+}
+
+// Press and release the keys of the events up to `frame`
+fn press_keys<'a, I>(chip8: &mut Chip8, keys: &mut Peekable<I>, frame: u32)
+where
+    I: Iterator<Item = &'a KeyEvent>,
+{
+    while let Some(event) = keys.peek() {
+        if event.frame > frame {
+            break;
+        }
+        if event.pressed {
+            chip8.set_key(event.key);
+        } else {
+            chip8.release_key(event.key);
+        }
+        keys.next();
+    }
+}
+
+// Key presses and releases that reproduce a movie's keypad states
+pub fn movie_key_events(movie: &Movie) -> Vec<KeyEvent> {
+    let mut events = Vec::new();
+    let mut last = 0;
+
+    for &(frame, keys) in &movie.inputs {
+        for key in 0..16 {
+            let pressed = keys & (1 << key) != 0;
+            if pressed != (last & (1 << key) != 0) {
+                events.push(KeyEvent {
+                    frame,
+                    key,
+                    pressed,
+                });
+            }
+        }
+        last = keys;
+    }
+    events
+}
+
+// Run a ROM alongside a reference trace, comparing the state after each
+// instruction with the next step of the reference, until they differ or
+// the reference ends. `context` of our own trace lines are shown before
+// the first difference. Gives up after `frames` frames, or once the ROM
+// waits for a key that the script will never press. Returns a report and
+// whether the traces matched
+pub fn trace_diff(
+    chip8: &mut Chip8,
+    instructions: u32,
+    frames: u32,
+    keys: &[KeyEvent],
+    reference: &[Step],
+    context: usize,
+) -> Result<(String, bool), io::Error> {
+    let mut tracer = Tracer::new(
+        Vec::new(),
+        trace::Filter::default(),
+        Some(Syntax::Cowgod),
+    )?;
+    tracer.get_mut().clear();
+    let mut history = VecDeque::new();
+    let mut keys = keys.iter().peekable();
+    let mut steps = reference.iter().peekable();
+
+    // What we ran before stopping, followed by `message`
+    let report = |history: &VecDeque<String>, message: String| {
+        let mut out = String::new();
+        if !history.is_empty() {
+            out.push_str("Last instructions:\n");
+            for line in history {
+                out.push_str(&format!("  {}\n", line));
+            }
+        }
+        out.push_str(&message);
+        out
+    };
+
+    let mut done = None;
+    for frame in 0..frames {
+        press_keys(chip8, &mut keys, frame);
+
+        chip8.run_frame_with::<io::Error, _>(instructions, |chip8| {
+            let step = match steps.peek() {
+                Some(&step) => step,
+                None => {
+                    let matched = format!(
+                        "Traces match for {} instructions\n",
+                        tracer.cycle()
+                    );
+                    done = Some((matched, true));
+                    return Ok(None);
+                }
+            };
+
+            let pc = chip8.pc();
+            let opcode = chip8.opcode_at(pc);
+            let before = tracer.cycle();
+            let outcome = match tracer.execute_opcode(chip8)? {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    let message = format!(
+                        "Execution stopped at cycle {}, before reference \
+                         line {}: {}\n",
+                        before + 1,
+                        step.line,
+                        e
+                    );
+                    done = Some((report(&history, message), false));
+                    return Ok(None);
+                }
+            };
+
+            let cycle = tracer.cycle();
+            if cycle > before {
+                let line = String::from_utf8_lossy(tracer.get_mut());
+                history.push_back(line.trim_end().to_string());
+                tracer.get_mut().clear();
+                if history.len() > context.max(1) {
+                    history.pop_front();
+                }
+
+                // The reference may skip instructions if it has a cycle
+                // column
+                let expected_cycle = step
+                    .values
+                    .iter()
+                    .find(|&&(field, _)| field == Field::Cycle)
+                    .map(|&(_, value)| value);
+                match expected_cycle {
+                    Some(expected) if expected < cycle => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Trace line {}: cycle {} has already run. \
+                                 Cycles count from 1 and must increase",
+                                step.line, expected
+                            ),
+                        ));
+                    }
+                    Some(expected) if expected > cycle => (),
+                    _ => {
+                        let differences =
+                            trace::compare(step, cycle, pc, opcode, chip8);
+                        if !differences.is_empty() {
+                            let mut message = format!(
+                                "Traces diverge at cycle {}, reference line \
+                                 {}:\n  {}\n",
+                                cycle,
+                                step.line,
+                                step.text.trim()
+                            );
+                            for difference in differences {
+                                message
+                                    .push_str(&format!("  {}\n", difference));
+                            }
+                            done = Some((report(&history, message), false));
+                            return Ok(None);
+                        }
+                        steps.next();
+                    }
+                }
+            }
+
+            if outcome == StepOutcome::Exit {
+                let message = format!(
+                    "The ROM exited after {} instructions, before reference \
+                     line {}\n",
+                    cycle, step.line
+                );
+                done = Some((report(&history, message), false));
+                return Ok(None);
+            }
+            Ok(Some(outcome))
+        })?;
+
+        if let Some(done) = done {
+            return Ok(done);
+        }
+        if chip8.waiting_for_key() && keys.peek().is_none() {
+            let message = format!(
+                "The ROM is waiting for a key with no scripted input left, \
+                 after {} instructions, before reference line {}\n",
+                tracer.cycle(),
+                steps.peek().map_or(0, |step| step.line)
+            );
+            return Ok((report(&history, message), false));
+        }
+    }
+
+    let message = format!(
+        "Stopped after {} frames and {} instructions, before reference line \
+         {}. Use --frames to run for longer\n",
+        frames,
+        tracer.cycle(),
+        steps.peek().map_or(0, |step| step.line)
+    );
+    Ok((report(&history, message), false))
+}
+
+// One line of text per row, one character per pixel
+pub fn display_ascii(chip8: &Chip8) -> String {
+    let w = chip8.display_width();
+    let mut ascii = String::with_capacity((w + 1) * chip8.display_height());
+
+    for row in chip8.display().chunks(w) {
+        ascii.extend(row.iter().map(|&p| ASCII_PIXELS[p as usize]));
+        ascii.push('\n');
+    }
+    ascii
+}
+
+// Write the display as an RGB PNG at its native resolution, in `palette`
+pub fn write_png(
+    chip8: &Chip8,
+    palette: Palette,
+    fname: &str,
+) -> Result<(), io::Error> {
+    let file = BufWriter::new(File::create(fname)?);
+    let mut encoder = png::Encoder::new(
+        file,
+        chip8.display_width() as u32,
+        chip8.display_height() as u32,
+    );
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity(chip8.display().len() * 3);
+    for &p in chip8.display() {
+        let colour = palette[p as usize];
+        data.push((colour >> 16) as u8);
+        data.push((colour >> 8) as u8);
+        data.push(colour as u8);
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+pub fn registers_json(
+    chip8: &Chip8,
+    frames: u32,
+    reason: StopReason,
+) -> String {
+    let list = |values: Vec<String>| values.join(", ");
+    let v = list(chip8.v().iter().map(|r| r.to_string()).collect());
+    let stack = list(chip8.stack().iter().map(|a| a.to_string()).collect());
+    let (reason, error) = match reason {
+        StopReason::Frames => ("frames", "null".to_string()),
+        StopReason::Exit => ("exit", "null".to_string()),
+        StopReason::UntilPc => ("until_pc", "null".to_string()),
+        StopReason::Error(e) => ("error", format!("\"{}\"", e)),
+    };
+
+    let mut json = String::new();
+    json.push_str("{\n");
+    json.push_str(&format!("  \"frames\": {},\n", frames));
+    json.push_str(&format!("  \"stop_reason\": \"{}\",\n", reason));
+    json.push_str(&format!("  \"error\": {},\n", error));
+    json.push_str(&format!("  \"pc\": {},\n", chip8.pc()));
+    json.push_str(&format!("  \"i\": {},\n", chip8.i()));
+    json.push_str(&format!("  \"v\": [{}],\n", v));
+    json.push_str(&format!("  \"stack\": [{}],\n", stack));
+    json.push_str(&format!("  \"delay_timer\": {},\n", chip8.delay_timer()));
+    json.push_str(&format!("  \"sound_timer\": {},\n", chip8.sound_timer()));
+    json.push_str(&format!(
+        "  \"waiting_for_key\": {}\n",
+        chip8.waiting_for_key()
+    ));
+    json.push_str("}\n");
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chip8::trace::Columns;
+    use chip8::{Platform, Quirks};
+
+    fn load_test_rom(rom: &[u8]) -> Chip8 {
+        Chip8::from_bytes(rom, Platform::Chip8, Quirks::default()).unwrap()
+    }
+
+    fn options(frames: u32, until_pc: Option<u16>) -> Options {
+        Options {
+            frames,
+            until_pc,
+            keys: Vec::new(),
+            display: None,
+            registers: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_key_script() {
+        let script = "# Start the game\n\n30 5 down\n10 a down # Early\n\
+                      32 5 up\n";
+        let events = parse_key_script(script).unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            KeyEvent {
+                frame: 10,
+                key: 0xA,
+                pressed: true
+            }
+        );
+        assert_eq!(events[2].frame, 32);
+        assert!(!events[2].pressed);
+
+        assert!(parse_key_script("30 5").is_err());
+        assert!(parse_key_script("30 10 down").is_err());
+        assert!(parse_key_script("x 5 down").is_err());
+        assert!(parse_key_script("30 5 pressed").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        // Draw the "0" glyph at the top left, then loop forever
+        let rom = [0xA0, 0x00, 0xD0, 0x05, 0x12, 0x04];
+        let mut chip8 = load_test_rom(&rom);
+
+        let result =
+            run(&mut chip8, 10, &options(5, None), &mut NullSink, None, None)
+                .unwrap();
+        assert_eq!(result, (5, StopReason::Frames));
+
+        let ascii = display_ascii(&chip8);
+        let rows: Vec<&str> = ascii.lines().collect();
+        assert_eq!(rows.len(), 32);
+        assert_eq!(&rows[0][..8], "####....");
+        assert_eq!(&rows[1][..8], "#..#....");
+
+        let json = registers_json(&chip8, result.0, result.1);
+        assert!(json.contains("\"stop_reason\": \"frames\""));
+        assert!(json.contains("\"pc\": 516"));
+        assert!(json.contains("\"waiting_for_key\": false"));
+    }
+
+    #[test]
+    fn test_movie_key_events() {
+        let rom = [0x12, 0x00];
+        let chip8 = load_test_rom(&rom);
+        let mut movie = Movie::new(&rom, &chip8, Platform::Chip8, 10);
+        movie.inputs = vec![(0, 0), (3, 0x0021), (5, 0x0020), (8, 0)];
+
+        let events = movie_key_events(&movie);
+        let event = |frame, key, pressed| KeyEvent {
+            frame,
+            key,
+            pressed,
+        };
+        assert_eq!(
+            events,
+            [
+                event(3, 0x0, true),
+                event(3, 0x5, true),
+                event(5, 0x0, false),
+                event(8, 0x5, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_stop_conditions() {
+        // Wait for key 5 to be pressed and released, then exit
+        let rom = [0xF0, 0x0A, 0x00, 0xFD];
+        let mut chip8 = load_test_rom(&rom);
+
+        let mut opts = options(100, Some(0x202));
+        opts.keys = parse_key_script("3 5 down\n4 5 up").unwrap();
+        let result =
+            run(&mut chip8, 10, &opts, &mut NullSink, None, None).unwrap();
+        assert_eq!(result, (4, StopReason::UntilPc));
+
+        opts.until_pc = None;
+        let result =
+            run(&mut chip8, 10, &opts, &mut NullSink, None, None).unwrap();
+        assert_eq!(result, (0, StopReason::Exit));
+
+        let rom = [0x00, 0x00];
+        let mut chip8 = load_test_rom(&rom);
+        let result = run(
+            &mut chip8,
+            10,
+            &options(100, None),
+            &mut NullSink,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            result.1,
+            StopReason::Error(Chip8Error::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x0000
+            })
+        );
+    }
+
+    #[test]
+    fn test_trace_diff() {
+        // 200: V0 = 5, 202: wait for a key in V1, 204: V0 += V1, 206: exit
+        let rom = [0x60, 0x05, 0xF1, 0x0A, 0x80, 0x14, 0x00, 0xFD];
+        let keys = [
+            KeyEvent {
+                frame: 1,
+                key: 2,
+                pressed: true,
+            },
+            KeyEvent {
+                frame: 2,
+                key: 2,
+                pressed: false,
+            },
+        ];
+        let columns = Columns::parse("cycle,pc,_,v0,v1").unwrap();
+        let diff = |reference: &str, context| {
+            let steps = trace::parse_reference(reference, &columns).unwrap();
+            let mut chip8 = load_test_rom(&rom);
+            trace_diff(&mut chip8, 10, 60, &keys, &steps, context).unwrap()
+        };
+
+        // Fx0A waits out the first frame, sees the key go down in the
+        // second and completes when it comes up in the third. The cycles
+        // spent waiting aren't counted
+        let (report, matched) = diff("1 200 6005 05 00\n2 202 F10A 05 02", 5);
+        assert!(matched, "{}", report);
+        assert_eq!(report, "Traces match for 2 instructions\n");
+
+        let (report, matched) =
+            diff("1 200 6005 05 00\n2 202 F10A 05 02\n3 204 8014 08 02", 2);
+        assert!(!matched);
+        assert_eq!(
+            report,
+            "Last instructions:\n\
+             \x20 2 0202 F10A 05 02 00 00 00 00 00 00 00 00 00 00 00 00 00 \
+             00 0000 0 00 00 ; LD V1, K\n\
+             \x20 3 0204 8014 07 02 00 00 00 00 00 00 00 00 00 00 00 00 00 \
+             00 0000 0 00 00 ; ADD V0, V1\n\
+             Traces diverge at cycle 3, reference line 3:\n\
+             \x20 3 204 8014 08 02\n\
+             \x20 V0: expected 08, got 07\n"
+        );
+
+        let (report, matched) = diff("1 200 6005 05 00\n7 208 0000 00 00", 0);
+        assert!(!matched);
+        assert!(report.ends_with(
+            "The ROM exited after 4 instructions, before reference line 2\n"
+        ));
+    }
+
+    #[test]
+    fn test_trace_diff_stuck() {
+        // 200: V0 = 5, 202: wait for a key in V1, 204: exit
+        let rom = [0x60, 0x05, 0xF1, 0x0A, 0x00, 0xFD];
+        let columns = Columns::parse("pc,opcode").unwrap();
+        let steps =
+            trace::parse_reference("200 6005\n202 F10A\n", &columns).unwrap();
+
+        let mut chip8 = load_test_rom(&rom);
+        let (report, matched) =
+            trace_diff(&mut chip8, 10, 60, &[], &steps, 0).unwrap();
+        assert!(!matched);
+        assert!(report.ends_with(
+            "The ROM is waiting for a key with no scripted input left, after \
+             1 instructions, before reference line 2\n"
+        ));
+
+        // 200: jump 200, which never ends on its own
+        let mut chip8 = load_test_rom(&[0x12, 0x00]);
+        let reference = "200 1200\n".repeat(100);
+        let steps = trace::parse_reference(&reference, &columns).unwrap();
+        let (report, matched) =
+            trace_diff(&mut chip8, 10, 3, &[], &steps, 0).unwrap();
+        assert!(!matched);
+        assert!(report.ends_with(
+            "Stopped after 3 frames and 30 instructions, before reference \
+             line 31. Use --frames to run for longer\n"
+        ));
+    }
+}
@@ -0,0 +1,481 @@
+use std::error;
+use std::fmt;
+
+/// Mnemonics to disassemble to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Cowgod, // Cowgod's Chip-8 Technical Reference, e.g. "LD V0, #05"
+    Octo,   // Octo assembly language, e.g. "v0 := 0x05"
+}
+
+pub const SYNTAX_NAMES: &[&str] = &["cowgod", "octo"];
+
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None,
+        }
+    }
+
+    /// A number in hexadecimal with at least `digits` digits, e.g. "#0A"
+    /// or "0x0A"
+    pub fn hex(self, n: usize, digits: usize) -> String {
+        match self {
+            Syntax::Cowgod => format!("#{:0width$X}", n, width = digits),
+            Syntax::Octo => format!("0x{:0width$X}", n, width = digits),
+        }
+    }
+}
+
+/// A decoded instruction. `x` and `y` are register numbers, `n` is a
+/// nibble, `kk` a byte and `nnn` a 12-bit address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Cls,                                // 00E0
+    Ret,                                // 00EE
+    ScrollDown { n: u8 },               // 00Cn
+    ScrollUp { n: u8 },                 // 00Dn
+    ScrollRight,                        // 00FB
+    ScrollLeft,                         // 00FC
+    Exit,                               // 00FD
+    LowRes,                             // 00FE
+    HighRes,                            // 00FF
+    Jump { nnn: u16 },                  // 1nnn
+    Call { nnn: u16 },                  // 2nnn
+    SkipEqImm { x: usize, kk: u8 },     // 3xkk
+    SkipNeImm { x: usize, kk: u8 },     // 4xkk
+    SkipEqReg { x: usize, y: usize },   // 5xy0
+    StoreRange { x: usize, y: usize },  // 5xy2
+    LoadRange { x: usize, y: usize },   // 5xy3
+    LoadImm { x: usize, kk: u8 },       // 6xkk
+    AddImm { x: usize, kk: u8 },        // 7xkk
+    LoadReg { x: usize, y: usize },     // 8xy0
+    Or { x: usize, y: usize },          // 8xy1
+    And { x: usize, y: usize },         // 8xy2
+    Xor { x: usize, y: usize },         // 8xy3
+    AddReg { x: usize, y: usize },      // 8xy4
+    Sub { x: usize, y: usize },         // 8xy5
+    ShiftRight { x: usize, y: usize },  // 8xy6
+    SubN { x: usize, y: usize },        // 8xy7
+    ShiftLeft { x: usize, y: usize },   // 8xyE
+    SkipNeReg { x: usize, y: usize },   // 9xy0
+    LoadI { nnn: u16 },                 // Annn
+    JumpV0 { nnn: u16 },                // Bnnn
+    Random { x: usize, kk: u8 },        // Cxkk
+    Draw { x: usize, y: usize, n: u8 }, // Dxyn
+    SkipKey { x: usize },               // Ex9E
+    SkipNotKey { x: usize },            // ExA1
+    LoadILong,                          // F000 nnnn, the operand follows
+    Plane { n: u8 },                    // Fn01
+    Audio,                              // F002
+    GetDelay { x: usize },              // Fx07
+    WaitKey { x: usize },               // Fx0A
+    SetDelay { x: usize },              // Fx15
+    SetSound { x: usize },              // Fx18
+    AddI { x: usize },                  // Fx1E
+    Font { x: usize },                  // Fx29
+    BigFont { x: usize },               // Fx30
+    Bcd { x: usize },                   // Fx33
+    Pitch { x: usize },                 // Fx3A
+    Store { x: usize },                 // Fx55
+    Load { x: usize },                  // Fx65
+    StoreFlags { x: usize },            // Fx75
+    LoadFlags { x: usize },             // Fx85
+}
+
+/// An opcode that isn't any instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown opcode {:04X}", self.opcode)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Decode an opcode. F000 decodes to `LoadILong`, whose operand is the
+/// word that follows it
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    use self::Instruction::*;
+
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let n = (opcode & 0x000F) as u8;
+    let kk = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+
+    let instruction = match opcode & 0xF000 {
+        0x0000 => match opcode & 0xFFF0 {
+            0x00C0 => ScrollDown { n },
+            0x00D0 => ScrollUp { n },
+            _ => match opcode {
+                0x00E0 => Cls,
+                0x00EE => Ret,
+                0x00FB => ScrollRight,
+                0x00FC => ScrollLeft,
+                0x00FD => Exit,
+                0x00FE => LowRes,
+                0x00FF => HighRes,
+                _ => return Err(DecodeError { opcode }),
+            },
+        },
+        0x1000 => Jump { nnn },
+        0x2000 => Call { nnn },
+        0x3000 => SkipEqImm { x, kk },
+        0x4000 => SkipNeImm { x, kk },
+        0x5000 => match n {
+            0x0 => SkipEqReg { x, y },
+            0x2 => StoreRange { x, y },
+            0x3 => LoadRange { x, y },
+            _ => return Err(DecodeError { opcode }),
+        },
+        0x6000 => LoadImm { x, kk },
+        0x7000 => AddImm { x, kk },
+        0x8000 => match n {
+            0x0 => LoadReg { x, y },
+            0x1 => Or { x, y },
+            0x2 => And { x, y },
+            0x3 => Xor { x, y },
+            0x4 => AddReg { x, y },
+            0x5 => Sub { x, y },
+            0x6 => ShiftRight { x, y },
+            0x7 => SubN { x, y },
+            0xE => ShiftLeft { x, y },
+            _ => return Err(DecodeError { opcode }),
+        },
+        0x9000 if n == 0 => SkipNeReg { x, y },
+        0xA000 => LoadI { nnn },
+        0xB000 => JumpV0 { nnn },
+        0xC000 => Random { x, kk },
+        0xD000 => Draw { x, y, n },
+        0xE000 => match kk {
+            0x9E => SkipKey { x },
+            0xA1 => SkipNotKey { x },
+            _ => return Err(DecodeError { opcode }),
+        },
+        0xF000 => match kk {
+            0x00 if x == 0 => LoadILong,
+            0x01 => Plane { n: x as u8 },
+            0x02 if x == 0 => Audio,
+            0x07 => GetDelay { x },
+            0x0A => WaitKey { x },
+            0x15 => SetDelay { x },
+            0x18 => SetSound { x },
+            0x1E => AddI { x },
+            0x29 => Font { x },
+            0x30 => BigFont { x },
+            0x33 => Bcd { x },
+            0x3A => Pitch { x },
+            0x55 => Store { x },
+            0x65 => Load { x },
+            0x75 => StoreFlags { x },
+            0x85 => LoadFlags { x },
+            _ => return Err(DecodeError { opcode }),
+        },
+        _ => return Err(DecodeError { opcode }),
+    };
+    Ok(instruction)
+}
+
+impl Instruction {
+    /// The opcode. Fields are truncated to the bits the opcode has for them
+    pub fn encode(self) -> u16 {
+        use self::Instruction::*;
+
+        let xy = |x: usize, y: usize| ((x & 0xF) << 8 | (y & 0xF) << 4) as u16;
+        let xkk = |x: usize, kk: u8| ((x & 0xF) << 8) as u16 | kk as u16;
+        let fx = |x: usize, low: u16| 0xF000 | ((x & 0xF) << 8) as u16 | low;
+
+        match self {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollDown { n } => 0x00C0 | (n & 0xF) as u16,
+            ScrollUp { n } => 0x00D0 | (n & 0xF) as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            Jump { nnn } => 0x1000 | nnn & 0x0FFF,
+            Call { nnn } => 0x2000 | nnn & 0x0FFF,
+            SkipEqImm { x, kk } => 0x3000 | xkk(x, kk),
+            SkipNeImm { x, kk } => 0x4000 | xkk(x, kk),
+            SkipEqReg { x, y } => 0x5000 | xy(x, y),
+            StoreRange { x, y } => 0x5002 | xy(x, y),
+            LoadRange { x, y } => 0x5003 | xy(x, y),
+            LoadImm { x, kk } => 0x6000 | xkk(x, kk),
+            AddImm { x, kk } => 0x7000 | xkk(x, kk),
+            LoadReg { x, y } => 0x8000 | xy(x, y),
+            Or { x, y } => 0x8001 | xy(x, y),
+            And { x, y } => 0x8002 | xy(x, y),
+            Xor { x, y } => 0x8003 | xy(x, y),
+            AddReg { x, y } => 0x8004 | xy(x, y),
+            Sub { x, y } => 0x8005 | xy(x, y),
+            ShiftRight { x, y } => 0x8006 | xy(x, y),
+            SubN { x, y } => 0x8007 | xy(x, y),
+            ShiftLeft { x, y } => 0x800E | xy(x, y),
+            SkipNeReg { x, y } => 0x9000 | xy(x, y),
+            LoadI { nnn } => 0xA000 | nnn & 0x0FFF,
+            JumpV0 { nnn } => 0xB000 | nnn & 0x0FFF,
+            Random { x, kk } => 0xC000 | xkk(x, kk),
+            Draw { x, y, n } => 0xD000 | xy(x, y) | (n & 0xF) as u16,
+            SkipKey { x } => 0xE09E | xy(x, 0),
+            SkipNotKey { x } => 0xE0A1 | xy(x, 0),
+            LoadILong => 0xF000,
+            Plane { n } => fx(n as usize, 0x01),
+            Audio => 0xF002,
+            GetDelay { x } => fx(x, 0x07),
+            WaitKey { x } => fx(x, 0x0A),
+            SetDelay { x } => fx(x, 0x15),
+            SetSound { x } => fx(x, 0x18),
+            AddI { x } => fx(x, 0x1E),
+            Font { x } => fx(x, 0x29),
+            BigFont { x } => fx(x, 0x30),
+            Bcd { x } => fx(x, 0x33),
+            Pitch { x } => fx(x, 0x3A),
+            Store { x } => fx(x, 0x55),
+            Load { x } => fx(x, 0x65),
+            StoreFlags { x } => fx(x, 0x75),
+            LoadFlags { x } => fx(x, 0x85),
+        }
+    }
+
+    /// Length in bytes, including the operand of F000 nnnn
+    pub fn size(self) -> usize {
+        if self == Instruction::LoadILong {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Whether the instruction may skip the one after it
+    pub fn is_skip(self) -> bool {
+        use self::Instruction::*;
+
+        match self {
+            SkipEqImm { .. }
+            | SkipNeImm { .. }
+            | SkipEqReg { .. }
+            | SkipNeReg { .. }
+            | SkipKey { .. }
+            | SkipNotKey { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Format the instruction with addresses as numbers. `long` is the
+    /// word after the opcode, the operand of F000 nnnn, which is left out
+    /// if it isn't known
+    pub fn format(self, long: Option<u16>, syntax: Syntax) -> String {
+        let name = |addr: usize, digits: usize| syntax.hex(addr, digits);
+        self.format_with_labels(long, syntax, &name)
+    }
+
+    /// Format the instruction like `format`, with `name` giving the label
+    /// or number to show for an address with the given number of digits
+    pub fn format_with_labels(
+        self,
+        long: Option<u16>,
+        syntax: Syntax,
+        name: &dyn Fn(usize, usize) -> String,
+    ) -> String {
+        use self::Instruction::*;
+
+        let kk = |kk: u8| syntax.hex(kk as usize, 2);
+        let nnn = |nnn: u16| name(nnn as usize, 3);
+        let long = match long {
+            Some(long) => format!(" {}", name(long as usize, 4)),
+            None => String::new(),
+        };
+
+        match syntax {
+            Syntax::Cowgod => match self {
+                Cls => "CLS".to_string(),
+                Ret => "RET".to_string(),
+                ScrollDown { n } => format!("SCD {}", n),
+                ScrollUp { n } => format!("SCU {}", n),
+                ScrollRight => "SCR".to_string(),
+                ScrollLeft => "SCL".to_string(),
+                Exit => "EXIT".to_string(),
+                LowRes => "LOW".to_string(),
+                HighRes => "HIGH".to_string(),
+                Jump { nnn: a } => format!("JP {}", nnn(a)),
+                Call { nnn: a } => format!("CALL {}", nnn(a)),
+                SkipEqImm { x, kk: b } => format!("SE V{:X}, {}", x, kk(b)),
+                SkipNeImm { x, kk: b } => format!("SNE V{:X}, {}", x, kk(b)),
+                SkipEqReg { x, y } => format!("SE V{:X}, V{:X}", x, y),
+                StoreRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+                LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+                LoadImm { x, kk: b } => format!("LD V{:X}, {}", x, kk(b)),
+                AddImm { x, kk: b } => format!("ADD V{:X}, {}", x, kk(b)),
+                LoadReg { x, y } => format!("LD V{:X}, V{:X}", x, y),
+                Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+                And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+                Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+                AddReg { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+                Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+                ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+                SubN { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+                ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+                SkipNeReg { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+                LoadI { nnn: a } => format!("LD I, {}", nnn(a)),
+                JumpV0 { nnn: a } => format!("JP V0, {}", nnn(a)),
+                Random { x, kk: b } => format!("RND V{:X}, {}", x, kk(b)),
+                Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+                SkipKey { x } => format!("SKP V{:X}", x),
+                SkipNotKey { x } => format!("SKNP V{:X}", x),
+                LoadILong => format!("LD I, LONG{}", long),
+                Plane { n } => format!("PLANE {}", n),
+                Audio => "AUDIO".to_string(),
+                GetDelay { x } => format!("LD V{:X}, DT", x),
+                WaitKey { x } => format!("LD V{:X}, K", x),
+                SetDelay { x } => format!("LD DT, V{:X}", x),
+                SetSound { x } => format!("LD ST, V{:X}", x),
+                AddI { x } => format!("ADD I, V{:X}", x),
+                Font { x } => format!("LD F, V{:X}", x),
+                BigFont { x } => format!("LD HF, V{:X}", x),
+                Bcd { x } => format!("LD B, V{:X}", x),
+                Pitch { x } => format!("PITCH V{:X}", x),
+                Store { x } => format!("LD [I], V{:X}", x),
+                Load { x } => format!("LD V{:X}, [I]", x),
+                StoreFlags { x } => format!("LD R, V{:X}", x),
+                LoadFlags { x } => format!("LD V{:X}, R", x),
+            },
+            Syntax::Octo => match self {
+                Cls => "clear".to_string(),
+                Ret => "return".to_string(),
+                ScrollDown { n } => format!("scroll-down {}", n),
+                ScrollUp { n } => format!("scroll-up {}", n),
+                ScrollRight => "scroll-right".to_string(),
+                ScrollLeft => "scroll-left".to_string(),
+                Exit => "exit".to_string(),
+                LowRes => "lores".to_string(),
+                HighRes => "hires".to_string(),
+                Jump { nnn: a } => format!("jump {}", nnn(a)),
+                // A call is written as the name of the subroutine
+                Call { nnn: a } => {
+                    let target = nnn(a);
+                    if target.starts_with("0x") {
+                        format!(":call {}", target)
+                    } else {
+                        target
+                    }
+                }
+                // Octo's conditions say when the next instruction runs, which
+                // is the opposite of when it is skipped
+                SkipEqImm { x, kk: b } => {
+                    format!("if v{:x} != {} then", x, kk(b))
+                }
+                SkipNeImm { x, kk: b } => {
+                    format!("if v{:x} == {} then", x, kk(b))
+                }
+                SkipEqReg { x, y } => format!("if v{:x} != v{:x} then", x, y),
+                StoreRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+                LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+                LoadImm { x, kk: b } => format!("v{:x} := {}", x, kk(b)),
+                AddImm { x, kk: b } => format!("v{:x} += {}", x, kk(b)),
+                LoadReg { x, y } => format!("v{:x} := v{:x}", x, y),
+                Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+                And { x, y } => format!("v{:x} &= v{:x}", x, y),
+                Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+                AddReg { x, y } => format!("v{:x} += v{:x}", x, y),
+                Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+                ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+                SubN { x, y } => format!("v{:x} =- v{:x}", x, y),
+                ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+                SkipNeReg { x, y } => format!("if v{:x} == v{:x} then", x, y),
+                LoadI { nnn: a } => format!("i := {}", nnn(a)),
+                JumpV0 { nnn: a } => format!("jump0 {}", nnn(a)),
+                Random { x, kk: b } => format!("v{:x} := random {}", x, kk(b)),
+                Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+                SkipKey { x } => format!("if v{:x} -key then", x),
+                SkipNotKey { x } => format!("if v{:x} key then", x),
+                LoadILong => format!("i := long{}", long),
+                Plane { n } => format!("plane {}", n),
+                Audio => "audio".to_string(),
+                GetDelay { x } => format!("v{:x} := delay", x),
+                WaitKey { x } => format!("v{:x} := key", x),
+                SetDelay { x } => format!("delay := v{:x}", x),
+                SetSound { x } => format!("buzzer := v{:x}", x),
+                AddI { x } => format!("i += v{:x}", x),
+                Font { x } => format!("i := hex v{:x}", x),
+                BigFont { x } => format!("i := bighex v{:x}", x),
+                Bcd { x } => format!("bcd v{:x}", x),
+                Pitch { x } => format!("pitch := v{:x}", x),
+                Store { x } => format!("save v{:x}", x),
+                Load { x } => format!("load v{:x}", x),
+                StoreFlags { x } => format!("saveflags v{:x}", x),
+                LoadFlags { x } => format!("loadflags v{:x}", x),
+            },
+        }
+    }
+}
+
+// Cowgod's mnemonics with addresses as numbers, e.g. "JP #208"
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(None, Syntax::Cowgod))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x7A05), Ok(Instruction::AddImm { x: 0xA, kk: 5 }));
+        assert_eq!(
+            decode(0xD12F),
+            Ok(Instruction::Draw { x: 1, y: 2, n: 0xF })
+        );
+        assert_eq!(decode(0xF301), Ok(Instruction::Plane { n: 3 }));
+        assert_eq!(decode(0xF000), Ok(Instruction::LoadILong));
+        assert_eq!(decode(0x0123), Err(DecodeError { opcode: 0x0123 }));
+        assert_eq!(decode(0xF100), Err(DecodeError { opcode: 0xF100 }));
+        assert_eq!(decode(0x9001), Err(DecodeError { opcode: 0x9001 }));
+        assert_eq!(
+            DecodeError { opcode: 0x5AB4 }.to_string(),
+            "Unknown opcode 5AB4"
+        );
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let mut valid = 0;
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = decode(opcode) {
+                assert_eq!(instruction.encode(), opcode);
+                valid += 1;
+            }
+        }
+        // 7 fixed 00Ex and 00Fx opcodes and 32 scrolls, 10 groups of 4096
+        // from 1nnn to Dxyn, 3 + 9 + 1 groups of 256 for 5xyn, 8xyn and
+        // 9xy0, 32 for Exkk, F000 and F002, and 14 groups of 16 for Fxkk
+        assert_eq!(valid, 7 + 32 + 10 * 4096 + 13 * 256 + 32 + 2 + 14 * 16);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::Call { nnn: 0x208 }.to_string(), "CALL #208");
+        assert_eq!(
+            Instruction::SkipEqImm { x: 3, kk: 0x10 }.to_string(),
+            "SE V3, #10"
+        );
+        assert_eq!(Instruction::LoadILong.to_string(), "LD I, LONG");
+        assert_eq!(
+            Instruction::LoadILong.format(Some(0x1234), Syntax::Octo),
+            "i := long 0x1234"
+        );
+        assert!(Instruction::SkipKey { x: 0 }.is_skip());
+        assert!(!Instruction::Jump { nnn: 0 }.is_skip());
+        assert_eq!(Instruction::LoadILong.size(), 4);
+    }
+}
@@ -0,0 +1,43 @@
+//! A CHIP-8 interpreter with SUPER-CHIP and XO-CHIP extensions.
+//!
+//! The interpreter has no display, audio or input of its own. A frontend
+//! loads a ROM, runs it a frame at a time and presents the result:
+//!
+//! ```no_run
+//! use chip8::{Chip8, Platform};
+//!
+//! let rom = std::fs::read("roms/PONG").unwrap();
+//! let platform = Platform::Chip8;
+//! let mut chip8 =
+//!     Chip8::from_bytes(&rom, platform, platform.default_quirks()).unwrap();
+//!
+//! chip8.set_key(0x1);
+//! chip8.run_frame(15).unwrap();
+//!
+//! let w = chip8.display_width();
+//! let lit = chip8.display()[..w].iter().filter(|&&p| p != 0).count();
+//! println!("{} pixels lit on the top row, PC = {:03X}", lit, chip8.pc());
+//! ```
+
+pub mod asm;
+pub mod chip8;
+pub mod database;
+pub mod debugger;
+pub mod disasm;
+pub mod instruction;
+pub mod movie;
+pub mod octo;
+pub mod platform;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod trace;
+
+pub use crate::chip8::{
+    AccessKind, Chip8, Chip8Error, MemoryAccess, StepOutcome, DISPLAY_H,
+    DISPLAY_W, HIRES_DISPLAY_H, HIRES_DISPLAY_W, PLANE_COUNT,
+};
+pub use crate::instruction::Instruction;
+pub use crate::platform::Platform;
+pub use crate::quirks::Quirks;
@@ -1,20 +1,696 @@
-mod chip8;
+mod audio;
+mod config;
 mod emulator;
+mod headless;
+mod palette;
 
+use audio::Beep;
+use chip8::asm;
+use chip8::database::Database;
+use chip8::disasm::{self, Syntax};
+use chip8::movie::{self, Movie};
+use chip8::octo;
+use chip8::platform::{self, Platform};
+use chip8::quirks::{self, Quirks};
+use chip8::rng::Rng;
+use chip8::trace::{self, Columns};
+use chip8::Chip8;
+use config::Bindings;
 use std::env;
+use std::fs;
 use std::io;
+use std::path::Path;
+
+// ROM database entries that add to or replace the bundled ones. Read if it
+// exists and no other database file is given
+const LOCAL_DATABASE_PATH: &str = "chip8-database.json";
+
+const USAGE: &str = "Usage: ./chip8 [--platform name] [--quirks profile] \
+                     [--quirk name[=on|off]]... \
+                     [--ipf instructions_per_frame] [--beep-freq hz] \
+                     [--volume 0.0-1.0] [--mute] [--wav wav_file] \
+                     [--rewind seconds] [--rewind-mb megabytes] \
+                     [--seed n] [--vip-rng vip_interpreter_file] \
+                     [--record movie_file] [--play movie_file] [--debug] \
+                     [--config toml_file] [--database json_file] \
+                     [--palette name_or_colours] \
+                     [--trace file [--trace-pc start-end] \
+                     [--trace-cycles start-end] \
+                     [--trace-syntax cowgod|octo|none]] \
+                     [--headless [--frames n] [--until-pc addr] \
+                     [--keys key_script] [--display txt_or_png_file] \
+                     [--registers json_file]] [rom_file]\n       \
+                     ./chip8 disasm [--syntax cowgod|octo] \
+                     [--output file] rom_file\n       \
+                     ./chip8 asm [--output rom_file] [--listing file] \
+                     source_file\n       \
+                     ./chip8 octo [--output rom_file] source_file.8o\n       \
+                     ./chip8 trace-diff [--platform name] \
+                     [--quirks profile] [--quirk name[=on|off]]... \
+                     [--ipf instructions_per_frame] \
+                     [--seed n] [--keys key_script] [--columns spec] \
+                     [--context n] [--frames n] rom_file trace_file";
 
 fn main() -> Result<(), io::Error> {
-    let args: Vec<String> = env::args().collect();
+    let mut machine = MachineArgs::default();
+    let mut beep = Beep::default();
+    let mut muted = false;
+    let mut wav_file = None;
+    let mut rewind_frames = emulator::DEFAULT_REWIND_SECONDS * 60;
+    let mut rewind_bytes = emulator::DEFAULT_REWIND_MEGABYTES << 20;
+    let mut vip_interpreter = None;
+    let mut record = None;
+    let mut play = None;
+    let mut debug = false;
+    let mut config = None;
+    let mut database = None;
+    let mut palette = None;
+    let mut trace = None;
+    let mut trace_filter = trace::Filter::default();
+    let mut trace_syntax = Some(Syntax::Cowgod);
+    let mut headless = false;
+    let mut frames = None;
+    let mut headless_options = headless::Options {
+        frames: headless::DEFAULT_FRAMES,
+        until_pc: None,
+        keys: Vec::new(),
+        display: None,
+        registers: None,
+    };
+    let mut rom = None;
+
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(|a| a.as_str()) {
+        Some("disasm") => {
+            args.next();
+            return disasm(args);
+        }
+        Some("asm") => {
+            args.next();
+            return asm(args);
+        }
+        Some("octo") => {
+            args.next();
+            return compile_octo(args);
+        }
+        Some("trace-diff") => {
+            args.next();
+            return trace_diff(args);
+        }
+        _ => (),
+    }
+
+    while let Some(arg) = args.next() {
+        if machine.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--beep-freq" => {
+                beep.frequency = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n: &f32| n > 0.0)
+                    .ok_or_else(|| {
+                        invalid_input(
+                            "--beep-freq expects a positive number".to_string(),
+                        )
+                    })?;
+            }
+            "--volume" => {
+                beep.volume = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n: &f32| (0.0..=1.0).contains(n))
+                    .ok_or_else(|| {
+                        invalid_input(
+                            "--volume expects a number from 0.0 to 1.0"
+                                .to_string(),
+                        )
+                    })?;
+            }
+            "--mute" => muted = true,
+            "--wav" => {
+                wav_file = Some(args.next().ok_or_else(|| {
+                    invalid_input("--wav expects a file name".to_string())
+                })?);
+            }
+            "--rewind" => {
+                let seconds: usize = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| {
+                    invalid_input("--rewind expects a number".to_string())
+                })?;
+                rewind_frames = seconds.checked_mul(60).ok_or_else(|| {
+                    invalid_input(format!(
+                        "--rewind {} is too many seconds",
+                        seconds
+                    ))
+                })?;
+            }
+            "--rewind-mb" => {
+                let megabytes: usize = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| {
+                        invalid_input(
+                            "--rewind-mb expects a number".to_string(),
+                        )
+                    })?;
+                rewind_bytes =
+                    megabytes.checked_mul(1 << 20).ok_or_else(|| {
+                        invalid_input(format!(
+                            "--rewind-mb {} is too many megabytes",
+                            megabytes
+                        ))
+                    })?;
+            }
+            "--vip-rng" => {
+                vip_interpreter = Some(args.next().ok_or_else(|| {
+                    invalid_input("--vip-rng expects a file name".to_string())
+                })?);
+            }
+            "--record" => {
+                record = Some(args.next().ok_or_else(|| {
+                    invalid_input("--record expects a file name".to_string())
+                })?);
+            }
+            "--play" => {
+                let fname = args.next().ok_or_else(|| {
+                    invalid_input("--play expects a file name".to_string())
+                })?;
+                play = Some(Movie::parse(&fs::read_to_string(fname)?)?);
+            }
+            "--debug" => debug = true,
+            "--config" => {
+                config = Some(args.next().ok_or_else(|| {
+                    invalid_input("--config expects a file name".to_string())
+                })?);
+            }
+            "--palette" => {
+                let spec = args.next().unwrap_or_default();
+                palette = Some(palette::parse(&spec).map_err(invalid_input)?);
+            }
+            "--database" => {
+                database = Some(args.next().ok_or_else(|| {
+                    invalid_input("--database expects a file name".to_string())
+                })?);
+            }
+            "--trace" => {
+                trace = Some(args.next().ok_or_else(|| {
+                    invalid_input("--trace expects a file name".to_string())
+                })?);
+            }
+            "--trace-pc" => {
+                let hex = |a: &str| u16::from_str_radix(a, 16).ok();
+                trace_filter.addresses = Some(
+                    args.next()
+                        .and_then(|a| trace::parse_range(&a, hex))
+                        .ok_or_else(|| {
+                            invalid_input(
+                                "--trace-pc expects a hexadecimal address \
+                                 range, e.g. 200-2ff"
+                                    .to_string(),
+                            )
+                        })?,
+                );
+            }
+            "--trace-cycles" => {
+                let number = |a: &str| a.parse().ok();
+                trace_filter.cycles = Some(
+                    args.next()
+                        .and_then(|a| trace::parse_range(&a, number))
+                        .ok_or_else(|| {
+                            invalid_input(
+                                "--trace-cycles expects a range of cycles, \
+                                 e.g. 1000-2000"
+                                    .to_string(),
+                            )
+                        })?,
+                );
+            }
+            "--trace-syntax" => {
+                let name = args.next().unwrap_or_default();
+                trace_syntax = match name.as_str() {
+                    "none" => None,
+                    _ => Some(Syntax::from_name(&name).ok_or_else(|| {
+                        invalid_input(format!(
+                            "Unknown syntax '{}'. Expected one of: {}, none",
+                            name,
+                            disasm::SYNTAX_NAMES.join(", ")
+                        ))
+                    })?),
+                };
+            }
+            "--headless" => headless = true,
+            "--frames" => {
+                frames =
+                    Some(args.next().and_then(|n| n.parse().ok()).ok_or_else(
+                        || {
+                            invalid_input(
+                                "--frames expects a number".to_string(),
+                            )
+                        },
+                    )?);
+            }
+            "--until-pc" => {
+                headless_options.until_pc = Some(
+                    args.next()
+                        .and_then(|a| u16::from_str_radix(&a, 16).ok())
+                        .ok_or_else(|| {
+                            invalid_input(
+                                "--until-pc expects a hexadecimal address"
+                                    .to_string(),
+                            )
+                        })?,
+                );
+            }
+            "--keys" => {
+                let fname = args.next().ok_or_else(|| {
+                    invalid_input("--keys expects a file name".to_string())
+                })?;
+                headless_options.keys =
+                    headless::parse_key_script(&fs::read_to_string(fname)?)?;
+            }
+            "--display" => {
+                headless_options.display =
+                    Some(args.next().ok_or_else(|| {
+                        invalid_input(
+                            "--display expects a file name".to_string(),
+                        )
+                    })?);
+            }
+            "--registers" => {
+                headless_options.registers =
+                    Some(args.next().ok_or_else(|| {
+                        invalid_input(
+                            "--registers expects a file name".to_string(),
+                        )
+                    })?);
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(invalid_input(USAGE.to_string())),
+        }
+    }
+
+    let rom = rom.ok_or_else(|| {
+        invalid_input(format!("ROM file not specified. {}", USAGE))
+    })?;
+
+    let rng = match vip_interpreter {
+        Some(fname) => Some(Rng::vip(
+            machine.seed.unwrap_or_else(rand::random),
+            &fs::read(fname)?,
+        )?),
+        None => machine.seed.map(Rng::new),
+    };
+
+    // A ROM found in the database picks the platform, quirks, speed, key
+    // layout and colours. An option given on the command line replaces
+    // just its own setting, so --platform keeps the ROM's quirks and --quirk
+    // changes one of them
+    let rom_data = emulator::read_rom(&rom)?;
+    let entry = load_database(database.as_deref())?
+        .identify(&rom_data)
+        .cloned();
+    if let Some(ref entry) = entry {
+        eprintln!("Detected {}", entry);
+    }
+    let platform = machine
+        .platform
+        .or_else(|| entry.as_ref().map(|entry| entry.platform))
+        .unwrap_or(Platform::Chip8);
+    let profile = entry
+        .as_ref()
+        .map_or_else(|| platform.default_quirks(), |entry| entry.quirks);
+    let ipf = machine
+        .ipf
+        .or_else(|| entry.as_ref().and_then(|entry| entry.tickrate))
+        .unwrap_or(emulator::DEFAULT_INSTRUCTIONS_PER_FRAME);
+    let layout = entry.as_ref().map_or(&[][..], |entry| &entry.keys[..]);
+    let palette = palette
+        .or_else(|| {
+            entry
+                .as_ref()
+                .and_then(|e| palette::from_colours(&e.colours))
+        })
+        .unwrap_or(palette::PALETTE);
+
+    let mut options = emulator::Options {
+        platform,
+        quirks: machine.quirks(profile),
+        instructions_per_frame: ipf,
+        beep,
+        muted,
+        wav_file,
+        rewind_frames,
+        rewind_bytes,
+        rng,
+        record,
+        play: None,
+        debug,
+        trace,
+        trace_filter,
+        trace_syntax,
+        bindings: Bindings::default(),
+        palette,
+    };
+
+    // A movie replays with the settings it was recorded with, whatever was
+    // given on the command line
+    headless_options.frames = frames.unwrap_or(headless::DEFAULT_FRAMES);
+    if let Some(movie) = play {
+        movie.check_rom(&rom_data)?;
+        options.platform = movie.platform;
+        options.quirks = movie.quirks;
+        options.instructions_per_frame = movie.instructions_per_frame;
+        options.rng = Some(movie.rng.clone());
+        headless_options.frames = frames.unwrap_or(movie.frames);
+        headless_options.keys = headless::movie_key_events(&movie);
+        options.play = Some(movie);
+    }
+
+    if headless {
+        headless::run_headless(&rom, &options, &headless_options)?;
+    } else {
+        let sha1 = movie::rom_sha1(&rom_data);
+        options.bindings =
+            Bindings::with_layout(layout).load(config.as_deref(), &sha1)?;
+        emulator::run_emulator(&rom, &options)?;
+    }
 
-    if args.len() != 2 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "ROM file not specified. Usage: ./chip8 [rom_file]",
-        ));
+    Ok(())
+}
+
+// Print a ROM as assembly language
+fn disasm(mut args: impl Iterator<Item = String>) -> Result<(), io::Error> {
+    let mut syntax = Syntax::Cowgod;
+    let mut output = None;
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                let name = args.next().unwrap_or_default();
+                syntax = Syntax::from_name(&name).ok_or_else(|| {
+                    invalid_input(format!(
+                        "Unknown syntax '{}'. Expected one of: {}",
+                        name,
+                        disasm::SYNTAX_NAMES.join(", ")
+                    ))
+                })?;
+            }
+            "--output" => {
+                output = Some(args.next().ok_or_else(|| {
+                    invalid_input("--output expects a file name".to_string())
+                })?);
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(invalid_input(USAGE.to_string())),
+        }
     }
 
-    emulator::run_emulator(&args[1])?;
+    let rom = rom.ok_or_else(|| {
+        invalid_input(format!("ROM file not specified. {}", USAGE))
+    })?;
+    let text = disasm::disassemble(&fs::read(rom)?, syntax);
+
+    match output {
+        Some(fname) => fs::write(fname, text),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+// Assemble a source file into a ROM, next to the source by default
+fn asm(mut args: impl Iterator<Item = String>) -> Result<(), io::Error> {
+    let mut output = None;
+    let mut listing = None;
+    let mut source = None;
 
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                output = Some(args.next().ok_or_else(|| {
+                    invalid_input("--output expects a file name".to_string())
+                })?);
+            }
+            "--listing" => {
+                listing = Some(args.next().ok_or_else(|| {
+                    invalid_input("--listing expects a file name".to_string())
+                })?);
+            }
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(invalid_input(USAGE.to_string())),
+        }
+    }
+
+    let source = source.ok_or_else(|| {
+        invalid_input(format!("Source file not specified. {}", USAGE))
+    })?;
+    let assembly = asm::assemble_file(&source).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    })?;
+
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .into_owned()
+    });
+    fs::write(output, &assembly.rom)?;
+
+    if let Some(fname) = listing {
+        fs::write(fname, assembly.listing_text())?;
+    }
     Ok(())
 }
+
+// Compile Octo source into a ROM, next to the source by default
+fn compile_octo(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(), io::Error> {
+    let mut output = None;
+    let mut source = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                output = Some(args.next().ok_or_else(|| {
+                    invalid_input("--output expects a file name".to_string())
+                })?);
+            }
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(invalid_input(USAGE.to_string())),
+        }
+    }
+
+    let source = source.ok_or_else(|| {
+        invalid_input(format!("Source file not specified. {}", USAGE))
+    })?;
+    let rom = octo::compile_file(&source).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    })?;
+
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .into_owned()
+    });
+    fs::write(output, rom)
+}
+
+// Run a ROM against another emulator's trace, stopping where they differ
+fn trace_diff(mut args: impl Iterator<Item = String>) -> Result<(), io::Error> {
+    let mut machine = MachineArgs::default();
+    let mut keys = Vec::new();
+    let mut columns = Columns::ours();
+    let mut context = 5;
+    let mut frames = headless::DEFAULT_FRAMES;
+    let mut files = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if machine.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--keys" => {
+                let fname = args.next().ok_or_else(|| {
+                    invalid_input("--keys expects a file name".to_string())
+                })?;
+                keys = headless::parse_key_script(&fs::read_to_string(fname)?)?;
+            }
+            "--columns" => {
+                columns = Columns::parse(&args.next().unwrap_or_default())?;
+            }
+            "--context" => {
+                context = args.next().and_then(|n| n.parse().ok()).ok_or_else(
+                    || invalid_input("--context expects a number".to_string()),
+                )?;
+            }
+            "--frames" => {
+                frames = args.next().and_then(|n| n.parse().ok()).ok_or_else(
+                    || invalid_input("--frames expects a number".to_string()),
+                )?;
+            }
+            _ if files.len() < 2 => files.push(arg),
+            _ => return Err(invalid_input(USAGE.to_string())),
+        }
+    }
+
+    if files.len() < 2 {
+        return Err(invalid_input(format!(
+            "ROM and trace files not specified. {}",
+            USAGE
+        )));
+    }
+    let rom = emulator::read_rom(&files[0])?;
+    let reference =
+        trace::parse_reference(&fs::read_to_string(&files[1])?, &columns)?;
+
+    let platform = machine.platform.unwrap_or(Platform::Chip8);
+    let quirks = machine.quirks(platform.default_quirks());
+    let mut chip8 = Chip8::from_bytes(&rom, platform, quirks)?;
+    if let Some(seed) = machine.seed {
+        chip8.set_rng(Rng::new(seed));
+    }
+
+    let ipf = machine
+        .ipf
+        .unwrap_or(emulator::DEFAULT_INSTRUCTIONS_PER_FRAME);
+    let (report, matched) = headless::trace_diff(
+        &mut chip8, ipf, frames, &keys, &reference, context,
+    )?;
+    print!("{}", report);
+    if matched {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "The traces differ".to_string(),
+        ))
+    }
+}
+
+// The bundled ROM database, with the entries of a local database file on
+// top. Without one, `LOCAL_DATABASE_PATH` is read if it exists
+fn load_database(fname: Option<&str>) -> Result<Database, io::Error> {
+    let mut database = Database::bundled();
+    let fname = match fname {
+        Some(fname) => fname,
+        None if Path::new(LOCAL_DATABASE_PATH).exists() => LOCAL_DATABASE_PATH,
+        None => return Ok(database),
+    };
+    database.merge(Database::load(fname)?);
+    Ok(database)
+}
+
+// Parse a --quirk argument, "name", "name=on" or "name=off"
+fn parse_quirk(arg: Option<String>) -> Result<(String, bool), io::Error> {
+    let arg = arg.unwrap_or_default();
+    let mut parts = arg.splitn(2, '=');
+    let name = parts.next().unwrap_or("").to_string();
+    let value = match parts.next() {
+        None | Some("on") => true,
+        Some("off") => false,
+        Some(value) => {
+            return Err(invalid_input(format!(
+                "Invalid value '{}' for quirk '{}'. Expected on or off",
+                value, name
+            )))
+        }
+    };
+
+    if Quirks::default().get(&name).is_none() {
+        return Err(invalid_input(format!(
+            "Unknown quirk '{}'. Expected one of: {}",
+            name,
+            quirks::QUIRK_NAMES.join(", ")
+        )));
+    }
+    Ok((name, value))
+}
+
+// How the interpreter is set up, from the options shared by running a ROM
+// and trace-diff
+#[derive(Default)]
+struct MachineArgs {
+    platform: Option<Platform>,
+    quirks: Option<Quirks>,
+    quirk_overrides: Vec<(String, bool)>, // --quirk arguments, in order
+    ipf: Option<u32>,
+    seed: Option<u64>,
+}
+
+impl MachineArgs {
+    // If `arg` is one of the shared options, read it and its value from
+    // `args`. Returns whether it was
+    fn parse(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, io::Error> {
+        match arg {
+            "--platform" => {
+                let name = args.next().unwrap_or_default();
+                self.platform =
+                    Some(Platform::from_name(&name).ok_or_else(|| {
+                        invalid_input(format!(
+                            "Unknown platform '{}'. Expected one of: {}",
+                            name,
+                            platform::PLATFORM_NAMES.join(", ")
+                        ))
+                    })?);
+            }
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                self.quirks =
+                    Some(Quirks::from_name(&name).ok_or_else(|| {
+                        invalid_input(format!(
+                            "Unknown quirks profile '{}'. Expected one of: {}",
+                            name,
+                            quirks::PROFILE_NAMES.join(", ")
+                        ))
+                    })?);
+            }
+            "--quirk" => self.quirk_overrides.push(parse_quirk(args.next())?),
+            "--ipf" => {
+                self.ipf = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| {
+                            invalid_input(
+                                "--ipf expects a positive number".to_string(),
+                            )
+                        })?,
+                );
+            }
+            "--seed" => {
+                self.seed =
+                    Some(args.next().and_then(|n| n.parse().ok()).ok_or_else(
+                        || invalid_input("--seed expects a number".to_string()),
+                    )?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // The --quirks profile, or `profile` without one, with the --quirk
+    // arguments applied on top
+    fn quirks(&self, profile: Quirks) -> Quirks {
+        let mut quirks = self.quirks.unwrap_or(profile);
+        for (name, value) in &self.quirk_overrides {
+            quirks.set(name, *value);
+        }
+        quirks
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,330 @@
+use crate::chip8::Chip8;
+use crate::platform::Platform;
+use crate::quirks::{self, Quirks};
+use crate::rng::Rng;
+use std::fmt;
+use std::io;
+
+const HEADER: &str = "chip8-movie 1";
+
+/// A recording of the keypad, frame by frame, along with everything else
+/// needed to replay it exactly: the ROM it was made with, the platform,
+/// quirks and speed, and the initial state of the random number generator.
+///
+/// Movies are stored as text, one setting or input per line:
+///
+/// ```text
+/// chip8-movie 1
+/// rom 0e91d4b3cc0e0a7bc2d1e1a4bcbc5f5d8a5c1d3e
+/// platform chip8
+/// quirks clip_sprites display_wait
+/// ipf 15
+/// rng xorshift 1234
+/// frames 600
+/// input 0 0000
+/// input 120 0020
+/// ```
+///
+/// Each input line gives the keypad state from that frame onwards, with bit
+/// n set while key n is held
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub rom_sha1: String,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    pub rng: Rng,
+    pub frames: u32,
+    pub inputs: Vec<(u32, u16)>, // Frame and keypad state, in frame order
+}
+
+impl Movie {
+    /// Start recording a run of `rom`. `chip8` must not have run yet
+    pub fn new(
+        rom: &[u8],
+        chip8: &Chip8,
+        platform: Platform,
+        ipf: u32,
+    ) -> Movie {
+        Movie {
+            rom_sha1: rom_sha1(rom),
+            platform,
+            quirks: chip8.quirks(),
+            instructions_per_frame: ipf,
+            rng: chip8.rng().clone(),
+            frames: 0,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Record the keypad state for the next frame. Called once per frame,
+    /// just before it runs
+    pub fn record_frame(&mut self, chip8: &Chip8) {
+        let keys = chip8.keypad();
+        if self.inputs.last().map(|&(_, k)| k) != Some(keys) {
+            self.inputs.push((self.frames, keys));
+        }
+        self.frames += 1;
+    }
+
+    /// Keypad state for `frame`, or None once the movie has ended
+    pub fn keys_at(&self, frame: u32) -> Option<u16> {
+        if frame >= self.frames {
+            return None;
+        }
+        let n = match self.inputs.binary_search_by_key(&frame, |&(f, _)| f) {
+            Ok(n) => n,
+            Err(0) => return Some(0),
+            Err(n) => n - 1,
+        };
+        Some(self.inputs[n].1)
+    }
+
+    /// Set the keypad for `frame`. Returns false once the movie has ended
+    pub fn play_frame(&self, frame: u32, chip8: &mut Chip8) -> bool {
+        match self.keys_at(frame) {
+            Some(keys) => {
+                chip8.set_keypad(keys);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check that the movie was recorded with `rom`
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), io::Error> {
+        let sha1 = rom_sha1(rom);
+        if sha1 == self.rom_sha1 {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Movie was recorded with a different ROM (SHA-1 {}, \
+                     not {})",
+                    self.rom_sha1, sha1
+                ),
+            ))
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, io::Error> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        match lines.next() {
+            Some((_, HEADER)) => (),
+            _ => return Err(invalid_data("Not a movie file".to_string())),
+        }
+
+        let mut rom_sha1 = None;
+        let mut platform = None;
+        let mut quirks = None;
+        let mut ipf = None;
+        let mut rng = None;
+        let mut frames = None;
+        let mut inputs: Vec<(u32, u16)> = Vec::new();
+
+        for (n, line) in lines {
+            let invalid =
+                || invalid_data(format!("Movie line {}: '{}'", n, line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match (fields[0], fields.len()) {
+                ("rom", 2) => rom_sha1 = Some(fields[1].to_lowercase()),
+                ("platform", 2) => {
+                    platform = Some(
+                        Platform::from_name(fields[1]).ok_or_else(invalid)?,
+                    )
+                }
+                ("quirks", _) => {
+                    let mut q = Quirks::default();
+                    for name in &fields[1..] {
+                        if !q.set(name, true) {
+                            return Err(invalid());
+                        }
+                    }
+                    quirks = Some(q);
+                }
+                ("ipf", 2) => {
+                    ipf = Some(fields[1].parse().map_err(|_| invalid())?)
+                }
+                ("rng", 3) if fields[1] == "xorshift" => {
+                    let state = fields[2].parse().map_err(|_| invalid())?;
+                    rng = Some(Rng::Xorshift { state });
+                }
+                ("rng", 4) if fields[1] == "vip" => {
+                    let r9 = fields[2].parse().map_err(|_| invalid())?;
+                    let page = parse_hex(fields[3])
+                        .filter(|page| page.len() == 0x100)
+                        .ok_or_else(invalid)?;
+                    rng = Some(Rng::Vip { r9, page });
+                }
+                ("frames", 2) => {
+                    frames = Some(fields[1].parse().map_err(|_| invalid())?)
+                }
+                ("input", 3) => {
+                    let frame = fields[1].parse().map_err(|_| invalid())?;
+                    let keys = u16::from_str_radix(fields[2], 16)
+                        .map_err(|_| invalid())?;
+                    if inputs.last().map_or(false, |&(f, _)| f >= frame) {
+                        return Err(invalid());
+                    }
+                    inputs.push((frame, keys));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let missing =
+            |name| invalid_data(format!("Movie has no '{}' line", name));
+        Ok(Movie {
+            rom_sha1: rom_sha1.ok_or_else(|| missing("rom"))?,
+            platform: platform.ok_or_else(|| missing("platform"))?,
+            quirks: quirks.ok_or_else(|| missing("quirks"))?,
+            instructions_per_frame: ipf.ok_or_else(|| missing("ipf"))?,
+            rng: rng.ok_or_else(|| missing("rng"))?,
+            frames: frames.ok_or_else(|| missing("frames"))?,
+            inputs,
+        })
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "rom {}", self.rom_sha1)?;
+        writeln!(f, "platform {}", self.platform.name())?;
+
+        write!(f, "quirks")?;
+        for name in quirks::QUIRK_NAMES {
+            if self.quirks.get(name) == Some(true) {
+                write!(f, " {}", name)?;
+            }
+        }
+        writeln!(f)?;
+
+        writeln!(f, "ipf {}", self.instructions_per_frame)?;
+        match self.rng {
+            Rng::Xorshift { state } => writeln!(f, "rng xorshift {}", state)?,
+            Rng::Vip { r9, ref page } => {
+                write!(f, "rng vip {} ", r9)?;
+                for b in page {
+                    write!(f, "{:02x}", b)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        writeln!(f, "frames {}", self.frames)?;
+
+        for &(frame, keys) in &self.inputs {
+            writeln!(f, "input {} {:04x}", frame, keys)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase hexadecimal SHA-1 of a ROM image
+pub fn rom_sha1(rom: &[u8]) -> String {
+    sha1::Sha1::from(rom).digest().to_string()
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(hex.get(n..(n + 2))?, 16).ok())
+        .collect()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Keeps loading V0 with random bytes, counting in V1 while key 5 is
+    // held
+    const ROM: [u8; 12] = [
+        0x65, 0x05, 0xC0, 0xFF, 0xE5, 0x9E, 0x12, 0x02, 0x71, 0x01, 0x12, 0x02,
+    ];
+
+    fn record(rom: &[u8], presses: &[u32]) -> (Movie, Chip8) {
+        let mut chip8 =
+            Chip8::from_bytes(rom, Platform::Chip8, Quirks::cosmac_vip())
+                .unwrap();
+        chip8.set_rng(Rng::new(99));
+        let mut movie = Movie::new(rom, &chip8, Platform::Chip8, 10);
+
+        for frame in 0..30 {
+            chip8.reset_keys();
+            if presses.contains(&frame) {
+                chip8.set_key(5);
+            }
+            movie.record_frame(&chip8);
+            chip8.run_frame(10).unwrap();
+        }
+        (movie, chip8)
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let (movie, recorded) = record(&ROM, &[3, 4, 20]);
+
+        assert_eq!(movie.frames, 30);
+        assert_eq!(
+            movie.inputs,
+            [(0, 0), (3, 0x20), (5, 0), (20, 0x20), (21, 0)]
+        );
+        assert_eq!(movie.keys_at(4), Some(0x20));
+        assert_eq!(movie.keys_at(10), Some(0));
+        assert_eq!(movie.keys_at(30), None);
+
+        // Replaying from the text form gives the same final state
+        let movie = Movie::parse(&movie.to_string()).unwrap();
+        movie.check_rom(&ROM).unwrap();
+
+        let mut chip8 =
+            Chip8::from_bytes(&ROM, movie.platform, movie.quirks).unwrap();
+        chip8.set_rng(movie.rng.clone());
+        let mut frame = 0;
+        while movie.play_frame(frame, &mut chip8) {
+            chip8.run_frame(movie.instructions_per_frame).unwrap();
+            frame += 1;
+        }
+
+        assert_eq!(frame, 30);
+        assert!(chip8.v()[1] > 0);
+        assert_eq!(chip8.save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn test_movie_text() {
+        let interpreter = vec![0xAB; crate::rng::VIP_INTERPRETER_SIZE];
+        let (mut movie, _) = record(&ROM, &[1]);
+        movie.rng = Rng::vip(7, &interpreter).unwrap();
+
+        let text = movie.to_string();
+        assert!(text.starts_with("chip8-movie 1\n"));
+        assert!(text.contains(
+            "\nquirks shift_uses_vy load_store_increments_i logic_resets_vf \
+             clip_sprites display_wait\n"
+        ));
+        assert!(text.contains("\ninput 1 0020\n"));
+        assert_eq!(Movie::parse(&text).unwrap(), movie);
+
+        assert!(movie.check_rom(&ROM[..6]).is_err());
+        assert!(Movie::parse("chip8-movie 2\n").is_err());
+        assert!(Movie::parse(&text.replace("ipf 10\n", "")).is_err());
+        assert!(Movie::parse(&text.replace("input 2 0000", "input 0 0000"))
+            .is_err());
+        assert!(Movie::parse(&format!("{}quirks fast\n", text)).is_err());
+    }
+}
@@ -0,0 +1,1355 @@
+use crate::asm::AsmError;
+use crate::instruction::Instruction;
+use std::collections::HashMap;
+use std::f64::consts;
+use std::fs;
+
+// ROMs are loaded at 0x200, and XO-CHIP has 64 KiB of RAM
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x10000;
+
+// Macros that expand more often than this are assumed to be recursive
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+/// Compile the Octo source file at `path`
+pub fn compile_file(path: &str) -> Result<Vec<u8>, AsmError> {
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.to_string(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    compile(&source, path)
+}
+
+/// Compile Octo source, which came from the file `name`, to a ROM that
+/// loads at 0x200. Execution starts at the label `main`:
+///
+/// ```text
+/// :alias x v1
+/// :const SPEED 2
+/// : main
+///   x := 0
+///   loop
+///     x += SPEED
+///     if x == 10 then jump done
+///   again
+/// : done
+///   loop again
+/// ```
+///
+/// Labels may be used before they are defined. The SUPER-CHIP and
+/// XO-CHIP instructions are always available
+pub fn compile(source: &str, name: &str) -> Result<Vec<u8>, AsmError> {
+    let mut tokens = tokenize(source, name)?;
+    tokens.reverse();
+    let last = Token {
+        text: String::new(),
+        line: 1,
+        column: 1,
+        string: false,
+    };
+
+    let mut compiler = Compiler {
+        file: name.to_string(),
+        tokens,
+        last,
+        rom: Vec::new(),
+        here: START,
+        dict: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        references: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+        expansions: 0,
+        main_jump: true,
+    };
+
+    // Room for a jump to main, which is dropped if main comes first
+    compiler.emit(&[0, 0])?;
+    while !compiler.tokens.is_empty() {
+        compiler.statement()?;
+    }
+    compiler.finish()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    string: bool, // Quoted, so never a keyword or name
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How a forward reference is written once the name is defined
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fixup {
+    Addr,       // Low 12 bits of an instruction
+    Long,       // 16-bit word
+    HighNibble, // Bits 8-11 into the low nibble of a byte
+    HighByte,
+    LowByte,
+}
+
+struct Reference {
+    addr: usize,
+    fixup: Fixup,
+    name: Token,
+}
+
+// An operand of a comparison
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Byte(u8),
+}
+
+struct Compiler {
+    file: String,
+    tokens: Vec<Token>, // Remaining tokens, the next one last
+    last: Token,        // Most recently read, for errors at the end
+    rom: Vec<u8>,
+    here: usize,
+    dict: HashMap<String, f64>, // Labels and constants
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    references: Vec<Reference>,
+    branches: Vec<(usize, Token)>, // Jumps made by begin and else
+    loops: Vec<(usize, Token, Vec<usize>)>, // Start and jumps made by while
+    expansions: usize,
+    main_jump: bool,
+}
+
+impl Compiler {
+    fn statement(&mut self) -> Result<(), AsmError> {
+        use crate::instruction::Instruction::*;
+
+        let token = self.next()?;
+        if token.string {
+            return Err(self.error(&token, "Unexpected string"));
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.label(name)
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = match self.register_or_calc()? {
+                    Some(register) => register,
+                    None => {
+                        return Err(self
+                            .error(&self.last.clone(), "Expected a register"))
+                    }
+                };
+                self.aliases.insert(name.text, register);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.constant()?;
+                self.define(name, value)
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.define(name, value)
+            }
+            ":byte" => {
+                let value = self.value(-0x80, 0xFF)?;
+                self.emit(&[value as u8])
+            }
+            ":pointer" => {
+                let value = self.address(Fixup::Long, self.here)?;
+                self.emit(&[(value >> 8) as u8, value as u8])
+            }
+            ":org" => {
+                let addr = self.value(START as i64, MEMORY_SIZE as i64 - 1)?;
+                self.here = addr as usize;
+                Ok(())
+            }
+            ":unpack" => self.unpack(),
+            ":next" => {
+                let name = self.name()?;
+                self.define(name, (self.here + 1) as f64)
+            }
+            ":breakpoint" => self.name().map(|_| ()),
+            ":monitor" => {
+                self.next()?;
+                self.next().map(|_| ())
+            }
+            ":macro" => self.macro_definition(),
+            ":call" => {
+                let nnn = self.address(Fixup::Addr, self.here)?;
+                self.op(Call { nnn })
+            }
+            "return" | ";" => self.op(Ret),
+            "clear" => self.op(Cls),
+            "hires" => self.op(HighRes),
+            "lores" => self.op(LowRes),
+            "scroll-right" => self.op(ScrollRight),
+            "scroll-left" => self.op(ScrollLeft),
+            "exit" => self.op(Exit),
+            "audio" => self.op(Audio),
+            "scroll-down" => {
+                let n = self.value(0, 0xF)? as u8;
+                self.op(ScrollDown { n })
+            }
+            "scroll-up" => {
+                let n = self.value(0, 0xF)? as u8;
+                self.op(ScrollUp { n })
+            }
+            "plane" => {
+                let n = self.value(0, 3)? as u8;
+                self.op(Plane { n })
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.op(Bcd { x })
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+                if self.peek() != Some("-") {
+                    return self.op(if save {
+                        Store { x }
+                    } else {
+                        Load { x }
+                    });
+                }
+
+                // save vx - vy and load vx - vy
+                self.next()?;
+                let y = self.register()?;
+                if save {
+                    self.op(StoreRange { x, y })
+                } else {
+                    self.op(LoadRange { x, y })
+                }
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.op(StoreFlags { x })
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.op(LoadFlags { x })
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.value(0, 0xF)? as u8;
+                self.op(Draw { x, y, n })
+            }
+            "jump" => {
+                let nnn = self.address(Fixup::Addr, self.here)?;
+                self.op(Jump { nnn })
+            }
+            "jump0" => {
+                let nnn = self.address(Fixup::Addr, self.here)?;
+                self.op(JumpV0 { nnn })
+            }
+            // 0nnn, a machine code routine, isn't an instruction we run
+            "native" => {
+                let addr = self.address(Fixup::Addr, self.here)?;
+                self.emit(&[(addr >> 8) as u8, addr as u8])
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.op(match token.text.as_str() {
+                    "delay" => SetDelay { x },
+                    "buzzer" => SetSound { x },
+                    _ => Pitch { x },
+                })
+            }
+            "i" => self.i_statement(),
+            "if" => self.if_statement(),
+            "else" => {
+                let (jump, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => {
+                        return Err(self.error(&token, "'else' without 'begin'"))
+                    }
+                };
+                self.branches.push((self.here, token.clone()));
+                self.op(Jump { nnn: 0 })?;
+                self.patch_jump(jump, &token)
+            }
+            "end" => match self.branches.pop() {
+                Some((jump, _)) => self.patch_jump(jump, &token),
+                None => Err(self.error(&token, "'end' without 'begin'")),
+            },
+            "loop" => {
+                self.loops.push((self.here, token, Vec::new()));
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "'while' outside a loop"));
+                }
+                self.condition(true)?;
+                let jump = self.here;
+                if let Some(current) = self.loops.last_mut() {
+                    current.2.push(jump);
+                }
+                self.op(Jump { nnn: 0 })
+            }
+            "again" => {
+                let (start, _, whiles) = match self.loops.pop() {
+                    Some(current) => current,
+                    None => {
+                        return Err(self.error(&token, "'again' without 'loop'"))
+                    }
+                };
+                self.op(Jump { nnn: start as u16 })?;
+                for jump in whiles {
+                    self.patch_jump(jump, &token)?;
+                }
+                Ok(())
+            }
+            text if text.starts_with(':') => {
+                Err(self
+                    .error(&token, &format!("Unknown directive '{}'", text)))
+            }
+            _ => {
+                if let Some(x) = self.register_name(&token.text) {
+                    return self.register_statement(x);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(&token);
+                }
+                if let Some(n) = number(&token.text) {
+                    // Bare numbers are data
+                    let value = self.check(&token, n, -0x80, 0xFF)?;
+                    return self.emit(&[value as u8]);
+                }
+
+                // Anything else is a call to a subroutine
+                self.tokens.push(token);
+                let nnn = self.address(Fixup::Addr, self.here)?;
+                self.op(Call { nnn })
+            }
+        }
+    }
+
+    fn register_statement(&mut self, x: usize) -> Result<(), AsmError> {
+        use crate::instruction::Instruction::*;
+
+        let op = self.next()?;
+        let y = self.peek_register();
+
+        let byte = |compiler: &mut Compiler| {
+            compiler.value(-0x80, 0xFF).map(|kk| kk as u8)
+        };
+        let instruction = match (op.text.as_str(), y) {
+            (":=", Some(y)) => LoadReg { x, y },
+            (":=", None) => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    Random { x, kk: byte(self)? }
+                }
+                Some("key") => {
+                    self.next()?;
+                    WaitKey { x }
+                }
+                Some("delay") => {
+                    self.next()?;
+                    GetDelay { x }
+                }
+                _ => LoadImm { x, kk: byte(self)? },
+            },
+            ("+=", Some(y)) => AddReg { x, y },
+            ("+=", None) => AddImm { x, kk: byte(self)? },
+            ("-=", Some(y)) => Sub { x, y },
+            ("-=", None) => AddImm {
+                x,
+                kk: byte(self)?.wrapping_neg(),
+            },
+            ("=-", Some(y)) => SubN { x, y },
+            ("|=", Some(y)) => Or { x, y },
+            ("&=", Some(y)) => And { x, y },
+            ("^=", Some(y)) => Xor { x, y },
+            (">>=", Some(y)) => ShiftRight { x, y },
+            ("<<=", Some(y)) => ShiftLeft { x, y },
+            ("=-", None)
+            | ("|=", None)
+            | ("&=", None)
+            | ("^=", None)
+            | (">>=", None)
+            | ("<<=", None) => {
+                let token = self.next()?;
+                return Err(self.error(&token, "Expected a register"));
+            }
+            _ => {
+                return Err(
+                    self.error(&op, &format!("Unknown operator '{}'", op.text))
+                )
+            }
+        };
+
+        if y.is_some() {
+            self.next()?;
+        }
+        self.op(instruction)
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        use crate::instruction::Instruction::*;
+
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    let addr = self.address(Fixup::Long, self.here + 2)?;
+                    self.emit(&[0xF0, 0x00, (addr >> 8) as u8, addr as u8])
+                }
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.op(Font { x })
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.op(BigFont { x })
+                }
+                _ => {
+                    let nnn = self.address(Fixup::Addr, self.here)?;
+                    self.op(LoadI { nnn })
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.op(AddI { x })
+            }
+            _ => Err(self
+                .error(&op, &format!("Unknown operator '{}' for i", op.text))),
+        }
+    }
+
+    // if CONDITION then STATEMENT, or if CONDITION begin ... [else ...] end
+    fn if_statement(&mut self) -> Result<(), AsmError> {
+        use crate::instruction::Instruction::*;
+
+        let begin = self
+            .tokens
+            .iter()
+            .rev()
+            .map(|t| t.text.as_str())
+            .find(|&t| t == "then" || t == "begin")
+            == Some("begin");
+
+        self.condition(begin)?;
+        let word = self.next()?;
+        match (word.text.as_str(), begin) {
+            ("then", false) => Ok(()),
+            ("begin", true) => {
+                self.branches.push((self.here, word));
+                self.op(Jump { nnn: 0 })
+            }
+            _ => Err(self.error(&word, "Expected 'then' or 'begin'")),
+        }
+    }
+
+    // Compile a condition into instructions that skip the next one unless
+    // the condition holds, or if `negate` is set, when it holds
+    fn condition(&mut self, negate: bool) -> Result<(), AsmError> {
+        use crate::instruction::Instruction::*;
+
+        let x = self.register()?;
+        let token = self.next()?;
+        let mut op = token.text.as_str();
+        if negate {
+            op = match op {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">=" => "<",
+                ">" => "<=",
+                "<=" => ">",
+                _ => op,
+            };
+        }
+
+        match op {
+            "key" => return self.op(SkipNotKey { x }),
+            "-key" => return self.op(SkipKey { x }),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => (),
+            _ => {
+                return Err(self.error(
+                    &token,
+                    &format!("Unknown comparison '{}'", token.text),
+                ))
+            }
+        }
+
+        let rhs = match self.peek_register() {
+            Some(y) => {
+                self.next()?;
+                Operand::Register(y)
+            }
+            None => Operand::Byte(self.value(-0x80, 0xFF)? as u8),
+        };
+
+        match (op, rhs) {
+            ("==", Operand::Register(y)) => self.op(SkipNeReg { x, y }),
+            ("!=", Operand::Register(y)) => self.op(SkipEqReg { x, y }),
+            ("==", Operand::Byte(kk)) => self.op(SkipNeImm { x, kk }),
+            ("!=", Operand::Byte(kk)) => self.op(SkipEqImm { x, kk }),
+            _ => {
+                // VF is set to 1 by a subtraction with no borrow, so
+                // a - b leaves VF = (a >= b)
+                let x = Operand::Register(x);
+                let (a, b) = match op {
+                    "<" | ">=" => (x, rhs),
+                    _ => (rhs, x),
+                };
+                match (a, b) {
+                    (Operand::Register(a), Operand::Register(b)) => {
+                        self.op(LoadReg { x: 0xF, y: a })?;
+                        self.op(Sub { x: 0xF, y: b })?;
+                    }
+                    (Operand::Register(a), Operand::Byte(b)) => {
+                        self.op(LoadImm { x: 0xF, kk: b })?;
+                        self.op(SubN { x: 0xF, y: a })?;
+                    }
+                    (Operand::Byte(a), Operand::Register(b)) => {
+                        self.op(LoadImm { x: 0xF, kk: a })?;
+                        self.op(Sub { x: 0xF, y: b })?;
+                    }
+                    (Operand::Byte(_), Operand::Byte(_)) => unreachable!(),
+                }
+                let kk = match op {
+                    "<" | ">" => 1,
+                    _ => 0,
+                };
+                self.op(SkipEqImm { x: 0xF, kk })
+            }
+        }
+    }
+
+    // :unpack NIBBLE LABEL sets v0 to the nibble and the label's high 4
+    // bits and v1 to its low byte. :unpack long LABEL uses all 16 bits
+    fn unpack(&mut self) -> Result<(), AsmError> {
+        let (high, fixup) = if self.peek() == Some("long") {
+            self.next()?;
+            (0, Fixup::HighByte)
+        } else {
+            (self.value(0, 0xF)? << 4, Fixup::HighNibble)
+        };
+
+        let here = self.here;
+        let addr =
+            self.reference(&[(fixup, here + 1), (Fixup::LowByte, here + 3)])?;
+        let high = if fixup == Fixup::HighByte {
+            addr >> 8
+        } else {
+            high | addr >> 8
+        };
+        self.emit(&[0x60, high as u8, 0x61, addr as u8])
+    }
+
+    // :macro NAME ARGS... { BODY }
+    fn macro_definition(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" && !token.string {
+                break;
+            }
+            args.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            if !token.string {
+                if token.text == "{" {
+                    depth += 1;
+                } else if token.text == "}" {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                }
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    // Replace a macro's name and arguments with its body
+    fn expand(&mut self, name: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(self.error(name, "Macros are expanded too many times"));
+        }
+
+        let count = self.macros[&name.text].args.len();
+        let mut values = HashMap::new();
+        for n in 0..count {
+            let value = self.next()?;
+            values.insert(self.macros[&name.text].args[n].clone(), value);
+        }
+
+        let body = &self.macros[&name.text].body;
+        let expanded: Vec<Token> = body
+            .iter()
+            .rev()
+            .map(|token| match values.get(&token.text) {
+                Some(value) if !token.string => value.clone(),
+                _ => token.clone(),
+            })
+            .collect();
+        self.tokens.extend(expanded);
+        Ok(())
+    }
+
+    fn label(&mut self, name: Token) -> Result<(), AsmError> {
+        // The jump to main isn't needed if main comes first
+        let first = self.rom.len() == 2
+            && self.here == START + 2
+            && self.references.is_empty()
+            && !self.dict.values().any(|&v| v == self.here as f64);
+        if name.text == "main" && self.main_jump && first {
+            self.rom.clear();
+            self.here = START;
+            self.main_jump = false;
+        }
+        self.define(name, self.here as f64)
+    }
+
+    fn define(&mut self, name: Token, value: f64) -> Result<(), AsmError> {
+        if self.dict.contains_key(&name.text) {
+            return Err(self
+                .error(&name, &format!("'{}' is already defined", name.text)));
+        }
+        self.dict.insert(name.text, value);
+        Ok(())
+    }
+
+    // Write the jump to main and everything that referred to a name before
+    // it was defined
+    fn finish(mut self) -> Result<Vec<u8>, AsmError> {
+        if let Some((_, token)) = self.branches.last() {
+            return Err(
+                self.error(token, &format!("'{}' without 'end'", token.text))
+            );
+        }
+        if let Some((_, token, _)) = self.loops.last() {
+            return Err(self.error(token, "'loop' without 'again'"));
+        }
+
+        if self.main_jump {
+            match self.dict.get("main") {
+                Some(&main) if main as usize <= 0xFFF => {
+                    let main = main as usize;
+                    self.rom[0] = 0x10 | (main >> 8) as u8;
+                    self.rom[1] = main as u8;
+                }
+                Some(_) => {
+                    return Err(AsmError {
+                        file: self.file.clone(),
+                        line: 0,
+                        column: 0,
+                        message: "'main' is out of range".to_string(),
+                    })
+                }
+                None => {
+                    return Err(AsmError {
+                        file: self.file.clone(),
+                        line: 0,
+                        column: 0,
+                        message: "The program has no 'main' label".to_string(),
+                    })
+                }
+            }
+        }
+
+        for reference in &self.references {
+            let value = match self.dict.get(&reference.name.text) {
+                Some(&value) => value.floor() as i64,
+                None => {
+                    return Err(self.error(
+                        &reference.name,
+                        &format!("Undefined name '{}'", reference.name.text),
+                    ))
+                }
+            };
+            let max = match reference.fixup {
+                Fixup::Addr | Fixup::HighNibble => 0xFFF,
+                _ => 0xFFFF,
+            };
+            let value = self.check(&reference.name, value as f64, 0, max)?;
+
+            let n = reference.addr - START;
+            match reference.fixup {
+                Fixup::Addr => {
+                    self.rom[n] = self.rom[n] & 0xF0 | (value >> 8) as u8;
+                    self.rom[n + 1] = value as u8;
+                }
+                Fixup::Long => {
+                    self.rom[n] = (value >> 8) as u8;
+                    self.rom[n + 1] = value as u8;
+                }
+                Fixup::HighNibble => self.rom[n] |= (value >> 8) as u8,
+                Fixup::HighByte => self.rom[n] = (value >> 8) as u8,
+                Fixup::LowByte => self.rom[n] = value as u8,
+            }
+        }
+        Ok(self.rom)
+    }
+
+    // An address, which may be a name that isn't defined yet. `at` is
+    // where it is written, to be filled in later
+    fn address(&mut self, fixup: Fixup, at: usize) -> Result<u16, AsmError> {
+        self.reference(&[(fixup, at)])
+    }
+
+    // A value that is written to each of `fixups` once it is defined
+    fn reference(
+        &mut self,
+        fixups: &[(Fixup, usize)],
+    ) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        let defined = self.dict.contains_key(&token.text)
+            || token.text == "{"
+            || number(&token.text).is_some();
+
+        if !defined
+            && !token.string
+            && self.register_name(&token.text).is_none()
+        {
+            for &(fixup, addr) in fixups {
+                self.references.push(Reference {
+                    addr,
+                    fixup,
+                    name: token.clone(),
+                });
+            }
+            return Ok(0);
+        }
+
+        self.tokens.push(token);
+        match fixups[0].0 {
+            Fixup::Long | Fixup::HighByte => self.value(0, 0xFFFF),
+            _ => self.value(0, 0xFFF),
+        }
+    }
+
+    // A number, name or calculation that must lie between `min` and `max`.
+    // Negative values are stored in two's complement
+    fn value(&mut self, min: i64, max: i64) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        let value = if token.text == "{" && !token.string {
+            self.calc()?
+        } else {
+            self.lookup(&token)?
+        };
+        self.check(&token, value, min, max)
+    }
+
+    fn check(
+        &self,
+        token: &Token,
+        value: f64,
+        min: i64,
+        max: i64,
+    ) -> Result<u16, AsmError> {
+        let value = value.floor() as i64;
+        if value < min || value > max {
+            return Err(self.error(
+                token,
+                &format!(
+                    "Value {} is out of range ({} to {})",
+                    value, min, max
+                ),
+            ));
+        }
+        Ok(value as u16)
+    }
+
+    // The value of a number or a defined name
+    fn lookup(&self, token: &Token) -> Result<f64, AsmError> {
+        if let Some(n) = number(&token.text) {
+            return Ok(n);
+        }
+        match self.dict.get(&token.text) {
+            Some(&value) => Ok(value),
+            None if token.string => Err(self.error(token, "Expected a number")),
+            None => {
+                Err(self
+                    .error(token, &format!("Undefined name '{}'", token.text)))
+            }
+        }
+    }
+
+    // The value of :const, which may be a number, name or calculation
+    fn constant(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        if token.text == "{" && !token.string {
+            self.calc()
+        } else {
+            self.lookup(&token)
+        }
+    }
+
+    // A register for :alias, by name or calculated
+    fn register_or_calc(&mut self) -> Result<Option<usize>, AsmError> {
+        let token = self.next()?;
+        if token.text == "{" && !token.string {
+            let value = self.calc()?;
+            let x = self.check(&token, value, 0, 0xF)?;
+            return Ok(Some(x as usize));
+        }
+        Ok(self.register_name(&token.text))
+    }
+
+    // Evaluate the expression after a '{', up to the matching '}'.
+    // As in Octo, operators have no precedence and are applied from right
+    // to left
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "}" && !token.string {
+                break;
+            }
+            tokens.push(token);
+        }
+
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            None => Ok(value),
+            Some(token) => Err(self.error(token, "Unexpected token")),
+        }
+    }
+
+    fn calc_expr(
+        &self,
+        tokens: &[Token],
+        pos: &mut usize,
+    ) -> Result<f64, AsmError> {
+        let a = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok(a),
+        };
+        *pos += 1;
+        let b = self.calc_expr(tokens, pos)?;
+
+        let (ai, bi) = (a as i64, b as i64);
+        let bool = |x: bool| if x { 1.0 } else { 0.0 };
+        Ok(match op.text.as_str() {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" if b == 0.0 => return Err(self.error(op, "Division by zero")),
+            "/" => a / b,
+            "%" if bi == 0 => return Err(self.error(op, "Division by zero")),
+            "%" => (ai % bi) as f64,
+            "&" => (ai & bi) as f64,
+            "|" => (ai | bi) as f64,
+            "^" => (ai ^ bi) as f64,
+            "<<" => (ai << (bi & 63)) as f64,
+            ">>" => (ai >> (bi & 63)) as f64,
+            "pow" => a.powf(b),
+            "min" => a.min(b),
+            "max" => a.max(b),
+            "<" => bool(a < b),
+            ">" => bool(a > b),
+            "<=" => bool(a <= b),
+            ">=" => bool(a >= b),
+            "==" => bool(a == b),
+            "!=" => bool(a != b),
+            _ => {
+                return Err(
+                    self.error(op, &format!("Unknown operator '{}'", op.text))
+                )
+            }
+        })
+    }
+
+    fn calc_term(
+        &self,
+        tokens: &[Token],
+        pos: &mut usize,
+    ) -> Result<f64, AsmError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return Err(self.error(&self.last, "Expected a value")),
+        };
+        *pos += 1;
+
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| {
+            self.calc_term(tokens, pos).map(f)
+        };
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(t) if t.text == ")" => *pos += 1,
+                    _ => return Err(self.error(token, "Unclosed '('")),
+                }
+                Ok(value)
+            }
+            "-" => unary(|x| -x, pos),
+            "~" => unary(|x| !(x as i64) as f64, pos),
+            "!" => unary(|x| if x == 0.0 { 1.0 } else { 0.0 }, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "tan" => unary(f64::tan, pos),
+            "exp" => unary(f64::exp, pos),
+            "log" => unary(f64::ln, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sign" => unary(f64::signum, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "floor" => unary(f64::floor, pos),
+            "@" => {
+                // A byte of the program compiled so far
+                let addr = self.calc_term(tokens, pos)? as usize;
+                let n = addr.wrapping_sub(START);
+                Ok(self.rom.get(n).map_or(0.0, |&b| b as f64))
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            _ => self.lookup(token),
+        }
+    }
+
+    fn register(&mut self) -> Result<usize, AsmError> {
+        let token = self.next()?;
+        match self.register_name(&token.text) {
+            Some(x) if !token.string => Ok(x),
+            _ => Err(self.error(&token, "Expected a register")),
+        }
+    }
+
+    fn peek_register(&self) -> Option<usize> {
+        match self.tokens.last() {
+            Some(token) if !token.string => self.register_name(&token.text),
+            _ => None,
+        }
+    }
+
+    // v0 to vf, or an alias
+    fn register_name(&self, name: &str) -> Option<usize> {
+        if let Some(&x) = self.aliases.get(name) {
+            return Some(x);
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(c), None) | (Some('V'), Some(c), None) => {
+                c.to_digit(16).map(|x| x as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        let reserved = token.string
+            || number(&token.text).is_some()
+            || self.register_name(&token.text).is_some()
+            || KEYWORDS.contains(&token.text.as_str());
+        if reserved {
+            return Err(self.error(
+                &token,
+                &format!("'{}' can't be used as a name", token.text),
+            ));
+        }
+        Ok(token)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token.text != text || token.string {
+            return Err(self.error(&token, &format!("Expected '{}'", text)));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|t| t.text.as_str())
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => Err(self.error(&self.last, "Unexpected end of file")),
+        }
+    }
+
+    fn op(&mut self, instruction: Instruction) -> Result<(), AsmError> {
+        let opcode = instruction.encode();
+        self.emit(&[(opcode >> 8) as u8, opcode as u8])
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), AsmError> {
+        if self.here + bytes.len() > MEMORY_SIZE {
+            return Err(
+                self.error(&self.last, "The program is too big for memory")
+            );
+        }
+
+        let n = self.here - START;
+        if self.rom.len() < n + bytes.len() {
+            self.rom.resize(n + bytes.len(), 0);
+        }
+        self.rom[n..n + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        Ok(())
+    }
+
+    // Point a jump made by begin, else or while at the current address
+    fn patch_jump(
+        &mut self,
+        jump: usize,
+        token: &Token,
+    ) -> Result<(), AsmError> {
+        if self.here > 0xFFF {
+            return Err(self.error(token, "Jump target is out of range"));
+        }
+        let n = jump - START;
+        self.rom[n] = 0x10 | (self.here >> 8) as u8;
+        self.rom[n + 1] = self.here as u8;
+        Ok(())
+    }
+
+    fn error(&self, token: &Token, message: &str) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.to_string(),
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    ":",
+    ":=",
+    "|=",
+    "&=",
+    "^=",
+    "-=",
+    "=-",
+    "+=",
+    ">>=",
+    "<<=",
+    "==",
+    "!=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    "key",
+    "-key",
+    "hex",
+    "bighex",
+    "random",
+    "delay",
+    "return",
+    "clear",
+    "bcd",
+    "save",
+    "load",
+    "buzzer",
+    "if",
+    "then",
+    "begin",
+    "else",
+    "end",
+    "jump",
+    "jump0",
+    "native",
+    "sprite",
+    "loop",
+    "while",
+    "again",
+    "scroll-down",
+    "scroll-up",
+    "scroll-right",
+    "scroll-left",
+    "lores",
+    "hires",
+    "loadflags",
+    "saveflags",
+    "i",
+    "audio",
+    "plane",
+    "pitch",
+    "exit",
+    "long",
+];
+
+// Decimal, 0x hexadecimal or 0b binary, optionally negative
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = if text.starts_with('-') {
+        (true, &text[1..])
+    } else {
+        (false, text)
+    };
+    let (radix, digits) = if digits.starts_with("0x") {
+        (16, &digits[2..])
+    } else if digits.starts_with("0b") {
+        (2, &digits[2..])
+    } else {
+        (10, digits)
+    };
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let n = i64::from_str_radix(digits, radix).ok()? as f64;
+    Some(if negative { -n } else { n })
+}
+
+// Split the source into whitespace-separated tokens and quoted strings,
+// dropping comments, which run from '#' to the end of the line
+fn tokenize(source: &str, name: &str) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+
+    for (n, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut pos = 0;
+
+        while pos < chars.len() {
+            let start = pos;
+            if chars[pos] == '#' {
+                break;
+            } else if chars[pos].is_whitespace() {
+                pos += 1;
+                continue;
+            }
+
+            let string = chars[pos] == '"';
+            let text = if string {
+                let end =
+                    match (pos + 1..chars.len()).find(|&i| chars[i] == '"') {
+                        Some(end) => end,
+                        None => {
+                            return Err(AsmError {
+                                file: name.to_string(),
+                                line: n + 1,
+                                column: start + 1,
+                                message: "Unclosed string".to_string(),
+                            })
+                        }
+                    };
+                pos = end + 1;
+                chars[start + 1..end].iter().collect()
+            } else {
+                while pos < chars.len() && !chars[pos].is_whitespace() {
+                    pos += 1;
+                }
+                chars[start..pos].iter().collect()
+            };
+
+            tokens.push(Token {
+                text,
+                line: n + 1,
+                column: start + 1,
+                string,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Chip8, Platform};
+
+    fn compile_str(source: &str) -> Vec<u8> {
+        compile(source, "test.8o").unwrap()
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = compile(source, "test.8o").unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn test_instructions() {
+        let source = "
+            : main
+              clear
+              v0 := 5  v1 := v0  v2 := random 0xFF  v3 := key
+              v4 := delay  v5 += 1  v6 -= 1  v7 += v0  v8 -= v0
+              v9 =- v0  va |= vb  vb &= vc  vc ^= vd  vd >>= ve  ve <<= vf
+              i := data  i := long data  i := hex v1  i := bighex v2
+              i += v3  delay := v4  buzzer := v5  pitch := v6
+              sprite v0 v1 8  bcd v7  save v8  load v9  save v1 - v3
+              load v2 - v4  saveflags v5  loadflags v6
+              hires lores scroll-down 4 scroll-up 2 scroll-left
+              scroll-right exit audio plane 3 jump0 data native 0x123
+              return ;
+            : data 0xFF -1
+              :byte 0x81  :pointer data";
+
+        assert_eq!(
+            compile_str(source),
+            vec![
+                0x00, 0xE0, 0x60, 0x05, 0x81, 0x00, 0xC2, 0xFF, 0xF3, 0x0A,
+                0xF4, 0x07, 0x75, 0x01, 0x76, 0xFF, 0x87, 0x04, 0x88, 0x05,
+                0x89, 0x07, 0x8A, 0xB1, 0x8B, 0xC2, 0x8C, 0xD3, 0x8D, 0xE6,
+                0x8E, 0xFE, 0xA2, 0x5C, 0xF0, 0x00, 0x02, 0x5C, 0xF1, 0x29,
+                0xF2, 0x30, 0xF3, 0x1E, 0xF4, 0x15, 0xF5, 0x18, 0xF6, 0x3A,
+                0xD0, 0x18, 0xF7, 0x33, 0xF8, 0x55, 0xF9, 0x65, 0x51, 0x32,
+                0x52, 0x43, 0xF5, 0x75, 0xF6, 0x85, 0x00, 0xFF, 0x00, 0xFE,
+                0x00, 0xC4, 0x00, 0xD2, 0x00, 0xFC, 0x00, 0xFB, 0x00, 0xFD,
+                0xF0, 0x02, 0xF3, 0x01, 0xB2, 0x5C, 0x01, 0x23, 0x00, 0xEE,
+                0x00, 0xEE, 0xFF, 0xFF, 0x81, 0x02, 0x5C
+            ]
+        );
+    }
+
+    #[test]
+    fn test_main_and_forward_references() {
+        // main isn't first, so a jump to it is kept
+        assert_eq!(
+            compile_str(": helper return : main helper jump main"),
+            [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02, 0x12, 0x04]
+        );
+        assert_eq!(
+            compile_str(": main :unpack 0xA data :unpack long data : data"),
+            [0x60, 0xA2, 0x61, 0x08, 0x60, 0x02, 0x61, 0x08]
+        );
+        assert_eq!(
+            compile_str(": main :next target v0 := 0 :call target"),
+            [0x60, 0x00, 0x22, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            : main
+              if v0 == 1 then v1 := 2
+              if v0 != v1 then return
+              if v2 key then return
+              if v0 == 1 begin
+                v1 := 1
+              else
+                v1 := 2
+              end
+              loop
+                while v3 != 5
+                v3 += 1
+              again";
+
+        assert_eq!(
+            compile_str(source),
+            vec![
+                0x40, 0x01, 0x61, 0x02, 0x50, 0x10, 0x00, 0xEE, 0xE2, 0xA1,
+                0x00, 0xEE, 0x30, 0x01, 0x12, 0x14, 0x61, 0x01, 0x12, 0x16,
+                0x61, 0x02, 0x43, 0x05, 0x12, 0x1E, 0x73, 0x01, 0x12, 0x16
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comparisons() {
+        // Count how many of the comparisons hold for each pair of values
+        let source = "
+            : main
+              v2 := 0
+              if v0 < v1 then v2 += 1
+              if v0 > v1 then v2 += 0x10
+              if v0 <= v1 then v2 += 0x20
+              if v0 >= 7 then v2 += 0x40
+              if v0 < 7 begin v2 += 0x80 end
+              exit";
+        let rom = compile_str(source);
+
+        for &(v0, v1, expected) in &[
+            (3, 5, 0xA1),
+            (5, 3, 0x90),
+            (5, 5, 0xA0),
+            (7, 9, 0x61),
+            (9, 7, 0x50),
+        ] {
+            let mut chip8 =
+                Chip8::from_bytes(&rom, Platform::Chip8, Default::default())
+                    .unwrap();
+            chip8.set_v(0, v0);
+            chip8.set_v(1, v1);
+            chip8.run_frame(100).unwrap();
+            assert!(chip8.exited());
+            assert_eq!(chip8.v()[2], expected, "v0 = {}, v1 = {}", v0, v1);
+        }
+    }
+
+    #[test]
+    fn test_definitions() {
+        let source = "
+            :alias counter v3
+            :const SPEED 2
+            :calc DOUBLE { SPEED * 2 + 1 }    # Right to left, so 6
+            :calc SHIFTED { 1 << 2 + 1 }
+            :macro twice op value { counter op value  counter op value }
+            : main
+              counter := DOUBLE
+              twice += SPEED
+              v0 := { SHIFTED - 1 }
+              v1 := { floor 7 / 2 }";
+
+        assert_eq!(
+            compile_str(source),
+            [0x63, 0x06, 0x73, 0x02, 0x73, 0x02, 0x60, 0x07, 0x61, 0x03]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            (2, 8, "Undefined name 'nowhere'".to_string())
+        );
+        assert_eq!(
+            error(": main v0 := 256"),
+            (1, 14, "Value 256 is out of range (-128 to 255)".to_string())
+        );
+        assert_eq!(
+            error(": main v0 += v1 v1 ?= 2"),
+            (1, 20, "Unknown operator '?='".to_string())
+        );
+        assert_eq!(
+            error(": main\n  if v0 == 1 begin"),
+            (2, 14, "'begin' without 'end'".to_string())
+        );
+        assert_eq!(error(": main again").2, "'again' without 'loop'");
+        assert_eq!(error(": main : main").2, "'main' is already defined");
+        assert_eq!(
+            error(": main :macro m { m } m").2,
+            "Macros are expanded too many times"
+        );
+        assert_eq!(error(": main :foo").2, "Unknown directive ':foo'");
+        assert_eq!(error(": main v0 :=").2, "Unexpected end of file");
+        assert_eq!(error("return").2, "The program has no 'main' label");
+    }
+}
@@ -0,0 +1,145 @@
+use chip8::database;
+
+// Colours for each combination of the two XO-CHIP bitplanes, as 0xRRGGBB:
+// neither plane, the first, the second and both. Plain CHIP-8 only ever
+// uses the first two
+pub type Palette = [u32; 4];
+
+pub const PALETTE: Palette = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+
+// Built-in palettes, in the order the palette hotkey cycles through them
+pub const PRESETS: &[(&str, Palette)] = &[
+    ("default", PALETTE),
+    ("amber", [0x1A0F00, 0xFFB000, 0xB37B00, 0x664600]), // Amber monochrome
+    ("green", [0x081A0C, 0x33FF66, 0x24B347, 0x146629]), // Green phosphor
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),   // Backlit LCD
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),  // Octo's default
+];
+
+// Parse a preset name, or a list of two or four colours separated by
+// commas, e.g. "#000000,#FFFFFF". Two colours are the background and
+// foreground
+pub fn parse(spec: &str) -> Result<Palette, String> {
+    if let Some(&(_, palette)) = PRESETS
+        .iter()
+        .find(|&&(name, _)| name.eq_ignore_ascii_case(spec))
+    {
+        return Ok(palette);
+    }
+
+    let colours: Vec<&str> = spec.split(',').map(str::trim).collect();
+    if colours.len() != 2 && colours.len() != 4 {
+        return Err(format!(
+            "Unknown palette '{}'. Expected two or four colours, or one of: \
+             {}",
+            spec,
+            names().join(", ")
+        ));
+    }
+    let colours = colours
+        .into_iter()
+        .map(|colour| {
+            database::parse_colour(colour).ok_or_else(|| {
+                format!(
+                    "Invalid colour '{}'. Expected #RRGGBB, e.g. #FFB000",
+                    colour
+                )
+            })
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+    Ok(from_colours(&colours).unwrap())
+}
+
+// A palette from two or more colours. With only two, the other plane
+// combinations are shades between them, as in the default palette
+pub fn from_colours(colours: &[u32]) -> Option<Palette> {
+    match colours.len() {
+        2 => {
+            let (bg, fg) = (colours[0], colours[1]);
+            Some([bg, fg, mix(bg, fg, 2), mix(bg, fg, 1)])
+        }
+        n if n >= 4 => Some([colours[0], colours[1], colours[2], colours[3]]),
+        _ => None,
+    }
+}
+
+// The palettes to cycle through, starting with `first`
+pub fn cycle(first: Palette) -> Vec<Palette> {
+    let mut palettes = vec![first];
+    palettes.extend(
+        PRESETS
+            .iter()
+            .map(|&(_, palette)| palette)
+            .filter(|&palette| palette != first),
+    );
+    palettes
+}
+
+// The preset's name, or "custom"
+pub fn name(palette: Palette) -> &'static str {
+    PRESETS
+        .iter()
+        .find(|&&(_, p)| p == palette)
+        .map_or("custom", |&(name, _)| name)
+}
+
+fn names() -> Vec<&'static str> {
+    PRESETS.iter().map(|&(name, _)| name).collect()
+}
+
+// `thirds` thirds of the way from `from` to `to`, in each channel
+fn mix(from: u32, to: u32, thirds: u32) -> u32 {
+    (0..3).fold(0, |colour, n| {
+        let shift = n * 8;
+        let from = (from >> shift) & 0xFF;
+        let to = (to >> shift) & 0xFF;
+        let channel = (from * (3 - thirds) + to * thirds) / 3;
+        colour | channel << shift
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("amber"), Ok(PRESETS[1].1));
+        assert_eq!(parse("LCD"), Ok(PRESETS[3].1));
+        assert_eq!(parse("#000000,#FFFFFF"), Ok(PALETTE));
+        assert_eq!(
+            parse("102030, 405060,708090,a0b0c0"),
+            Ok([0x102030, 0x405060, 0x708090, 0xA0B0C0])
+        );
+        assert_eq!(
+            parse("#000000,#FFB000"),
+            Ok([0x000000, 0xFFB000, 0xAA7500, 0x553A00])
+        );
+
+        assert_eq!(
+            parse("#FFFFFF"),
+            Err(
+                "Unknown palette '#FFFFFF'. Expected two or four colours, or \
+                 one of: default, amber, green, lcd, octo"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse("#000000,white"),
+            Err("Invalid colour 'white'. Expected #RRGGBB, e.g. #FFB000"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let palettes = cycle(PRESETS[2].1);
+        assert_eq!(palettes.len(), PRESETS.len());
+        assert_eq!(palettes[..2], [PRESETS[2].1, PALETTE]);
+        assert_eq!(name(palettes[0]), "green");
+
+        let custom = [1, 2, 3, 4];
+        assert_eq!(cycle(custom).len(), PRESETS.len() + 1);
+        assert_eq!(name(custom), "custom");
+    }
+}
@@ -0,0 +1,49 @@
+use crate::quirks::Quirks;
+
+/// Machine the ROM was written for. Extension instructions are always
+/// decoded; the platform decides the amount of RAM and the default quirks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    /// CHIP-8 and SUPER-CHIP with 4 KiB of RAM
+    Chip8,
+    /// XO-CHIP with 64 KiB of RAM
+    XoChip,
+}
+
+/// Names of the platforms accepted by `from_name`
+pub const PLATFORM_NAMES: &[&str] = &["chip8", "xochip"];
+
+impl Platform {
+    /// Bytes of RAM the platform has
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// Quirks to use when neither the user nor the database choose any
+    pub fn default_quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::default(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    /// The name used for the platform on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    /// Look up a platform by one of the `PLATFORM_NAMES`
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+/// Behaviour of instructions that were implemented differently by different
+/// CHIP-8 interpreters. The default matches this emulator's original
+/// interpretation of each instruction
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quirks {
+    /// 8xy6/8xyE shift Vy into Vx
+    pub shift_uses_vy: bool,
+    /// Fx55/Fx65 leave I = I + x + 1
+    pub load_store_increments_i: bool,
+    /// Bxnn jumps to xnn + Vx, not nnn + V0
+    pub jump_uses_vx: bool,
+    /// 8xy1/8xy2/8xy3 set VF = 0
+    pub logic_resets_vf: bool,
+    /// Clip sprites at the display edges instead of wrapping them
+    pub clip_sprites: bool,
+    /// Dxyn waits for the next vertical blank before continuing
+    pub display_wait: bool,
+    /// The timers stop while Fx0A waits for a key
+    pub key_wait_stops_timers: bool,
+}
+
+/// Names of the quirk profiles accepted by `from_name`
+pub const PROFILE_NAMES: &[&str] =
+    &["default", "vip", "chip48", "schip", "xochip"];
+
+/// Names of the individual quirks, matching the field names
+pub const QUIRK_NAMES: &[&str] = &[
+    "shift_uses_vy",
+    "load_store_increments_i",
+    "jump_uses_vx",
+    "logic_resets_vf",
+    "clip_sprites",
+    "display_wait",
+    "key_wait_stops_timers",
+];
+
+impl Quirks {
+    /// Original COSMAC VIP interpreter (1977)
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            display_wait: true,
+            key_wait_stops_timers: false,
+        }
+    }
+
+    /// CHIP-48 for the HP-48 calculators (1990)
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            key_wait_stops_timers: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1 (1991)
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            key_wait_stops_timers: false,
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+            key_wait_stops_timers: false,
+        }
+    }
+
+    /// Look up an individual quirk by name
+    pub fn get(self, name: &str) -> Option<bool> {
+        let mut quirks = self;
+        quirks.flag_mut(name).map(|flag| *flag)
+    }
+
+    /// Set an individual quirk by name. Returns false if there is no quirk
+    /// with that name
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match self.flag_mut(name) {
+            Some(flag) => {
+                *flag = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift_uses_vy" => Some(&mut self.shift_uses_vy),
+            "load_store_increments_i" => {
+                Some(&mut self.load_store_increments_i)
+            }
+            "jump_uses_vx" => Some(&mut self.jump_uses_vx),
+            "logic_resets_vf" => Some(&mut self.logic_resets_vf),
+            "clip_sprites" => Some(&mut self.clip_sprites),
+            "display_wait" => Some(&mut self.display_wait),
+            "key_wait_stops_timers" => Some(&mut self.key_wait_stops_timers),
+            _ => None,
+        }
+    }
+
+    /// Look up a quirk profile by one of the `PROFILE_NAMES`
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "default" => Some(Quirks::default()),
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::superchip()),
+            "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
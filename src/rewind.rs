@@ -0,0 +1,228 @@
+use crate::chip8::Chip8;
+use std::collections::VecDeque;
+
+/// Consecutive frames usually differ in only a few bytes, so rather than
+/// keeping every state in full, the buffer keeps the newest state and a
+/// delta for each step back from it. A delta is the XOR of two states,
+/// which is mostly zero, run-length encoded
+pub struct Rewind {
+    latest: Option<Vec<u8>>, // Save state of the newest frame
+    deltas: VecDeque<Vec<u8>>, // Oldest first
+    max_frames: usize,
+    max_bytes: usize,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    /// Keep at most `max_frames` frames of history, using at most about
+    /// `max_bytes` bytes. The oldest frames are dropped first
+    pub fn new(max_frames: usize, max_bytes: usize) -> Rewind {
+        Rewind {
+            latest: None,
+            deltas: VecDeque::new(),
+            max_frames,
+            max_bytes,
+            delta_bytes: 0,
+        }
+    }
+
+    /// Record the state at the end of a frame
+    pub fn push(&mut self, chip8: &Chip8) {
+        let state = chip8.save_state();
+
+        if let Some(latest) = self.latest.take() {
+            let delta = encode_delta(&state, &latest);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(state);
+
+        while self.deltas.len() > self.max_frames
+            || (!self.deltas.is_empty() && self.memory_usage() > self.max_bytes)
+        {
+            if let Some(delta) = self.deltas.pop_front() {
+                self.delta_bytes -= delta.len();
+            }
+        }
+    }
+
+    /// Step back one frame, returning the machine as it was at the end of
+    /// the previous frame. Returns None once the history is exhausted
+    pub fn pop(&mut self) -> Option<Chip8> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+
+        self.delta_bytes -= delta.len();
+        *latest = apply_delta(latest, &delta);
+        Chip8::from_state(latest).ok()
+    }
+
+    /// Number of frames that can be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    /// Bytes used by the stored states
+    pub fn memory_usage(&self) -> usize {
+        self.latest.as_ref().map_or(0, |s| s.len()) + self.delta_bytes
+    }
+}
+
+// Delta that turns `from` into `to`. It holds the length of `to`, followed
+// by pairs of runs over the XOR of the two states: a count of unchanged
+// bytes, then a count of changed bytes and their XORed values
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let len = from.len().max(to.len());
+    let xor = |n: usize| {
+        from.get(n).cloned().unwrap_or(0) ^ to.get(n).cloned().unwrap_or(0)
+    };
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, to.len());
+
+    let mut n = 0;
+    while n < len {
+        let unchanged = (n..len).take_while(|&m| xor(m) == 0).count();
+        n += unchanged;
+        let changed = (n..len).take_while(|&m| xor(m) != 0).count();
+
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, changed);
+        delta.extend((n..(n + changed)).map(xor));
+        n += changed;
+    }
+    delta
+}
+
+fn apply_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let to_len = read_varint(delta, &mut pos);
+
+    let mut to = from.to_vec();
+    to.resize(from.len().max(to_len), 0);
+
+    let mut n = 0;
+    while pos < delta.len() {
+        n += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for (b, x) in to[n..(n + changed)].iter_mut().zip(&delta[pos..]) {
+            *b ^= x;
+        }
+        pos += changed;
+        n += changed;
+    }
+
+    to.truncate(to_len);
+    to
+}
+
+// Unsigned LEB128: seven bits per byte, high bit set on all but the last
+fn write_varint(buf: &mut Vec<u8>, mut x: usize) {
+    while x >= 0x80 {
+        buf.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut x = 0;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        x |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return x;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // Counts V0 up by one each instruction, switching to high resolution
+    // partway through so that the state changes size
+    const ROM: [u8; 10] =
+        [0x70, 0x01, 0x70, 0x01, 0x00, 0xFF, 0x70, 0x01, 0x12, 0x06];
+
+    #[test]
+    fn test_delta() {
+        let from = [1, 2, 3, 4, 5, 6, 7, 8];
+        let to = [1, 2, 9, 4, 5, 6, 7, 8, 0, 10];
+
+        assert_eq!(apply_delta(&from, &encode_delta(&from, &to)), to);
+        assert_eq!(apply_delta(&to, &encode_delta(&to, &from)), from);
+        assert_eq!(apply_delta(&from, &encode_delta(&from, &from)), from);
+
+        let big = vec![0xAA; 1000];
+        assert_eq!(apply_delta(&[], &encode_delta(&[], &big)), big);
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xAC, 0x02]);
+        assert_eq!(read_varint(&buf, &mut 0), 300);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut c = Chip8::from_bytes(&ROM, Platform::Chip8, Quirks::default())
+            .unwrap();
+        let mut rewind = Rewind::new(100, 1 << 20);
+        let mut states = Vec::new();
+
+        for _ in 0..6 {
+            c.execute_opcode().unwrap();
+            rewind.push(&c);
+            states.push(c.save_state());
+        }
+        assert_eq!(rewind.len(), 5);
+
+        // Each delta is far smaller than a full state
+        assert!(rewind.memory_usage() < states[5].len() + 5 * 64);
+
+        for n in (0..5).rev() {
+            let c = rewind.pop().unwrap();
+            assert_eq!(c.save_state(), states[n]);
+        }
+        assert!(rewind.pop().is_none());
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_rewind_limits() {
+        let mut c = Chip8::from_bytes(&ROM, Platform::Chip8, Quirks::default())
+            .unwrap();
+
+        let mut rewind = Rewind::new(3, 1 << 20);
+        for _ in 0..10 {
+            c.execute_opcode().unwrap();
+            rewind.push(&c);
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop().unwrap().v()[0], 5);
+
+        // A budget smaller than one state keeps only the newest frame
+        let mut rewind = Rewind::new(100, 1);
+        for _ in 0..10 {
+            c.execute_opcode().unwrap();
+            rewind.push(&c);
+        }
+        assert!(rewind.is_empty());
+        assert!(rewind.pop().is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::savestate::{self, Reader, Writer};
+use std::io;
+
+/// The COSMAC VIP interpreter occupies the first 512 bytes of RAM
+pub const VIP_INTERPRETER_SIZE: usize = 0x200;
+
+/// Random number generator for Cxkk. It is owned by the interpreter, rather
+/// than taken from the thread, so that runs can be reproduced from a seed
+/// and its state can be saved and restored along with everything else
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rng {
+    /// xorshift64*
+    Xorshift { state: u64 },
+    /// The COSMAC VIP interpreter's routine. It keeps a 16-bit seed in
+    /// register R9 and uses bytes of its own code, in the page at 0x0100,
+    /// as a lookup table
+    Vip { r9: u16, page: Vec<u8> },
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift never leaves the all-zero state, so it is replaced
+        let state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+        Rng::Xorshift { state }
+    }
+
+    /// Use the VIP's routine. `interpreter` is an image of the 512-byte
+    /// VIP CHIP-8 interpreter, which is needed for the lookup table. The
+    /// low 16 bits of `seed` are the initial value of R9, which the VIP
+    /// leaves as whatever it held at power on
+    pub fn vip(seed: u64, interpreter: &[u8]) -> Result<Rng, io::Error> {
+        if interpreter.len() != VIP_INTERPRETER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "VIP interpreter image must be {} bytes, not {}",
+                    VIP_INTERPRETER_SIZE,
+                    interpreter.len()
+                ),
+            ));
+        }
+
+        Ok(Rng::Vip {
+            r9: seed as u16,
+            page: interpreter[0x100..].to_vec(),
+        })
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        match *self {
+            Rng::Xorshift { ref mut state } => {
+                let mut x = *state;
+                x ^= x >> 12;
+                x ^= x << 25;
+                x ^= x >> 27;
+                *state = x;
+                // The high byte has the best quality
+                (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+            }
+            Rng::Vip {
+                ref mut r9,
+                ref page,
+            } => {
+                // INC R9, then add the table byte at 0x01[R9.0] to R9.1.
+                // The sum, shifted right through the carry, is added to
+                // itself and becomes both the new R9.1 and the result
+                *r9 = r9.wrapping_add(1);
+                let (hi, lo) = ((*r9 >> 8) as u8, *r9 as u8);
+                let (sum, carry) = hi.overflowing_add(page[lo as usize]);
+                let shifted = (sum >> 1) | ((carry as u8) << 7);
+                let result = sum.wrapping_add(shifted);
+
+                *r9 = (result as u16) << 8 | (lo as u16);
+                result
+            }
+        }
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        match *self {
+            Rng::Xorshift { state } => {
+                w.u8(0);
+                w.u64(state);
+            }
+            Rng::Vip { r9, ref page } => {
+                w.u8(1);
+                w.u16(r9);
+                w.bytes(page);
+            }
+        }
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Rng, io::Error> {
+        match r.u8()? {
+            0 => Ok(Rng::Xorshift { state: r.u64()? }),
+            1 => Ok(Rng::Vip {
+                r9: r.u16()?,
+                page: r.bytes(0x100)?.to_vec(),
+            }),
+            x => Err(savestate::invalid_data(format!(
+                "Invalid random number generator {} in save state",
+                x
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_xorshift() {
+        let mut a = Rng::new(1234);
+        let mut b = Rng::new(1234);
+        let bytes: Vec<u8> = (0..64).map(|_| a.next_u8()).collect();
+
+        assert!((0..64).all(|n| b.next_u8() == bytes[n]));
+        assert!(bytes.iter().any(|&x| x != bytes[0]));
+        assert_ne!(Rng::new(0), Rng::Xorshift { state: 0 });
+    }
+
+    #[test]
+    fn test_vip() {
+        let mut interpreter = vec![0; VIP_INTERPRETER_SIZE];
+        interpreter[0x101] = 0x90;
+        interpreter[0x102] = 0x05;
+
+        assert!(Rng::vip(0, &interpreter[1..]).is_err());
+
+        let mut rng = Rng::vip(0x1000, &interpreter).unwrap();
+
+        // 0x10 + 0x90 = 0xA0, shifted 0x50, added 0xF0
+        assert_eq!(rng.next_u8(), 0xF0);
+        // 0xF0 + 0x05 = 0xF5 (no carry), shifted 0x7A, added 0x6F
+        assert_eq!(rng.next_u8(), 0x6F);
+        assert_eq!(
+            rng,
+            Rng::Vip {
+                r9: 0x6F02,
+                page: interpreter[0x100..].to_vec()
+            }
+        );
+
+        // A carry out of the first addition is shifted into bit 7:
+        // 0xF0 + 0x90 = 0x80 carry 1, shifted 0xC0, added 0x40
+        let mut rng = Rng::vip(0xF000, &interpreter).unwrap();
+        assert_eq!(rng.next_u8(), 0x40);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let interpreter = vec![0x5A; VIP_INTERPRETER_SIZE];
+
+        for rng in &[Rng::new(42), Rng::vip(42, &interpreter).unwrap()] {
+            let mut w = Writer::new();
+            rng.save(&mut w);
+            let state = w.finish();
+
+            let mut r = Reader::new(&state).unwrap();
+            let mut restored = Rng::load(&mut r).unwrap();
+            r.finish().unwrap();
+
+            let mut rng = rng.clone();
+            assert_eq!(restored, rng);
+            assert_eq!(restored.next_u8(), rng.next_u8());
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+
+/// Save states start with this magic number followed by a format version.
+/// The version is bumped whenever the layout changes, and states written by
+/// other versions are rejected rather than misread
+pub const MAGIC: &[u8; 4] = b"C8SS";
+pub const VERSION: u16 = 4;
+
+/// Save states for a ROM are stored alongside it, one file per slot
+pub fn slot_path(rom: &str, slot: u8) -> String {
+    format!("{}.state{}", rom, slot)
+}
+
+/// Appends little-endian fields to a save state
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        let mut w = Writer { buf: Vec::new() };
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w
+    }
+
+    pub fn u8(&mut self, x: u8) {
+        self.buf.push(x);
+    }
+
+    pub fn bool(&mut self, x: bool) {
+        self.buf.push(x as u8);
+    }
+
+    pub fn u16(&mut self, x: u16) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, x: u64) {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, x: &[u8]) {
+        self.buf.extend_from_slice(x);
+    }
+
+    /// Variable-length data is preceded by its length
+    pub fn sized_bytes(&mut self, x: &[u8]) {
+        self.buf.extend_from_slice(&(x.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(x);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Writer {
+        Writer::new()
+    }
+}
+
+/// Reads fields back in the order they were written. Every read is bounds
+/// checked, so truncated or corrupt states produce an error
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Reader<'a>, io::Error> {
+        let mut r = Reader { buf };
+
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("Not a save state".to_string()));
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported save state version {} (expected {})",
+                version, VERSION
+            )));
+        }
+        Ok(r)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, io::Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(invalid_data(format!("Invalid flag {} in save state", x))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, io::Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, io::Error> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if len > self.buf.len() {
+            return Err(invalid_data("Save state is truncated".to_string()));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn sized_bytes(&mut self) -> Result<&'a [u8], io::Error> {
+        let b = self.bytes(4)?;
+        let len = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        self.bytes(len as usize)
+    }
+
+    /// Check that the whole state has been read
+    pub fn finish(self) -> Result<(), io::Error> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_data("Unexpected data after save state".to_string()))
+        }
+    }
+}
+
+pub fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = Writer::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u64(0x789A_BCDE_F012_3456);
+        w.sized_bytes(&[1, 2, 3]);
+        let state = w.finish();
+
+        let mut r = Reader::new(&state).unwrap();
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u64().unwrap(), 0x789A_BCDE_F012_3456);
+        assert_eq!(r.sized_bytes().unwrap(), [1, 2, 3]);
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Reader::new(b"C8S").is_err());
+        assert!(Reader::new(b"XXXX\x01\x00").is_err());
+        assert!(Reader::new(b"C8SS\x03\x00").is_err());
+
+        let mut r = Reader::new(b"C8SS\x04\x00\x05").unwrap();
+        assert!(r.u16().is_err());
+
+        let mut r = Reader::new(b"C8SS\x04\x00\x02").unwrap();
+        assert!(r.bool().is_err());
+
+        let r = Reader::new(b"C8SS\x04\x00\x02").unwrap();
+        assert!(r.finish().is_err());
+    }
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(slot_path("roms/PONG", 3), "roms/PONG.state3");
+    }
+}
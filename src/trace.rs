@@ -0,0 +1,566 @@
+use crate::chip8::{Chip8, Chip8Error, StepOutcome};
+use crate::instruction::{decode, Syntax};
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// An execution trace has one line per instruction, giving the cycle (the
+/// number of instructions executed, counting this one), the address and
+/// opcode of the instruction, then the registers after it ran. Numbers are
+/// hexadecimal apart from the cycle, and the disassembly follows a ';':
+///
+/// ```text
+/// # cycle pc opcode v0 v1 ... vf i sp dt st ; instruction
+/// 1 0200 6A02 00 00 00 00 00 00 00 00 00 00 02 00 00 00 00 00 0000 0 00 00 ; LD VA, #02
+/// ```
+///
+/// Lines starting with '#' are comments
+pub const HEADER: &str = "# cycle pc opcode v0 v1 v2 v3 v4 v5 v6 v7 v8 v9 \
+                          va vb vc vd ve vf i sp dt st ; instruction";
+
+/// Which instructions are logged. Both ranges are inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Filter {
+    pub addresses: Option<(u16, u16)>, // Addresses of the instructions
+    pub cycles: Option<(u64, u64)>,
+}
+
+impl Filter {
+    pub fn matches(&self, cycle: u64, pc: u16) -> bool {
+        let within = |range: Option<(u64, u64)>, n: u64| {
+            range.map_or(true, |(start, end)| n >= start && n <= end)
+        };
+        within(self.cycles, cycle)
+            && within(
+                self.addresses.map(|(s, e)| (s as u64, e as u64)),
+                pc as u64,
+            )
+    }
+}
+
+/// Parse an inclusive range written "START-END", or a single number, with
+/// `parse` reading each end
+pub fn parse_range<T, F>(s: &str, parse: F) -> Option<(T, T)>
+where
+    T: Copy + PartialOrd,
+    F: Fn(&str) -> Option<T>,
+{
+    let mut ends = s.splitn(2, '-');
+    let start = parse(ends.next()?.trim())?;
+    let end = match ends.next() {
+        Some(end) => parse(end.trim())?,
+        None => start,
+    };
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Writes a trace of the instructions it executes
+pub struct Tracer<W: Write> {
+    out: W,
+    filter: Filter,
+    syntax: Option<Syntax>, // Disassembly syntax, or None to leave it out
+    cycle: u64,
+}
+
+impl<W: Write> Tracer<W> {
+    /// Start a trace, writing its header to `out`
+    pub fn new(
+        mut out: W,
+        filter: Filter,
+        syntax: Option<Syntax>,
+    ) -> Result<Tracer<W>, io::Error> {
+        writeln!(out, "{}", HEADER)?;
+        Ok(Tracer {
+            out,
+            filter,
+            syntax,
+            cycle: 0,
+        })
+    }
+
+    /// Instructions executed so far
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Execute one instruction, like `Chip8::execute_opcode`, and log it.
+    /// Steps that don't execute anything, because the ROM has exited or is
+    /// waiting for the display or a key, and instructions that fail aren't
+    /// logged. An Fx0A is logged once, when the key it waited for is
+    /// released
+    pub fn execute_opcode(
+        &mut self,
+        chip8: &mut Chip8,
+    ) -> Result<Result<StepOutcome, Chip8Error>, io::Error> {
+        // The instruction may overwrite itself, so read it before it runs
+        let pc = chip8.pc();
+        let opcode = chip8.opcode_at(pc);
+        let long = chip8.opcode_at(pc.wrapping_add(2));
+        let exited = chip8.exited();
+        let outcome = match chip8.execute_opcode() {
+            Ok(outcome) => outcome,
+            Err(e) => return Ok(Err(e)),
+        };
+        let waiting = outcome == StepOutcome::WaitingForVBlank
+            || outcome == StepOutcome::WaitingForKey;
+        if exited || waiting {
+            return Ok(Ok(outcome));
+        }
+
+        self.cycle += 1;
+        if self.filter.matches(self.cycle, pc) {
+            let line =
+                format_line(self.cycle, pc, opcode, long, chip8, self.syntax);
+            writeln!(self.out, "{}", line)?;
+        }
+        Ok(Ok(outcome))
+    }
+
+    /// Run one 60Hz frame, like `Chip8::run_frame`, logging each
+    /// instruction
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions: u32,
+    ) -> Result<Result<StepOutcome, Chip8Error>, io::Error> {
+        // An instruction that fails stops the frame
+        let mut result = Ok(StepOutcome::Executed);
+        chip8.run_frame_with::<io::Error, _>(instructions, |chip8| {
+            result = self.execute_opcode(chip8)?;
+            Ok(result.ok())
+        })?;
+        Ok(result)
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.out.flush()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+}
+
+/// One line of a trace, for the instruction `opcode` at `pc` that has just
+/// run. `long` is the word after the opcode, as it was before it ran
+pub fn format_line(
+    cycle: u64,
+    pc: u16,
+    opcode: u16,
+    long: u16,
+    chip8: &Chip8,
+    syntax: Option<Syntax>,
+) -> String {
+    let v: Vec<String> =
+        chip8.v().iter().map(|v| format!("{:02X}", v)).collect();
+    let mut line = format!(
+        "{} {:04X} {:04X} {} {:04X} {:X} {:02X} {:02X}",
+        cycle,
+        pc,
+        opcode,
+        v.join(" "),
+        chip8.i(),
+        chip8.stack().len(),
+        chip8.delay_timer(),
+        chip8.sound_timer()
+    );
+
+    if let (Some(syntax), Ok(instruction)) = (syntax, decode(opcode)) {
+        line.push_str(" ; ");
+        line.push_str(&instruction.format(Some(long), syntax));
+    }
+    line
+}
+
+/// A value in a trace line, as it is after the instruction has run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Cycle,
+    Pc,
+    Opcode,
+    V(usize),
+    I,
+    Sp, // Stack depth
+    Dt,
+    St,
+    Memory(u16), // The byte at an address
+}
+
+impl Field {
+    /// "cycle", "pc", "opcode", "v0" to "vf", "i", "sp", "dt", "st", or an
+    /// address in brackets, e.g. "[3f0]", for a byte of memory
+    pub fn from_name(name: &str) -> Option<Field> {
+        let name = name.to_lowercase();
+        let field = match name.as_str() {
+            "cycle" => Field::Cycle,
+            "pc" => Field::Pc,
+            "opcode" | "op" => Field::Opcode,
+            "i" => Field::I,
+            "sp" => Field::Sp,
+            "dt" => Field::Dt,
+            "st" => Field::St,
+            _ if name.starts_with('[') && name.ends_with(']') => {
+                let addr = &name[1..name.len() - 1];
+                Field::Memory(u16::from_str_radix(addr, 16).ok()?)
+            }
+            _ if name.starts_with('v') && name.len() == 2 => {
+                Field::V(usize::from_str_radix(&name[1..], 16).ok()?)
+            }
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    /// The value after `chip8` has run the instruction `opcode` at `pc`
+    pub fn get(self, cycle: u64, pc: u16, opcode: u16, chip8: &Chip8) -> u64 {
+        match self {
+            Field::Cycle => cycle,
+            Field::Pc => pc as u64,
+            Field::Opcode => opcode as u64,
+            Field::V(x) => chip8.v()[x] as u64,
+            Field::I => chip8.i() as u64,
+            Field::Sp => chip8.stack().len() as u64,
+            Field::Dt => chip8.delay_timer() as u64,
+            Field::St => chip8.sound_timer() as u64,
+            Field::Memory(addr) => {
+                chip8.memory().get(addr as usize).cloned().unwrap_or(0) as u64
+            }
+        }
+    }
+
+    /// Format a value of this field as it appears in our traces
+    pub fn format(self, value: u64) -> String {
+        match self {
+            Field::Cycle => value.to_string(),
+            Field::Pc | Field::Opcode | Field::I => format!("{:04X}", value),
+            Field::Sp => format!("{:X}", value),
+            _ => format!("{:02X}", value),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Field::Cycle => write!(f, "cycle"),
+            Field::Pc => write!(f, "PC"),
+            Field::Opcode => write!(f, "opcode"),
+            Field::V(x) => write!(f, "V{:X}", x),
+            Field::I => write!(f, "I"),
+            Field::Sp => write!(f, "SP"),
+            Field::Dt => write!(f, "DT"),
+            Field::St => write!(f, "ST"),
+            Field::Memory(addr) => write!(f, "[{:03X}]", addr),
+        }
+    }
+}
+
+/// The field held by each column of a trace line, or None for columns that
+/// are ignored. Columns after the last one listed are also ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct Columns(pub Vec<Option<Field>>);
+
+impl Columns {
+    /// The columns of our own traces
+    pub fn ours() -> Columns {
+        let mut fields = vec![Field::Cycle, Field::Pc, Field::Opcode];
+        fields.extend((0..16).map(Field::V));
+        fields.extend(&[Field::I, Field::Sp, Field::Dt, Field::St]);
+        Columns(fields.into_iter().map(Some).collect())
+    }
+
+    /// Parse a comma-separated list of field names, with "_" for a column
+    /// that is ignored and "v0-vf" for a run of registers, e.g.
+    /// "_,pc,opcode,v0-vf,i"
+    pub fn parse(spec: &str) -> Result<Columns, io::Error> {
+        let mut columns = Vec::new();
+        for name in spec.split(',').map(|name| name.trim()) {
+            let registers = parse_range(name, |r| match Field::from_name(r) {
+                Some(Field::V(x)) => Some(x),
+                _ => None,
+            });
+            match (name, registers) {
+                ("_", _) => columns.push(None),
+                (_, Some((start, end))) if name.contains('-') => {
+                    columns.extend((start..=end).map(|x| Some(Field::V(x))));
+                }
+                _ => match Field::from_name(name) {
+                    Some(field) => columns.push(Some(field)),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Unknown trace column '{}'", name),
+                        ))
+                    }
+                },
+            }
+        }
+        Ok(Columns(columns))
+    }
+}
+
+/// One line of a reference trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub line: usize, // Line number in the file
+    pub text: String,
+    pub values: Vec<(Field, u64)>,
+}
+
+/// Parse a trace written by us or another emulator. Columns are separated
+/// by spaces or commas, and may be labelled, e.g. "PC:0200" or "pc=0200".
+/// Numbers are hexadecimal, with or without a "0x", "$" or "#" prefix,
+/// apart from the cycle. Blank lines and lines starting with '#' are
+/// skipped
+pub fn parse_reference(
+    text: &str,
+    columns: &Columns,
+) -> Result<Vec<Step>, io::Error> {
+    let mut steps = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let cells: Vec<&str> = trimmed
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|cell| !cell.is_empty())
+            .collect();
+        let mut values = Vec::new();
+        for (column, field) in columns.0.iter().enumerate() {
+            let field = match *field {
+                Some(field) => field,
+                None => continue,
+            };
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Trace line {}: {}", n + 1, message),
+                )
+            };
+            let cell = cells.get(column).ok_or_else(|| {
+                invalid(format!("missing column {} ({})", column + 1, field))
+            })?;
+
+            let value = cell.rsplit(&[':', '='][..]).next().unwrap();
+            let value = value
+                .trim_start_matches("0x")
+                .trim_start_matches('$')
+                .trim_start_matches('#');
+            let radix = if field == Field::Cycle { 10 } else { 16 };
+            let value = u64::from_str_radix(value, radix).map_err(|_| {
+                invalid(format!("invalid {} '{}'", field, cell))
+            })?;
+            values.push((field, value));
+        }
+
+        steps.push(Step {
+            line: n + 1,
+            text: line.to_string(),
+            values,
+        });
+    }
+    Ok(steps)
+}
+
+/// A value that differs from the reference trace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    pub field: Field,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, got {}",
+            self.field,
+            self.field.format(self.expected),
+            self.field.format(self.actual)
+        )
+    }
+}
+
+/// Compare a step of the reference with the state after `chip8` ran the
+/// instruction `opcode` at `pc` as its `cycle`th instruction
+pub fn compare(
+    step: &Step,
+    cycle: u64,
+    pc: u16,
+    opcode: u16,
+    chip8: &Chip8,
+) -> Vec<Difference> {
+    step.values
+        .iter()
+        .filter_map(|&(field, expected)| {
+            let actual = field.get(cycle, pc, opcode, chip8);
+            if actual == expected {
+                None
+            } else {
+                Some(Difference {
+                    field,
+                    expected,
+                    actual,
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // 200: V0 = 5, 202: I = 300, 204: jump 204
+    const ROM: [u8; 6] = [0x60, 0x05, 0xA3, 0x00, 0x12, 0x04];
+
+    fn trace(filter: Filter, syntax: Option<Syntax>, steps: u32) -> String {
+        let mut chip8 =
+            Chip8::from_bytes(&ROM, Platform::Chip8, Quirks::default())
+                .unwrap();
+        let mut tracer = Tracer::new(Vec::new(), filter, syntax).unwrap();
+        for _ in 0..steps {
+            tracer.execute_opcode(&mut chip8).unwrap().unwrap();
+        }
+        assert_eq!(tracer.cycle(), steps as u64);
+        String::from_utf8(tracer.out).unwrap()
+    }
+
+    #[test]
+    fn test_format() {
+        let out = trace(Filter::default(), Some(Syntax::Cowgod), 2);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], HEADER);
+        assert_eq!(
+            lines[1],
+            "1 0200 6005 05 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 \
+             0000 0 00 00 ; LD V0, #05"
+        );
+        assert!(lines[2].starts_with("2 0202 A300 05 00"));
+        assert!(lines[2].ends_with(" 0300 0 00 00 ; LD I, #300"));
+
+        let octo = trace(Filter::default(), Some(Syntax::Octo), 1);
+        assert!(octo.ends_with("; v0 := 0x05\n"));
+        let bare = trace(Filter::default(), None, 1);
+        assert!(bare.ends_with(" 0000 0 00 00\n"));
+    }
+
+    #[test]
+    fn test_overwritten_opcode() {
+        // 200: I = 202, 202: save v0 over itself
+        let rom = [0xA2, 0x02, 0xF0, 0x55];
+        let mut chip8 =
+            Chip8::from_bytes(&rom, Platform::Chip8, Quirks::default())
+                .unwrap();
+        let mut tracer =
+            Tracer::new(Vec::new(), Filter::default(), Some(Syntax::Cowgod))
+                .unwrap();
+        for _ in 0..2 {
+            tracer.execute_opcode(&mut chip8).unwrap().unwrap();
+        }
+        assert_eq!(chip8.opcode_at(0x202), 0x0055);
+        let out = String::from_utf8(tracer.out).unwrap();
+        let last = out.lines().last().unwrap();
+        assert!(last.starts_with("2 0202 F055 "));
+        assert!(last.ends_with("; LD [I], V0"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            addresses: Some((0x202, 0x204)),
+            cycles: Some((2, 4)),
+        };
+        let out = trace(filter, None, 6);
+        let cycles: Vec<&str> = out
+            .lines()
+            .skip(1)
+            .map(|l| l.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(cycles, ["2", "3", "4"]);
+
+        let only_jumps = Filter {
+            addresses: Some((0x204, 0x204)),
+            cycles: None,
+        };
+        assert_eq!(trace(only_jumps, None, 6).lines().count(), 1 + 4);
+
+        let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+        assert_eq!(parse_range("200-2ff", hex), Some((0x200, 0x2FF)));
+        assert_eq!(parse_range("300", hex), Some((0x300, 0x300)));
+        assert_eq!(parse_range("300-200", hex), None);
+        assert_eq!(parse_range("x-200", hex), None);
+    }
+
+    #[test]
+    fn test_columns() {
+        assert_eq!(
+            Columns::parse("cycle,pc,opcode,v0-vf,i,sp,dt,st").unwrap(),
+            Columns::ours()
+        );
+        assert_eq!(
+            Columns::parse("_, PC, v3, [3F0]").unwrap(),
+            Columns(vec![
+                None,
+                Some(Field::Pc),
+                Some(Field::V(3)),
+                Some(Field::Memory(0x3F0))
+            ])
+        );
+        assert!(Columns::parse("pc,vg").is_err());
+        assert!(Columns::parse("v3-v1").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        // Our own traces parse with the default columns
+        let ours = trace(Filter::default(), Some(Syntax::Cowgod), 2);
+        let steps = parse_reference(&ours, &Columns::ours()).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1].line, 3);
+        assert_eq!(steps[1].values[1], (Field::Pc, 0x202));
+        assert_eq!(steps[1].values[19], (Field::I, 0x300));
+
+        // Another emulator's, with labelled columns in a different order
+        let columns = Columns::parse("pc,_,v0,i,[300]").unwrap();
+        let theirs = "PC:0x0200 op:6005 V0:05 I:000 [300]=00\n\
+                      PC:0x0202 op:A300 V0:06 I:300 [300]=00\n";
+        let steps = parse_reference(theirs, &columns).unwrap();
+
+        let mut chip8 =
+            Chip8::from_bytes(&ROM, Platform::Chip8, Quirks::default())
+                .unwrap();
+        chip8.execute_opcode().unwrap();
+        assert!(compare(&steps[0], 1, 0x200, 0x6005, &chip8).is_empty());
+        chip8.execute_opcode().unwrap();
+        let differences = compare(&steps[1], 2, 0x202, 0xA300, &chip8);
+        assert_eq!(
+            differences,
+            [Difference {
+                field: Field::V(0),
+                expected: 6,
+                actual: 5
+            }]
+        );
+        assert_eq!(differences[0].to_string(), "V0: expected 06, got 05");
+
+        let short = parse_reference("0200 6005", &columns);
+        assert_eq!(
+            short.unwrap_err().to_string(),
+            "Trace line 1: missing column 3 (V0)"
+        );
+        assert!(parse_reference("0200 x zz", &columns).is_err());
+    }
+}